@@ -1,6 +1,6 @@
 //! Central configuration for the oauth2_passkey crate
 
-use std::sync::LazyLock;
+use std::{env, sync::LazyLock};
 
 /// Route prefix for all oauth2_passkey endpoints
 ///
@@ -8,3 +8,33 @@ use std::sync::LazyLock;
 /// Default: "/o2p"
 pub static O2P_ROUTE_PREFIX: LazyLock<String> =
     LazyLock::new(|| std::env::var("O2P_ROUTE_PREFIX").unwrap_or_else(|_| "/o2p".to_string()));
+
+/// Fallback of AUTH_SERVER_SECRET, public and so only fit for development
+pub(crate) const DEFAULT_AUTH_SERVER_SECRET: &str = "default_secret_key_change_in_production";
+
+/// Server secret used to sign context tokens and to derive encryption keys
+pub(crate) static AUTH_SERVER_SECRET: LazyLock<Vec<u8>> =
+    LazyLock::new(|| match env::var("AUTH_SERVER_SECRET") {
+        Ok(secret) => secret.into_bytes(),
+        Err(_) => DEFAULT_AUTH_SERVER_SECRET.to_string().into_bytes(),
+    });
+
+/// Origins post-login redirects may go to: ORIGIN and the comma separated
+/// O2P_ALLOWED_RETURN_ORIGINS, e.g. "https://app.example.com"
+pub(crate) static O2P_RETURN_TO_ORIGINS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    env::var("ORIGIN")
+        .into_iter()
+        .chain(
+            env::var("O2P_ALLOWED_RETURN_ORIGINS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty()),
+        )
+        .map(|origin| {
+            url::Url::parse(&origin)
+                .map(|url| url.origin().ascii_serialization())
+                .unwrap_or_else(|_| panic!("Invalid return origin: {}", origin))
+        })
+        .collect()
+});
@@ -54,6 +54,14 @@ pub enum CoordinationError {
     #[error("Invalid state parameter")]
     InvalidState,
 
+    /// The provider has not verified the account's email address
+    #[error("Email address not verified: {0}")]
+    UnverifiedEmail(String),
+
+    /// The account's domain is not allowed to sign in with the provider
+    #[error("Sign-in not allowed for domain: {0}")]
+    DomainNotAllowed(String),
+
     /// Error from the user database operations
     #[error("User error: {0}")]
     UserError(UserError),
@@ -83,29 +91,24 @@ impl CoordinationError {
     ///
     /// # Example
     /// ```
-    /// fn some_function() -> Result<(), CoordinationError> {
-    ///     // Case 1: Creating an error directly with explicit logging
-    ///     if some_condition {
-    ///         // For simple variants without data
-    ///         return Err(CoordinationError::Unauthorized.log());
-    ///
-    ///         // For variants with data
-    ///         return Err(CoordinationError::ResourceNotFound {
-    ///             resource_type: "User".to_string(),
-    ///             resource_id: "123".to_string(),
-    ///         }.log());
+    /// use oauth2_passkey::CoordinationError;
     ///
-    ///         // For variants that wrap other errors
-    ///         let util_error = UtilError::SomeVariant("error message".to_string());
-    ///         return Err(CoordinationError::UtilsError(util_error).log());
+    /// fn find_user(id: &str) -> Result<(), CoordinationError> {
+    ///     // For simple variants without data
+    ///     if id.is_empty() {
+    ///         return Err(CoordinationError::Unauthorized.log());
     ///     }
     ///
-    ///     // Case 2: Using the ? operator with automatic logging
-    ///     // The From implementations automatically log errors when using ?
-    ///     let result = some_function_that_returns_result()?;
-    ///
-    ///     Ok(())
+    ///     // For variants with data
+    ///     Err(CoordinationError::ResourceNotFound {
+    ///         resource_type: "User".to_string(),
+    ///         resource_id: id.to_string(),
+    ///     }
+    ///     .log())
     /// }
+    ///
+    /// // Errors converted with `?` are logged by the From implementations instead
+    /// assert!(matches!(find_user(""), Err(CoordinationError::Unauthorized)));
     /// ```
     pub fn log(self) -> Self {
         match &self {
@@ -122,6 +125,12 @@ impl CoordinationError {
                 resource_id,
             } => tracing::error!("Resource not found: {} {}", resource_type, resource_id),
             Self::InvalidState => tracing::error!("Invalid state parameter"),
+            Self::UnverifiedEmail(email) => {
+                tracing::error!("Email address not verified: {}", email)
+            }
+            Self::DomainNotAllowed(domain) => {
+                tracing::error!("Sign-in not allowed for domain: {}", domain)
+            }
             Self::UserError(err) => tracing::error!("User error: {}", err),
             Self::OAuth2Error(err) => tracing::error!("OAuth2 error: {}", err),
             Self::PasskeyError(err) => tracing::error!("Passkey error: {}", err),
@@ -136,7 +145,11 @@ impl CoordinationError {
 
 impl From<OAuth2Error> for CoordinationError {
     fn from(err: OAuth2Error) -> Self {
-        let error = Self::OAuth2Error(err);
+        let error = match err {
+            // A policy decision rather than a protocol failure, so it gets its own variant
+            OAuth2Error::DomainNotAllowed(domain) => Self::DomainNotAllowed(domain),
+            err => Self::OAuth2Error(err),
+        };
         tracing::error!("{}", error);
         error
     }
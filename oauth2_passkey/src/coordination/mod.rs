@@ -3,15 +3,18 @@ mod oauth2;
 mod passkey;
 mod user;
 
-pub use oauth2::{delete_oauth2_account_core, list_accounts_core};
+pub use oauth2::{confirm_pending_link_core, delete_oauth2_account_core, list_accounts_core};
 
-pub use oauth2::{get_authorized_core, post_authorized_core};
+pub use oauth2::{
+    IdTokenCheck, IdTokenRequest, backchannel_logout_core, get_authorized_core,
+    id_token_nonce_core, id_token_sign_in_core, logout_core, post_authorized_core,
+};
 
 pub use passkey::{
     RegistrationStartRequest, delete_passkey_credential_core, handle_finish_authentication_core,
     handle_finish_registration_core, handle_start_authentication_core,
     handle_start_registration_core, list_credentials_core,
 };
-pub use user::{delete_user_account, update_user_account};
+pub use user::{UserDeletion, delete_user_account, update_user_account};
 
 pub use errors::CoordinationError;
@@ -1,30 +1,113 @@
 use chrono::{Duration, Utc};
 use http::HeaderMap;
-use std::env;
+use serde::{Deserialize, Serialize};
+use std::{env, sync::LazyLock};
 
 use crate::oauth2::{
-    AccountSearchField, AuthResponse, OAUTH2_AUTH_URL, OAUTH2_CSRF_COOKIE_NAME, OAuth2Account,
-    OAuth2Store, csrf_checks, decode_state, delete_session_and_misc_token_from_store,
-    get_idinfo_userinfo, get_uid_from_stored_session_by_state_param, validate_origin,
+    AccountSearchField, AuthResponse, AutoLinkPolicy, OAUTH2_CSRF_COOKIE_NAME, OAuth2Account,
+    OAuth2Store, auto_link_policy, check_error_response, csrf_checks, decode_state,
+    delete_session_and_misc_token_from_store, end_session_url, get_oauth2_account,
+    get_oauth2_account_from_id_token, get_uid_from_stored_session_by_state_param,
+    issue_id_token_nonce, revoke_oauth2_tokens, store_oauth2_tokens, validate_origin,
+    verify_backchannel_logout,
 };
 
 use crate::session::User as SessionUser;
+use crate::storage::{CacheData, GENERIC_CACHE_STORE};
 use crate::userdb::{User as DbUser, UserStore};
-use crate::utils::header_set_cookie;
+use crate::utils::{gen_random_string, header_set_cookie};
 
 use super::errors::CoordinationError;
 use super::user::gen_new_user_id;
 
-use crate::session::renew_session_header;
+use crate::session::{
+    delete_sessions_by_idp_session, get_idp_session, prepare_logout_response, renew_session_header,
+};
+
+/// How accounts with an email address the provider has not verified are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UnverifiedEmailPolicy {
+    /// Refuse to create a user or link the account
+    Reject,
+    /// Create or link the account, but don't derive the user's account or label from the email
+    Quarantine,
+}
+
+/// Handling of unverified email addresses, "reject" or "quarantine"
+/// Default: "quarantine"
+static OAUTH2_UNVERIFIED_EMAIL: LazyLock<UnverifiedEmailPolicy> =
+    LazyLock::new(|| match env::var("OAUTH2_UNVERIFIED_EMAIL").as_deref() {
+        Ok("reject") => UnverifiedEmailPolicy::Reject,
+        Ok("quarantine") | Err(_) => UnverifiedEmailPolicy::Quarantine,
+        Ok(policy) => panic!("Invalid OAUTH2_UNVERIFIED_EMAIL: {}", policy),
+    });
+
+/// Keep the changes of each account's profile as `profile_history` metadata
+/// Default: false
+static OAUTH2_PROFILE_HISTORY: LazyLock<bool> = LazyLock::new(|| {
+    env::var("OAUTH2_PROFILE_HISTORY")
+        .map(|v| v.to_lowercase() == "true")
+        .unwrap_or(false)
+});
+
+/// Changes kept in the profile history, older ones are dropped
+const PROFILE_HISTORY_LIMIT: usize = 20;
+
+/// Update the user's account and label from the profile at each sign-in,
+/// instead of only when the user is created
+/// Default: false
+static OAUTH2_USER_SYNC: LazyLock<bool> = LazyLock::new(|| {
+    env::var("OAUTH2_USER_SYNC")
+        .map(|v| v.to_lowercase() == "true")
+        .unwrap_or(false)
+});
+
+/// Double-submit token of Google One Tap, posted and set as a cookie by the widget
+const G_CSRF_TOKEN_NAME: &str = "g_csrf_token";
+
+/// An ID token posted by the client, e.g. by Google One Tap or a native app
+#[derive(Debug, Deserialize)]
+pub struct IdTokenRequest {
+    /// The ID token, as Google Identity Services names it
+    #[serde(alias = "id_token")]
+    pub credential: String,
+    /// One Tap's double-submit token, matching the `g_csrf_token` cookie
+    pub g_csrf_token: Option<String>,
+    /// Nonce from `id_token_nonce_core` the native app obtained the token with
+    pub nonce: Option<String>,
+}
+
+/// How the client shows the posted ID token was obtained for this sign-in
+#[derive(Debug, Clone, Copy)]
+pub enum IdTokenCheck<'a> {
+    /// Browsers: One Tap's `g_csrf_token`, posted with the token and set as a cookie
+    DoubleSubmit(&'a headers::Cookie),
+    /// Native apps: the token carries the posted nonce, issued by `id_token_nonce_core`
+    Nonce,
+}
+
+/// Cookie identifying the account waiting for the user to confirm the link
+const PENDING_LINK_COOKIE_NAME: &str = "__Host-PendingLink";
+/// Time the user has to sign in to the existing user and confirm the link
+const PENDING_LINK_TTL: u64 = 600;
+
+/// An account to be linked to the user with the same verified email address
+#[derive(Serialize, Deserialize)]
+struct PendingLink {
+    user_id: String,
+    account: OAuth2Account,
+}
 
 pub async fn get_authorized_core(
     auth_response: &AuthResponse,
     cookies: &headers::Cookie,
     headers: &HeaderMap,
 ) -> Result<(HeaderMap, String), CoordinationError> {
-    validate_origin(headers, OAUTH2_AUTH_URL.as_str()).await?;
+    let state_in_response = decode_state(&auth_response.state)?;
+    validate_origin(headers, &state_in_response.provider).await?;
 
     csrf_checks(cookies.clone(), auth_response, headers.clone()).await?;
+    check_error_response(auth_response).await?;
 
     process_oauth2_authorization(auth_response).await
 }
@@ -33,28 +116,24 @@ pub async fn post_authorized_core(
     auth_response: &AuthResponse,
     headers: &HeaderMap,
 ) -> Result<(HeaderMap, String), CoordinationError> {
-    validate_origin(headers, OAUTH2_AUTH_URL.as_str()).await?;
-
     if auth_response.state.is_empty() {
         return Err(CoordinationError::InvalidState);
     }
 
+    let state_in_response = decode_state(&auth_response.state)?;
+    validate_origin(headers, &state_in_response.provider).await?;
+    check_error_response(auth_response).await?;
+
     process_oauth2_authorization(auth_response).await
 }
 
 pub async fn process_oauth2_authorization(
     auth_response: &AuthResponse,
 ) -> Result<(HeaderMap, String), CoordinationError> {
-    let (idinfo, userinfo) = get_idinfo_userinfo(auth_response).await?;
-
-    // Convert GoogleUserInfo to DbUser and store it
-    static OAUTH2_GOOGLE_USER: &str = "idinfo";
-
-    let mut oauth2_account = match OAUTH2_GOOGLE_USER {
-        "idinfo" => OAuth2Account::from(idinfo),
-        "userinfo" => OAuth2Account::from(userinfo),
-        _ => OAuth2Account::from(idinfo), // Default case
-    };
+    // The provider in the state maps its ID token and userinfo claims into the account
+    let (oauth2_account, tokens, auth_time, idp_session) =
+        get_oauth2_account(auth_response).await?;
+    let account_key = oauth2_account.clone();
 
     // Upsert oauth2_account and user
     // 1. Decode the state from the auth response
@@ -76,7 +155,93 @@ pub async fn process_oauth2_authorization(
 
     // Extract user_id from the stored session if available
     let state_user = get_uid_from_stored_session_by_state_param(&state_in_response).await?;
+    let logged_in = state_user.is_some();
+
+    let (user_id, message) = match link_oauth2_account(oauth2_account, state_user).await? {
+        LinkResult::SignIn(user_id, message) => (user_id, message),
+        LinkResult::PendingLink(headers, message) => return Ok((headers, message)),
+    };
+    if logged_in {
+        delete_session_and_misc_token_from_store(&state_in_response).await?;
+    }
+
+    // Keep the tokens for offline access now that the account is stored
+    store_oauth2_tokens(&account_key, &tokens).await?;
+
+    let mut headers = renew_session_header(user_id, auth_time, idp_session).await?;
+
+    let _ = header_set_cookie(
+        &mut headers,
+        OAUTH2_CSRF_COOKIE_NAME.to_string(),
+        "value".to_string(),
+        Utc::now() - Duration::seconds(86400),
+        -86400,
+    )?;
+
+    Ok((headers, message))
+}
+
+/// Issue a nonce for a native app to obtain an ID token with, posted back with the token
+pub async fn id_token_nonce_core(provider: &str) -> Result<String, CoordinationError> {
+    Ok(issue_id_token_nonce(provider).await?)
+}
+
+/// Sign in with an ID token the client obtained itself, from Google One Tap or a native app
+///
+/// The token is only accepted with the proof of `check` that it was obtained for this
+/// sign-in, so another site can't sign the user in with its own token. The account is
+/// created or linked by email like at a sign-in through the authorization code flow.
+pub async fn id_token_sign_in_core(
+    provider: &str,
+    request: &IdTokenRequest,
+    check: IdTokenCheck<'_>,
+) -> Result<(HeaderMap, String), CoordinationError> {
+    let nonce = match check {
+        IdTokenCheck::DoubleSubmit(cookies) => {
+            let cookie_token = cookies.get(G_CSRF_TOKEN_NAME);
+            if cookie_token.is_none() || cookie_token != request.g_csrf_token.as_deref() {
+                return Err(CoordinationError::InvalidState);
+            }
+            None
+        }
+        IdTokenCheck::Nonce => match request.nonce.as_deref() {
+            Some(nonce) => Some(nonce),
+            None => return Err(CoordinationError::InvalidState),
+        },
+    };
+
+    let (oauth2_account, auth_time, idp_session) =
+        get_oauth2_account_from_id_token(provider, &request.credential, nonce).await?;
+
+    let (user_id, message) = match link_oauth2_account(oauth2_account, None).await? {
+        LinkResult::SignIn(user_id, message) => (user_id, message),
+        LinkResult::PendingLink(headers, message) => return Ok((headers, message)),
+    };
+    let headers = renew_session_header(user_id, auth_time, Some(idp_session)).await?;
+    Ok((headers, message))
+}
+
+/// Outcome of linking the account a provider authenticated
+enum LinkResult {
+    /// Sign in as the user, with a message for the user
+    SignIn(String, String),
+    /// The link waits for the user's confirmation, with the pending link cookie to set
+    PendingLink(HeaderMap, String),
+}
 
+/// Find or create the user of the account, linking it to the signed-in user if any
+async fn link_oauth2_account(
+    oauth2_account: OAuth2Account,
+    state_user: Option<SessionUser>,
+) -> Result<LinkResult, CoordinationError> {
+    link_oauth2_account_with_policy(oauth2_account, state_user, *OAUTH2_UNVERIFIED_EMAIL).await
+}
+
+async fn link_oauth2_account_with_policy(
+    mut oauth2_account: OAuth2Account,
+    state_user: Option<SessionUser>,
+    unverified_email: UnverifiedEmailPolicy,
+) -> Result<LinkResult, CoordinationError> {
     let (state_user_id, state_user_name) = match &state_user {
         Some(user) => (Some(user.id.clone()), Some(user.account.clone())),
         None => (None, None),
@@ -89,14 +254,20 @@ pub async fn process_oauth2_authorization(
     )
     .await?;
 
+    // Providers may send the name only once, a new account without one takes the email
+    if stored_oauth2_account.is_none() && oauth2_account.name.is_empty() {
+        oauth2_account.name = oauth2_account.email.clone();
+    }
+
     // Match on the combination of auth_user and existing_account
     let (user_id, message) = match (state_user_id, stored_oauth2_account) {
         // Case 1: User is logged in and account exists
         (Some(state_user_id), Some(stored_oauth2_account)) => {
             let message = if state_user_id == stored_oauth2_account.user_id {
+                sync_oauth2_account(stored_oauth2_account, &oauth2_account).await?;
                 let msg = format!(
                     "Already linked to current user {}",
-                    state_user_name.unwrap()
+                    state_user_name.unwrap_or_default()
                 );
                 tracing::debug!("{}", msg);
                 // Nothing to do, account is already properly linked
@@ -107,38 +278,229 @@ pub async fn process_oauth2_authorization(
                 // return Err((StatusCode::BAD_REQUEST, "This OAuth2 account is already linked to a different user".to_string()));
                 msg
             };
-            delete_session_and_misc_token_from_store(&state_in_response).await?;
             (state_user_id.to_string(), message)
         }
         // Case 2: User is logged in but account doesn't exist
         (Some(state_user_id), None) => {
-            let message = format!("Successfully linked to {}", state_user_name.unwrap());
+            check_unverified_email(&oauth2_account, unverified_email)?;
+            let message = format!(
+                "Successfully linked to {}",
+                state_user_name.unwrap_or_default()
+            );
             tracing::debug!("{}", message);
             oauth2_account.user_id = state_user_id.clone();
             OAuth2Store::upsert_oauth2_account(oauth2_account).await?;
-            delete_session_and_misc_token_from_store(&state_in_response).await?;
             (state_user_id.to_string(), message)
         }
         // Case 3: User is not logged in but account exists
         (None, Some(stored_oauth2_account)) => {
             let message = format!("Signing in as {}", stored_oauth2_account.name);
             tracing::debug!("{}", message);
-            (stored_oauth2_account.user_id, message)
+            let user_id = stored_oauth2_account.user_id.clone();
+            sync_oauth2_account(stored_oauth2_account, &oauth2_account).await?;
+            (user_id, message)
         }
         // Case 4: User is not logged in and account doesn't exist
         (None, None) => {
-            let name = oauth2_account.name.clone();
-            #[allow(clippy::let_and_return)]
-            let user_id = create_user_and_oauth2account(oauth2_account).await?;
-            let message = format!("Created {}", name);
-            tracing::debug!("{}", message);
-            (user_id, message)
+            // A trusted provider may have verified the same email address for an existing user
+            match find_user_by_verified_email(&oauth2_account).await? {
+                Some(user_id)
+                    if auto_link_policy(&oauth2_account.provider) == AutoLinkPolicy::Confirm =>
+                {
+                    let (headers, message) = stage_pending_link(user_id, oauth2_account).await?;
+                    return Ok(LinkResult::PendingLink(headers, message));
+                }
+                Some(user_id) => {
+                    let message =
+                        format!("Linked to the existing user of {}", oauth2_account.email);
+                    tracing::info!("{}: {}", message, user_id);
+                    oauth2_account.user_id = user_id.clone();
+                    OAuth2Store::upsert_oauth2_account(oauth2_account).await?;
+                    (user_id, message)
+                }
+                None => {
+                    check_unverified_email(&oauth2_account, unverified_email)?;
+                    let name = oauth2_account.name.clone();
+                    #[allow(clippy::let_and_return)]
+                    let user_id = create_user_and_oauth2account(oauth2_account).await?;
+                    let message = format!("Created {}", name);
+                    tracing::debug!("{}", message);
+                    (user_id, message)
+                }
+            }
         }
     };
+    Ok(LinkResult::SignIn(user_id, message))
+}
 
-    let mut headers = renew_session_header(user_id).await?;
+/// Refresh the stored account with the profile mapped at this sign-in
+///
+/// Changes are kept in the account's `profile_history` metadata if `OAUTH2_PROFILE_HISTORY`
+/// is set, and the user's account and label follow the profile if `OAUTH2_USER_SYNC` is set.
+async fn sync_oauth2_account(
+    mut stored_oauth2_account: OAuth2Account,
+    oauth2_account: &OAuth2Account,
+) -> Result<(), CoordinationError> {
+    let changes = stored_oauth2_account.merge_profile(oauth2_account);
+    if changes.is_empty() {
+        return Ok(());
+    }
+    tracing::debug!(
+        "Profile of {} changed: {:?}",
+        stored_oauth2_account.id,
+        changes.keys()
+    );
 
-    let _ = header_set_cookie(
+    if *OAUTH2_PROFILE_HISTORY
+        && let Some(metadata) = stored_oauth2_account.metadata.as_object_mut()
+    {
+        let mut history = match metadata.remove("profile_history") {
+            Some(serde_json::Value::Array(history)) => history,
+            _ => Vec::new(),
+        };
+        history.push(serde_json::json!({
+            "changed_at": Utc::now(),
+            "changes": changes,
+        }));
+        let excess = history.len().saturating_sub(PROFILE_HISTORY_LIMIT);
+        history.drain(..excess);
+        metadata.insert(
+            "profile_history".to_string(),
+            serde_json::Value::Array(history),
+        );
+    }
+
+    let stored_oauth2_account = OAuth2Store::upsert_oauth2_account(stored_oauth2_account).await?;
+
+    if *OAUTH2_USER_SYNC
+        && let Some(mut user) = UserStore::get_user(&stored_oauth2_account.user_id).await?
+    {
+        let (account, label) = get_account_and_label_from_oauth2_account(&stored_oauth2_account);
+        if user.account != account || user.label != label {
+            user.account = account;
+            user.label = label;
+            UserStore::upsert_user(user).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Log out of the local session and, if the user signed in with a provider that has
+/// RP-initiated logout enabled, return the URL ending the session at the provider too
+pub async fn logout_core(
+    cookies: headers::Cookie,
+) -> Result<(HeaderMap, Option<String>), CoordinationError> {
+    let end_session_url = match get_idp_session(&cookies).await? {
+        // The local session ends even if the provider can't be reached
+        Some(idp_session) => end_session_url(&idp_session).await.unwrap_or_else(|e| {
+            tracing::error!("Failed to build end session URL: {}", e);
+            None
+        }),
+        None => None,
+    };
+    let headers = prepare_logout_response(cookies).await?;
+    Ok((headers, end_session_url))
+}
+
+/// Delete the sessions of the user or IdP session a provider's logout token names
+/// (OIDC Back-Channel Logout 1.0)
+pub async fn backchannel_logout_core(
+    provider: &str,
+    logout_token: &str,
+) -> Result<(), CoordinationError> {
+    let logout_token = verify_backchannel_logout(provider, logout_token).await?;
+    let deleted = delete_sessions_by_idp_session(
+        provider,
+        logout_token.sub.as_deref(),
+        logout_token.sid.as_deref(),
+    )
+    .await?;
+    tracing::info!(
+        "Back-channel logout from {} ended {} session(s)",
+        provider,
+        deleted
+    );
+    Ok(())
+}
+
+/// Find the user to link a new account to by its verified email address
+///
+/// Only accounts of providers with an auto link policy are trusted to have verified their
+/// email addresses. Nothing is linked if the address belongs to more than one user.
+async fn find_user_by_verified_email(
+    oauth2_account: &OAuth2Account,
+) -> Result<Option<String>, CoordinationError> {
+    if auto_link_policy(&oauth2_account.provider) == AutoLinkPolicy::Off
+        || !oauth2_account.email_verified()
+        || oauth2_account.email.is_empty()
+    {
+        return Ok(None);
+    }
+
+    let mut user_ids: Vec<String> = OAuth2Store::get_oauth2_accounts_by(AccountSearchField::Email(
+        oauth2_account.email.clone(),
+    ))
+    .await?
+    .into_iter()
+    .filter(|account| {
+        account.email_verified() && auto_link_policy(&account.provider) != AutoLinkPolicy::Off
+    })
+    .map(|account| account.user_id)
+    .collect();
+    user_ids.sort();
+    user_ids.dedup();
+
+    match user_ids.as_slice() {
+        [user_id] => Ok(Some(user_id.clone())),
+        [] => Ok(None),
+        _ => {
+            tracing::warn!(
+                "Not linking {}, its email belongs to {} users",
+                oauth2_account.provider_user_id,
+                user_ids.len()
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Keep the account until the user confirms the link, see `confirm_pending_link_core`
+///
+/// No session is created, the user has to sign in to the existing user first.
+async fn stage_pending_link(
+    user_id: String,
+    account: OAuth2Account,
+) -> Result<(HeaderMap, String), CoordinationError> {
+    let message = format!(
+        "Sign in to your existing account of {} to link it",
+        account.email
+    );
+    let link_id = gen_random_string(32)?;
+    let pending = PendingLink { user_id, account };
+    let value = serde_json::to_string(&pending)
+        .map_err(|e| CoordinationError::Coordination(e.to_string()))?;
+
+    GENERIC_CACHE_STORE
+        .lock()
+        .await
+        .put_with_ttl(
+            "pending_link",
+            &link_id,
+            CacheData { value },
+            PENDING_LINK_TTL as usize,
+        )
+        .await
+        .map_err(|e| CoordinationError::Database(e.to_string()))?;
+
+    let mut headers = HeaderMap::new();
+    header_set_cookie(
+        &mut headers,
+        PENDING_LINK_COOKIE_NAME.to_string(),
+        link_id,
+        Utc::now() + Duration::seconds(PENDING_LINK_TTL as i64),
+        PENDING_LINK_TTL as i64,
+    )?;
+    header_set_cookie(
         &mut headers,
         OAUTH2_CSRF_COOKIE_NAME.to_string(),
         "value".to_string(),
@@ -149,6 +511,102 @@ pub async fn process_oauth2_authorization(
     Ok((headers, message))
 }
 
+/// Link the account waiting for confirmation to the signed in user
+///
+/// The account is only linked if the user is the one with the matching verified email.
+pub async fn confirm_pending_link_core(
+    user: Option<&SessionUser>,
+    cookies: &headers::Cookie,
+) -> Result<(HeaderMap, String), CoordinationError> {
+    let user = user.ok_or_else(|| CoordinationError::Unauthorized.log())?;
+    let link_id = cookies.get(PENDING_LINK_COOKIE_NAME).ok_or_else(|| {
+        CoordinationError::ResourceNotFound {
+            resource_type: "PendingLink".to_string(),
+            resource_id: String::new(),
+        }
+        .log()
+    })?;
+
+    let mut cache = GENERIC_CACHE_STORE.lock().await;
+    let pending: PendingLink = cache
+        .get("pending_link", link_id)
+        .await
+        .map_err(|e| CoordinationError::Database(e.to_string()))?
+        .map(|data| serde_json::from_str(&data.value))
+        .transpose()
+        .map_err(|e| CoordinationError::Coordination(e.to_string()))?
+        .ok_or_else(|| {
+            CoordinationError::ResourceNotFound {
+                resource_type: "PendingLink".to_string(),
+                resource_id: link_id.to_string(),
+            }
+            .log()
+        })?;
+    if pending.user_id != user.id {
+        return Err(CoordinationError::Unauthorized.log());
+    }
+    cache
+        .remove("pending_link", link_id)
+        .await
+        .map_err(|e| CoordinationError::Database(e.to_string()))?;
+    drop(cache);
+
+    let mut account = pending.account;
+    let message = match OAuth2Store::get_oauth2_account_by_provider(
+        &account.provider,
+        &account.provider_user_id,
+    )
+    .await?
+    {
+        // Signed up separately in the meantime
+        Some(stored) if stored.user_id != user.id => {
+            return Err(CoordinationError::Coordination(
+                "Already linked to a different user".to_string(),
+            )
+            .log());
+        }
+        Some(_) => format!("Already linked to current user {}", user.account),
+        None => {
+            account.user_id = user.id.clone();
+            OAuth2Store::upsert_oauth2_account(account).await?;
+            format!("Successfully linked to {}", user.account)
+        }
+    };
+
+    let mut headers = HeaderMap::new();
+    header_set_cookie(
+        &mut headers,
+        PENDING_LINK_COOKIE_NAME.to_string(),
+        "value".to_string(),
+        Utc::now() - Duration::seconds(86400),
+        -86400,
+    )?;
+    Ok((headers, message))
+}
+
+/// Apply the unverified email policy before a user is created or an account linked
+fn check_unverified_email(
+    oauth2_account: &OAuth2Account,
+    policy: UnverifiedEmailPolicy,
+) -> Result<(), CoordinationError> {
+    if oauth2_account.email_verified() || oauth2_account.email.is_empty() {
+        return Ok(());
+    }
+    match policy {
+        UnverifiedEmailPolicy::Reject => {
+            Err(CoordinationError::UnverifiedEmail(oauth2_account.email.clone()).log())
+        }
+        UnverifiedEmailPolicy::Quarantine => {
+            tracing::warn!(
+                "Unverified email {} of {} not used for the user",
+                oauth2_account.email,
+                oauth2_account.provider_user_id
+            );
+            Ok(())
+        }
+    }
+}
+
 // When creating a new user, map fields according to configuration or defaults
 // We also assign the user_id to the oauth2_account.
 async fn create_user_and_oauth2account(
@@ -173,18 +631,28 @@ fn get_account_and_label_from_oauth2_account(oauth2_account: &OAuth2Account) ->
     // Get field mappings from configuration
     let (account_field, label_field) = get_oauth2_field_mappings();
 
-    // Map fields based on configuration
-    let account = match account_field.as_str() {
-        "email" => oauth2_account.email.clone(),
-        "name" => oauth2_account.name.clone(),
-        _ => oauth2_account.email.clone(), // Default to email if invalid mapping
+    // An unverified email is never used to identify the user, the provider's ID is used instead
+    let email = if oauth2_account.email_verified() {
+        oauth2_account.email.clone()
+    } else {
+        oauth2_account.provider_user_id.clone()
     };
 
-    let label = match label_field.as_str() {
-        "email" => oauth2_account.email.clone(),
-        "name" => oauth2_account.name.clone(),
-        _ => oauth2_account.name.clone(), // Default to name if invalid mapping
+    // Map fields based on configuration, "metadata.{key}" takes a string from the metadata
+    let field = |field: &str| match field {
+        "email" => Some(email.clone()),
+        "name" => Some(oauth2_account.name.clone()),
+        field => field
+            .strip_prefix("metadata.")
+            .and_then(|key| oauth2_account.metadata.get(key))
+            .and_then(|value| value.as_str())
+            .filter(|value| !value.is_empty())
+            .map(String::from),
     };
+
+    // Default to email and name if the mapping is invalid or the metadata is missing
+    let account = field(&account_field).unwrap_or_else(|| email.clone());
+    let label = field(&label_field).unwrap_or_else(|| oauth2_account.name.clone());
     (account, label)
 }
 
@@ -200,11 +668,14 @@ fn get_oauth2_field_mappings() -> (String, String) {
 ///
 /// This function checks that the OAuth2 account belongs to the authenticated user
 /// before deleting it to prevent unauthorized deletions.
+///
+/// Returns a warning for the user if the grant couldn't be revoked at the provider,
+/// the account is deleted regardless.
 pub async fn delete_oauth2_account_core(
     user: Option<&SessionUser>,
     provider: &str,
     provider_user_id: &str,
-) -> Result<(), CoordinationError> {
+) -> Result<Option<String>, CoordinationError> {
     // Ensure user is authenticated
     let user = user.ok_or_else(|| CoordinationError::Unauthorized.log())?;
 
@@ -233,6 +704,12 @@ pub async fn delete_oauth2_account_core(
         return Err(CoordinationError::Unauthorized.log());
     }
 
+    // Revoke the grant at the provider, failures don't block the deletion
+    let warning = revoke_oauth2_tokens(&account)
+        .await
+        .err()
+        .map(|_| revocation_warning(provider));
+
     // Delete the OAuth2 account
     OAuth2Store::delete_oauth2_accounts_by(AccountSearchField::ProviderUserId(
         provider_user_id.to_string(),
@@ -245,7 +722,15 @@ pub async fn delete_oauth2_account_core(
         provider_user_id,
         user.id
     );
-    Ok(())
+    Ok(warning)
+}
+
+/// Warning for the user when the access granted at the provider is left in place
+pub(super) fn revocation_warning(provider: &str) -> String {
+    format!(
+        "The access granted at {} could not be revoked, please remove it in your {} account settings",
+        provider, provider
+    )
 }
 
 /// Get all OAuth2 accounts for a user
@@ -263,3 +748,332 @@ pub async fn list_accounts_core(
 
     get_oauth2_accounts(&user.id).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oauth2::OAuth2Error;
+    use crate::storage::GENERIC_DATA_STORE;
+    use crate::test_utils::{TEST_EC_KEY_PEM, init_test_stores, test_ec_jwk};
+    use crate::userdb::{DB_TABLE_USERS, User, UserStore};
+    use headers::HeaderMapExt;
+    use http::header::{COOKIE, SET_COOKIE};
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+
+    #[tokio::test]
+    async fn test_delete_oauth2_account_revocation_failure() {
+        init_test_stores().await;
+        let user = UserStore::upsert_user(User {
+            id: "revocation-user".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let account = OAuth2Store::upsert_oauth2_account(OAuth2Account {
+            user_id: user.id.clone(),
+            provider: "test-idp".to_string(),
+            provider_user_id: "test-idp_revocation".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let tokens = serde_json::from_value(serde_json::json!({
+            "access_token": "access",
+            "token_type": "Bearer",
+            "refresh_token": "refresh",
+        }))
+        .unwrap();
+        store_oauth2_tokens(&account, &tokens).await.unwrap();
+
+        let session_user = SessionUser::from(user);
+        let warning =
+            delete_oauth2_account_core(Some(&session_user), "test-idp", "test-idp_revocation")
+                .await
+                .unwrap();
+
+        // The revocation endpoint is unreachable, the account is deleted regardless
+        assert_eq!(warning, Some(revocation_warning("test-idp")));
+        assert!(
+            OAuth2Store::get_oauth2_account_by_provider("test-idp", "test-idp_revocation")
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            OAuth2Store::get_oauth2_tokens(&account.id)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    fn new_account(provider: &str, sub: &str, email: &str, verified: bool) -> OAuth2Account {
+        OAuth2Account {
+            provider: provider.to_string(),
+            provider_user_id: format!("{}_{}", provider, sub),
+            name: sub.to_string(),
+            email: email.to_string(),
+            metadata: serde_json::json!({ "verified_email": verified }),
+            ..Default::default()
+        }
+    }
+
+    async fn sign_in(account: OAuth2Account) -> String {
+        match link_oauth2_account(account, None).await.unwrap() {
+            LinkResult::SignIn(user_id, _) => user_id,
+            LinkResult::PendingLink(..) => panic!("Unexpected pending link"),
+        }
+    }
+
+    async fn session_user(user_id: &str) -> SessionUser {
+        UserStore::get_user(user_id).await.unwrap().unwrap().into()
+    }
+
+    #[tokio::test]
+    async fn test_new_account_without_name() {
+        init_test_stores().await;
+        let email = "no-name@example.com";
+        let account = OAuth2Account {
+            name: String::new(),
+            ..new_account("test-idp", "no-name", email, true)
+        };
+        sign_in(account.clone()).await;
+
+        let stored = OAuth2Store::get_oauth2_account_by_provider("test-idp", "test-idp_no-name");
+        assert_eq!(stored.await.unwrap().unwrap().name, email);
+    }
+
+    #[tokio::test]
+    async fn test_auto_link_unverified_email() {
+        init_test_stores().await;
+        let email = "unverified-link@example.com";
+        let user_id = sign_in(new_account("test-link", "unverified-1", email, true)).await;
+
+        // An unverified email of the new account doesn't link
+        let new_user_id = sign_in(new_account("test-link", "unverified-2", email, false)).await;
+        assert_ne!(new_user_id, user_id);
+
+        // Nor does an unverified email of the existing account
+        let email = "unverified-stored@example.com";
+        let user_id = sign_in(new_account("test-link", "unverified-3", email, false)).await;
+        let new_user_id = sign_in(new_account("test-link", "unverified-4", email, true)).await;
+        assert_ne!(new_user_id, user_id);
+    }
+
+    #[tokio::test]
+    async fn test_unverified_email_reject() {
+        init_test_stores().await;
+        let email = "unverified-reject@example.com";
+        let account = new_account("test-idp", "reject-1", email, false);
+        let result =
+            link_oauth2_account_with_policy(account, None, UnverifiedEmailPolicy::Reject).await;
+        assert!(matches!(result, Err(CoordinationError::UnverifiedEmail(e)) if e == email));
+
+        // Neither the account nor a user for it was created
+        let stored = OAuth2Store::get_oauth2_account_by_provider("test-idp", "test-idp_reject-1");
+        assert!(stored.await.unwrap().is_none());
+        let store = GENERIC_DATA_STORE.lock().await;
+        let query = format!("SELECT COUNT(*) FROM {} WHERE account = ?", *DB_TABLE_USERS);
+        let users: i64 = sqlx::query_scalar(&query)
+            .bind("test-idp_reject-1")
+            .fetch_one(store.as_sqlite().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(users, 0);
+        drop(store);
+
+        // A verified email is still accepted
+        let account = new_account("test-idp", "reject-2", email, true);
+        let result =
+            link_oauth2_account_with_policy(account, None, UnverifiedEmailPolicy::Reject).await;
+        assert!(matches!(result, Ok(LinkResult::SignIn(..))));
+    }
+
+    #[tokio::test]
+    async fn test_auto_link_policy() {
+        init_test_stores().await;
+        let email = "policy-link@example.com";
+        let user_id = sign_in(new_account("test-link", "policy-1", email, true)).await;
+
+        // Not configured for the provider
+        let new_user_id = sign_in(new_account("test-idp", "policy-2", email, true)).await;
+        assert_ne!(new_user_id, user_id);
+
+        // Configured with OAUTH2_TEST_LINK_AUTO_LINK=true
+        let new_user_id = sign_in(new_account("test-link", "policy-3", email, true)).await;
+        assert_eq!(new_user_id, user_id);
+    }
+
+    #[tokio::test]
+    async fn test_auto_link_confirm() {
+        init_test_stores().await;
+        let email = "confirm-link@example.com";
+        let user_id = sign_in(new_account("test-link", "confirm-1", email, true)).await;
+        let other_user_id = sign_in(new_account("test-link", "confirm-2", "", true)).await;
+
+        let account = new_account("test-confirm", "confirm-3", email, true);
+        let headers = match link_oauth2_account(account, None).await.unwrap() {
+            LinkResult::PendingLink(headers, _) => headers,
+            LinkResult::SignIn(..) => panic!("Linked without confirmation"),
+        };
+        let stored = || {
+            OAuth2Store::get_oauth2_account_by_provider("test-confirm", "test-confirm_confirm-3")
+        };
+        assert!(stored().await.unwrap().is_none());
+
+        let pending_link_cookie = headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok()?.split(';').next())
+            .find(|cookie| cookie.starts_with(PENDING_LINK_COOKIE_NAME))
+            .unwrap()
+            .to_string();
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(COOKIE, pending_link_cookie.parse().unwrap());
+        let cookies: headers::Cookie = request_headers.typed_get().unwrap();
+
+        // Only the user with the matching email can confirm
+        assert!(matches!(
+            confirm_pending_link_core(None, &cookies).await,
+            Err(CoordinationError::Unauthorized)
+        ));
+        let other_user = session_user(&other_user_id).await;
+        assert!(matches!(
+            confirm_pending_link_core(Some(&other_user), &cookies).await,
+            Err(CoordinationError::Unauthorized)
+        ));
+        assert!(stored().await.unwrap().is_none());
+
+        let user = session_user(&user_id).await;
+        confirm_pending_link_core(Some(&user), &cookies)
+            .await
+            .unwrap();
+        assert_eq!(stored().await.unwrap().unwrap().user_id, user_id);
+
+        // The pending link is used up
+        assert!(
+            confirm_pending_link_core(Some(&user), &cookies)
+                .await
+                .is_err()
+        );
+    }
+
+    async fn id_token(sub: &str, nonce: Option<&str>) -> String {
+        crate::oauth2::cache_test_jwks(
+            "https://idp.example.com/jwks",
+            serde_json::json!({ "keys": [test_ec_jwk("test-key")] }),
+        )
+        .await;
+        let now = Utc::now().timestamp();
+        let claims = serde_json::json!({
+            "iss": "https://idp.example.com",
+            "aud": "test-app",
+            "sub": sub,
+            "email": format!("{}@example.com", sub),
+            "email_verified": true,
+            "iat": now,
+            "exp": now + 300,
+            "nonce": nonce,
+        });
+        let header = Header {
+            kid: Some("test-key".to_string()),
+            ..Header::new(Algorithm::ES256)
+        };
+        let key = EncodingKey::from_ec_pem(TEST_EC_KEY_PEM.as_bytes()).unwrap();
+        jsonwebtoken::encode(&header, &claims, &key).unwrap()
+    }
+
+    fn id_token_request(
+        credential: String,
+        g_csrf_token: Option<&str>,
+        nonce: Option<&str>,
+    ) -> IdTokenRequest {
+        IdTokenRequest {
+            credential,
+            g_csrf_token: g_csrf_token.map(str::to_string),
+            nonce: nonce.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_id_token_sign_in_double_submit() {
+        init_test_stores().await;
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(COOKIE, "g_csrf_token=csrf-1".parse().unwrap());
+        let cookies: headers::Cookie = request_headers.typed_get().unwrap();
+        let check = IdTokenCheck::DoubleSubmit(&cookies);
+        let token = id_token("one-tap", None).await;
+
+        for g_csrf_token in [None, Some("csrf-2")] {
+            let request = id_token_request(token.clone(), g_csrf_token, None);
+            assert!(matches!(
+                id_token_sign_in_core("test-idp", &request, check).await,
+                Err(CoordinationError::InvalidState)
+            ));
+        }
+
+        // An issued nonce doesn't stand in for the double-submit token
+        let nonce = id_token_nonce_core("test-idp").await.unwrap();
+        let token_with_nonce = id_token("one-tap", Some(&nonce)).await;
+        let request = id_token_request(token_with_nonce, None, Some(&nonce));
+        assert!(matches!(
+            id_token_sign_in_core("test-idp", &request, check).await,
+            Err(CoordinationError::InvalidState)
+        ));
+
+        let request = id_token_request(token, Some("csrf-1"), None);
+        let (headers, _) = id_token_sign_in_core("test-idp", &request, check)
+            .await
+            .unwrap();
+        assert!(headers.contains_key(SET_COOKIE));
+    }
+
+    #[tokio::test]
+    async fn test_id_token_sign_in_nonce() {
+        init_test_stores().await;
+        let check = IdTokenCheck::Nonce;
+
+        // Without a nonce, or with one the server didn't issue
+        let token = id_token("native-app", Some("made-up")).await;
+        for nonce in [None, Some("made-up")] {
+            let request = id_token_request(token.clone(), None, nonce);
+            assert!(
+                id_token_sign_in_core("test-idp", &request, check)
+                    .await
+                    .is_err()
+            );
+        }
+
+        // The token carries another nonce, and the issued one is used up
+        let nonce = id_token_nonce_core("test-idp").await.unwrap();
+        let other_token = id_token("native-app", Some("other")).await;
+        let request = id_token_request(other_token, None, Some(&nonce));
+        assert!(matches!(
+            id_token_sign_in_core("test-idp", &request, check).await,
+            Err(CoordinationError::OAuth2Error(OAuth2Error::NonceMismatch))
+        ));
+        let request = id_token_request(
+            id_token("native-app", Some(&nonce)).await,
+            None,
+            Some(&nonce),
+        );
+        assert!(matches!(
+            id_token_sign_in_core("test-idp", &request, check).await,
+            Err(CoordinationError::OAuth2Error(OAuth2Error::NonceExpired))
+        ));
+
+        let nonce = id_token_nonce_core("test-idp").await.unwrap();
+        let token = id_token("native-app", Some(&nonce)).await;
+        let request = id_token_request(token, None, Some(&nonce));
+        let (headers, _) = id_token_sign_in_core("test-idp", &request, check)
+            .await
+            .unwrap();
+        assert!(headers.contains_key(SET_COOKIE));
+
+        // A nonce is accepted once
+        assert!(matches!(
+            id_token_sign_in_core("test-idp", &request, check).await,
+            Err(CoordinationError::OAuth2Error(OAuth2Error::NonceExpired))
+        ));
+    }
+}
@@ -119,7 +119,7 @@ pub async fn handle_finish_registration_core(
             match result {
                 Ok((message, stored_user_id)) => {
                     // Create session with the user_id
-                    let headers = renew_session_header(stored_user_id).await?;
+                    let headers = renew_session_header(stored_user_id, Utc::now(), None).await?;
 
                     Ok((headers, message))
                 }
@@ -208,7 +208,7 @@ pub async fn handle_finish_authentication_core(
     tracing::debug!("User ID: {:#?}", uid);
 
     // Create a session for the authenticated user
-    let headers = renew_session_header(uid.clone()).await?;
+    let headers = renew_session_header(uid.clone(), Utc::now(), None).await?;
 
     Ok((uid, name, headers))
 }
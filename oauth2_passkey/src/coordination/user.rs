@@ -1,9 +1,11 @@
-use crate::oauth2::{AccountSearchField, OAuth2Store};
+use crate::oauth2::{AccountSearchField, OAuth2Store, revoke_oauth2_tokens};
+use crate::oidc_provider::OidcConsentStore;
 use crate::passkey::{CredentialSearchField, PasskeyStore};
 use crate::userdb::{User, UserStore};
 use crate::utils::gen_random_string;
 
 use super::errors::CoordinationError;
+use super::oauth2::revocation_warning;
 
 /// Update a user's account and label
 pub async fn update_user_account(
@@ -33,10 +35,17 @@ pub async fn update_user_account(
     Ok(user)
 }
 
-/// Delete a user account and all associated OAuth2 accounts and Passkey credentials
-///
-/// Returns a list of deleted passkey credential IDs for client-side notification
-pub async fn delete_user_account(user_id: &str) -> Result<Vec<String>, CoordinationError> {
+/// What a deleted user leaves for the client to handle
+#[derive(Debug, Clone, Default)]
+pub struct UserDeletion {
+    /// Deleted passkey credential IDs, for client-side notification
+    pub credential_ids: Vec<String>,
+    /// Warnings for the user, e.g. about grants that couldn't be revoked at the providers
+    pub warnings: Vec<String>,
+}
+
+/// Delete a user account and all associated OAuth2 accounts, Passkey credentials and consents
+pub async fn delete_user_account(user_id: &str) -> Result<UserDeletion, CoordinationError> {
     // Check if the user exists
     let user = UserStore::get_user(user_id).await?.ok_or_else(|| {
         CoordinationError::ResourceNotFound {
@@ -57,16 +66,32 @@ pub async fn delete_user_account(user_id: &str) -> Result<Vec<String>, Coordinat
         .map(|c| c.credential_id.clone())
         .collect();
 
+    // Revoke the provider grants of the OAuth2 accounts, failures don't block the deletion
+    let mut warnings = Vec::new();
+    for account in OAuth2Store::get_oauth2_accounts(user_id).await? {
+        if revoke_oauth2_tokens(&account).await.is_err() {
+            warnings.push(revocation_warning(&account.provider));
+        }
+    }
+
     // Delete all OAuth2 accounts for this user
     OAuth2Store::delete_oauth2_accounts_by(AccountSearchField::UserId(user_id.to_string())).await?;
 
     // Delete all Passkey credentials for this user
     PasskeyStore::delete_credential_by(CredentialSearchField::UserId(user_id.to_string())).await?;
 
+    // Delete the consents given to the OIDC provider's clients
+    OidcConsentStore::delete_consents_by_user(user_id)
+        .await
+        .map_err(|e| CoordinationError::Database(e.to_string()).log())?;
+
     // Finally, delete the user account
     UserStore::delete_user(user_id).await?;
 
-    Ok(credential_ids)
+    Ok(UserDeletion {
+        credential_ids,
+        warnings,
+    })
 }
 
 // generate a unique user ID, with built-in collision detection
@@ -6,9 +6,12 @@
 mod config;
 mod coordination;
 mod oauth2;
+mod oidc_provider;
 mod passkey;
 mod session;
 mod storage;
+#[cfg(test)]
+mod test_utils;
 mod userdb;
 mod utils;
 
@@ -25,14 +28,27 @@ pub use coordination::{
 // };
 
 pub use coordination::{
-    delete_oauth2_account_core, delete_user_account, get_authorized_core, list_accounts_core,
-    post_authorized_core, update_user_account,
+    IdTokenCheck, IdTokenRequest, UserDeletion, backchannel_logout_core, confirm_pending_link_core,
+    delete_oauth2_account_core, delete_user_account, get_authorized_core, id_token_nonce_core,
+    id_token_sign_in_core, list_accounts_core, logout_core, post_authorized_core,
+    update_user_account,
 };
 
 // Re-export the route prefixes
 pub use config::O2P_ROUTE_PREFIX;
 
-pub use oauth2::{AuthResponse, OAuth2Account, OAuth2Error, prepare_oauth2_auth_request};
+pub use oauth2::{
+    AuthRequestOptions, AuthResponse, CallbackMode, OAuth2Account, OAuth2AuthRequest, OAuth2Error,
+    Prompt, get_callback_mode, get_fresh_access_token, get_oauth2_client_jwks, get_return_to,
+    list_oauth2_providers, prepare_oauth2_auth_request,
+};
+
+pub use oidc_provider::{
+    OidcAuthorizeRequest, OidcAuthorizeResponse, OidcConsentForm, OidcConsentPage,
+    OidcProviderError, OidcTokenRequest, OidcTokenResponse, get_oidc_provider_jwks,
+    get_oidc_provider_metadata, is_oidc_provider_enabled, oidc_authorize_core, oidc_consent_core,
+    oidc_token_core, oidc_userinfo_core,
+};
 
 pub use passkey::{
     AuthenticationOptions, AuthenticatorResponse, PasskeyCredential, RegisterCredential,
@@ -45,11 +61,14 @@ pub use session::{
     verify_context_token_and_page,
 };
 
+pub use utils::validate_return_to;
+
 /// Initialize the authentication coordination layer
 pub async fn init() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize the underlying stores
     userdb::init().await?;
     oauth2::init().await?;
     passkey::init().await?;
+    oidc_provider::init().await?;
     Ok(())
 }
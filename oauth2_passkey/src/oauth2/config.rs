@@ -1,22 +1,6 @@
 use crate::config::O2P_ROUTE_PREFIX;
 use std::{env, sync::LazyLock};
 
-pub(crate) static OAUTH2_USERINFO_URL: &str = "https://www.googleapis.com/userinfo/v2/me";
-
-pub static OAUTH2_AUTH_URL: LazyLock<String> = LazyLock::new(|| {
-    env::var("OAUTH2_AUTH_URL")
-        .ok()
-        .unwrap_or("https://accounts.google.com/o/oauth2/v2/auth".to_string())
-});
-pub(crate) static OAUTH2_TOKEN_URL: LazyLock<String> = LazyLock::new(|| {
-    env::var("OAUTH2_TOKEN_URL")
-        .ok()
-        .unwrap_or("https://oauth2.googleapis.com/token".to_string())
-});
-
-static OAUTH2_SCOPE: LazyLock<String> =
-    LazyLock::new(|| std::env::var("OAUTH2_SCOPE").unwrap_or("openid+email+profile".to_string()));
-
 static OAUTH2_RESPONSE_MODE: LazyLock<String> =
     LazyLock::new(|| std::env::var("OAUTH2_RESPONSE_MODE").unwrap_or("form_post".to_string()));
 
@@ -26,19 +10,26 @@ static OAUTH2_RESPONSE_TYPE: LazyLock<String> =
 pub(crate) static OAUTH2_QUERY_STRING: LazyLock<String> = LazyLock::new(|| {
     let mut query_string = "".to_string();
     query_string.push_str(&format!("&response_type={}", *OAUTH2_RESPONSE_TYPE));
-    query_string.push_str(&format!("&scope={}", *OAUTH2_SCOPE));
-    query_string.push_str(&format!("&response_mode={}", *OAUTH2_RESPONSE_MODE));
-    query_string.push_str(&format!("&access_type={}", "online"));
-    query_string.push_str(&format!("&prompt={}", "consent"));
     query_string
 });
 
+/// `response_mode` of the authorization request, unless the provider's extra parameters
+/// set their own, like Apple's `form_post`
+pub(crate) fn response_mode_param(extra_params: &str) -> String {
+    if extra_params
+        .split('&')
+        .any(|param| param.starts_with("response_mode="))
+    {
+        return String::new();
+    }
+    format!("&response_mode={}", *OAUTH2_RESPONSE_MODE)
+}
+
 // Supported parameters:
 // response_type: code
-// scope: openid+email+profile
 // response_mode: form_post, query
-// access_type: online, offline(for refresh token)
-// prompt: none, consent, select_account
+// Scope and provider specific parameters (e.g. access_type, prompt) are
+// configured per provider, see oauth2::provider.
 
 // "__Host-" prefix are added to make cookies "host-only".
 
@@ -63,10 +54,7 @@ pub(crate) static OAUTH2_REDIRECT_URI: LazyLock<String> = LazyLock::new(|| {
     )
 });
 
-pub(crate) static OAUTH2_GOOGLE_CLIENT_ID: LazyLock<String> = LazyLock::new(|| {
-    std::env::var("OAUTH2_GOOGLE_CLIENT_ID").expect("OAUTH2_GOOGLE_CLIENT_ID must be set")
-});
-
-pub(crate) static OAUTH2_GOOGLE_CLIENT_SECRET: LazyLock<String> = LazyLock::new(|| {
-    std::env::var("OAUTH2_GOOGLE_CLIENT_SECRET").expect("OAUTH2_GOOGLE_CLIENT_SECRET must be set")
-});
+/// Where the provider sends the user after RP-initiated logout, registered at the provider.
+/// The provider shows its own page if not set.
+pub(crate) static OAUTH2_POST_LOGOUT_REDIRECT_URI: LazyLock<Option<String>> =
+    LazyLock::new(|| env::var("OAUTH2_POST_LOGOUT_REDIRECT_URI").ok());
@@ -43,18 +43,61 @@ pub enum OAuth2Error {
     #[error("Id token error: {0}")]
     IdToken(String),
 
+    #[error("Tenant not allowed: {0}")]
+    TenantNotAllowed(String),
+
+    #[error("Domain not allowed: {0}")]
+    DomainNotAllowed(String),
+
+    #[error("Unsupported provider: {0}")]
+    UnsupportedProvider(String),
+
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("Discovery error: {0}")]
+    Discovery(String),
+
     #[error("Invalid origin: {0}")]
     InvalidOrigin(String),
 
     #[error("Decode state error: {0}")]
     DecodeState(String),
 
+    #[error("State expired")]
+    StateExpired,
+
     #[error("Fetch user info error: {0}")]
     FetchUserInfo(String),
 
+    /// The user or the provider declined the authorization
+    #[error("Access denied: {0}")]
+    AccessDenied(String),
+
+    /// Interaction with the user was needed, but the request had prompt=none
+    #[error("Interaction required: {0}")]
+    InteractionRequired(String),
+
+    /// Any other error response of the authorization endpoint
+    #[error("Authorization error: {0}")]
+    AuthorizationError(String),
+
     #[error("Token exchange error: {0}")]
     TokenExchange(String),
 
+    /// The refresh token is missing, expired or revoked
+    #[error("Invalid grant: {0}")]
+    InvalidGrant(String),
+
+    #[error("Invalid logout token: {0}")]
+    InvalidLogoutToken(String),
+
+    #[error("Pushed authorization request error: {0}")]
+    PushedAuthRequest(String),
+
+    #[error("Token revocation error: {0}")]
+    TokenRevocation(String),
+
     #[error("Database error: {0}")]
     Database(String),
 
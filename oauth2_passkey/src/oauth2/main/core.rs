@@ -1,28 +1,49 @@
 use headers::Cookie;
 use http::header::HeaderMap;
 
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
 use sha2::{Digest, Sha256};
+use url::form_urlencoded::byte_serialize;
 
 use crate::oauth2::config::{
-    OAUTH2_AUTH_URL, OAUTH2_CSRF_COOKIE_MAX_AGE, OAUTH2_CSRF_COOKIE_NAME, OAUTH2_GOOGLE_CLIENT_ID,
-    OAUTH2_QUERY_STRING, OAUTH2_REDIRECT_URI,
+    OAUTH2_CSRF_COOKIE_MAX_AGE, OAUTH2_CSRF_COOKIE_NAME, OAUTH2_QUERY_STRING, OAUTH2_REDIRECT_URI,
+    response_mode_param,
 };
 use crate::oauth2::errors::OAuth2Error;
-use crate::oauth2::types::{AuthResponse, GoogleUserInfo, StateParams, StoredToken};
-use crate::session::get_session_id_from_headers;
-use crate::utils::{base64url_encode, header_set_cookie};
+use crate::oauth2::provider::{ClaimMapping, ClaimTarget, OAuth2Provider, get_provider};
+use crate::oauth2::types::{
+    AuthRequestOptions, AuthResponse, CallbackMode, OAuth2Account, OAuth2AuthRequest,
+    OidcTokenResponse, StateParams, StoredToken,
+};
+use crate::session::{IdpSession, get_session_id_from_headers};
+use crate::storage::{CacheData, GENERIC_CACHE_STORE};
+use crate::utils::{base64url_encode, gen_random_string, header_set_cookie, validate_return_to};
 
-use super::google::{exchange_code_for_token, fetch_user_data_from_google};
-use super::idtoken::{IdInfo as GoogleIdInfo, verify_idtoken};
+use super::idtoken::{IdInfo, decode_claims, verify_idtoken};
+use super::par::{push_authorization_request, uses_pushed_auth_requests};
+use super::token::exchange_code_for_token;
 use super::utils::{
     decode_state, encode_state, generate_store_token, get_token_from_store,
     remove_token_from_store, store_token_in_cache,
 };
 
+/// Cache prefix of the `jti` of the ID tokens posted by clients, to refuse replays
+const ID_TOKEN_JTI_PREFIX: &str = "id_token_jti";
+/// Cache prefix of the nonces issued to native apps
+const ID_TOKEN_NONCE_PREFIX: &str = "id_token_nonce";
+/// Time a native app has to obtain an ID token with the nonce it was issued
+const ID_TOKEN_NONCE_TTL: u64 = 600;
+
+/// Build the authorization request for the provider, with the CSRF cookie to set
 pub async fn prepare_oauth2_auth_request(
     headers: HeaderMap,
-) -> Result<(String, HeaderMap), OAuth2Error> {
+    provider_name: &str,
+    options: &AuthRequestOptions,
+) -> Result<(OAuth2AuthRequest, HeaderMap), OAuth2Error> {
+    let provider = get_provider(provider_name)?;
+    let config = provider.config();
+    let metadata = provider.metadata().await?;
+
     let expires_at = Utc::now() + Duration::seconds((*OAUTH2_CSRF_COOKIE_MAX_AGE) as i64);
     let ttl = *OAUTH2_CSRF_COOKIE_MAX_AGE;
     let user_agent = headers
@@ -48,21 +69,68 @@ pub async fn prepare_oauth2_auth_request(
     let pkce_challenge = base64url_encode(Sha256::digest(pkce_token.as_bytes()).to_vec())?;
 
     tracing::debug!("PKCE Challenge: {:#?}", pkce_challenge);
+
+    // The configured scope is already form-urlencoded, e.g. "openid+email+profile"
+    let mut scopes: Vec<String> = config
+        .scope
+        .split('+')
+        .filter(|scope| !scope.is_empty())
+        .map(String::from)
+        .collect();
+    let mut scope_param = config.scope.clone();
+    for scope in &options.scopes {
+        if !scopes.contains(scope) {
+            validate_scope(scope, &config.extra_scopes)?;
+            scope_param.push_str(&format!("+{}", form_urlencode(scope)));
+            scopes.push(scope.clone());
+        }
+    }
+
+    let return_to = match &options.return_to {
+        Some(return_to) => Some(validate_return_to(return_to).ok_or_else(|| {
+            OAuth2Error::InvalidRequest(format!("return_to not allowed: {}", return_to))
+        })?),
+        None => None,
+    };
+
     let state_params = StateParams {
+        provider: provider.name().to_string(),
         csrf_token,
+        csrf_id: csrf_id.clone(),
         nonce_id,
         pkce_id,
         misc_id,
+        callback_mode: options.callback_mode,
+        scope: scopes.join(" "),
+        return_to,
+        expires_at: expires_at.timestamp(),
     };
 
     let encoded_state = encode_state(state_params)?;
 
-    let auth_url = format!(
-        "{}?{}&client_id={}&redirect_uri={}&state={}&nonce={}\
+    let mut request_params = String::new();
+    if let Some(prompt) = options.prompt.or(config.prompt) {
+        request_params.push_str(&format!("&prompt={}", prompt.as_str()));
+    }
+    if let Some(login_hint) = &options.login_hint {
+        request_params.push_str(&format!("&login_hint={}", form_urlencode(login_hint)));
+    }
+    if options.include_granted_scopes {
+        request_params.push_str("&include_granted_scopes=true");
+    }
+    if let Some(max_age) = config.max_age {
+        request_params.push_str(&format!("&max_age={}", max_age));
+    }
+
+    let params = format!(
+        "{}{}&scope={}{}{}&client_id={}&redirect_uri={}&state={}&nonce={}\
         &code_challenge={}&code_challenge_method={}",
-        OAUTH2_AUTH_URL.as_str(),
         OAUTH2_QUERY_STRING.as_str(),
-        OAUTH2_GOOGLE_CLIENT_ID.as_str(),
+        response_mode_param(&config.extra_params),
+        scope_param,
+        config.extra_params,
+        request_params,
+        config.client_id,
         OAUTH2_REDIRECT_URI.as_str(),
         encoded_state,
         nonce_token,
@@ -70,6 +138,13 @@ pub async fn prepare_oauth2_auth_request(
         "S256"
     );
 
+    // With PAR the parameters don't pass through the browser at all
+    let auth_url = if uses_pushed_auth_requests(provider, &metadata) {
+        push_authorization_request(provider, &metadata, &params).await?
+    } else {
+        format!("{}?{}", metadata.authorization_endpoint, params)
+    };
+
     tracing::debug!("Auth URL: {:#?}", auth_url);
 
     let mut headers = HeaderMap::new();
@@ -84,33 +159,452 @@ pub async fn prepare_oauth2_auth_request(
 
     tracing::debug!("Headers: {:#?}", headers);
 
-    Ok((auth_url, headers))
+    Ok((
+        OAuth2AuthRequest {
+            auth_url,
+            state: encoded_state,
+        },
+        headers,
+    ))
+}
+
+/// Check a requested scope against the scope-token syntax (RFC 6749, 3.3) and the
+/// provider's configured extra scopes
+fn validate_scope(scope: &str, extra_scopes: &[String]) -> Result<(), OAuth2Error> {
+    let valid = !scope.is_empty()
+        && scope
+            .chars()
+            .all(|c| c == '!' || ('#'..='[').contains(&c) || (']'..='~').contains(&c));
+    if !valid {
+        return Err(OAuth2Error::InvalidRequest(format!(
+            "Invalid scope: {}",
+            scope
+        )));
+    }
+    // Scopes come from the sign-in's query string, only configured ones are requested
+    if !extra_scopes.iter().any(|extra_scope| extra_scope == scope) {
+        return Err(OAuth2Error::InvalidRequest(format!(
+            "Scope not allowed: {}",
+            scope
+        )));
+    }
+    Ok(())
+}
+
+fn form_urlencode(value: &str) -> String {
+    byte_serialize(value.as_bytes()).collect()
+}
+
+/// Callback mode recorded in the state parameter, the popup if the state is invalid
+pub fn get_callback_mode(state: &str) -> CallbackMode {
+    decode_state(state)
+        .map(|state| state.callback_mode)
+        .unwrap_or_default()
 }
 
-pub async fn get_idinfo_userinfo(
+/// URL to send the user to after login recorded in the state parameter, if still allowed
+pub fn get_return_to(state: &str) -> Option<String> {
+    decode_state(state)
+        .ok()
+        .and_then(|state| state.return_to)
+        .and_then(|return_to| validate_return_to(&return_to))
+}
+
+/// Exchange the authorization code, verify the ID token and map the provider's
+/// claims into an OAuth2Account using the provider recorded in the state parameter
+///
+/// The token response is returned as well, to be stored once the account is stored,
+/// along with the time the user authenticated at the provider and, for OpenID Connect,
+/// the provider's session to record with the local session.
+pub async fn get_oauth2_account(
     auth_response: &AuthResponse,
-) -> Result<(GoogleIdInfo, GoogleUserInfo), OAuth2Error> {
+) -> Result<
+    (
+        OAuth2Account,
+        OidcTokenResponse,
+        DateTime<Utc>,
+        Option<IdpSession>,
+    ),
+    OAuth2Error,
+> {
+    let state_in_response = decode_state(&auth_response.state)?;
+    let provider = get_provider(&state_in_response.provider)?;
+    let metadata = provider.metadata().await?;
+
+    let code = auth_response
+        .code
+        .clone()
+        .ok_or_else(|| OAuth2Error::InvalidRequest("No code in auth response".to_string()))?;
     let pkce_verifier = get_pkce_verifier(auth_response).await?;
-    let (access_token, id_token) =
-        exchange_code_for_token(auth_response.code.clone(), pkce_verifier).await?;
+    let tokens = exchange_code_for_token(provider, &metadata, code, pkce_verifier).await?;
+
+    let idinfo = match tokens.id_token.clone() {
+        Some(id_token) => {
+            let config = provider.config();
+            let idinfo = verify_idtoken(
+                id_token,
+                Some(&tokens.access_token),
+                std::slice::from_ref(&config.client_id),
+                &metadata,
+                config,
+            )
+            .await
+            .map_err(|e| OAuth2Error::IdToken(e.to_string()))?;
+            verify_tenant(&config.allowed_tenants, &idinfo)?;
+            verify_nonce(auth_response, idinfo.clone()).await?;
+            Some(idinfo)
+        }
+        None if provider.issues_id_token() => {
+            return Err(OAuth2Error::TokenExchange(
+                "No id_token in token response".to_string(),
+            ));
+        }
+        None => {
+            // Plain OAuth2 providers ignore the nonce, so it is only cleaned up
+            let state_in_response = decode_state(&auth_response.state)?;
+            remove_token_from_store("nonce", &state_in_response.nonce_id).await?;
+            None
+        }
+    };
+
+    // The userinfo endpoint is optional in OIDC, the ID token alone is enough then
+    let mut userinfo = match (&metadata.userinfo_endpoint, &idinfo) {
+        (Some(userinfo_url), _) => {
+            let userinfo = provider
+                .fetch_userinfo(userinfo_url, &tokens.access_token)
+                .await?;
+            if let Some(idinfo) = &idinfo
+                && provider.userinfo_subject(&userinfo).as_deref() != Some(idinfo.sub.as_str())
+            {
+                tracing::error!(
+                    "Id mismatch in IdInfo and Userinfo: \nIdInfo: {:#?}\nUserInfo: {:#?}",
+                    idinfo,
+                    userinfo
+                );
+                return Err(OAuth2Error::IdMismatch);
+            }
+            userinfo
+        }
+        (None, Some(_)) => serde_json::json!({}),
+        (None, None) => {
+            return Err(OAuth2Error::FetchUserInfo(
+                "No userinfo endpoint configured".to_string(),
+            ));
+        }
+    };
+
+    // Sign in with Apple posts the user's name once, as a JSON `user` form field
+    if let Some(user) = &auth_response.user
+        && let Some(userinfo) = userinfo.as_object_mut()
+    {
+        match serde_json::from_str::<serde_json::Value>(user) {
+            Ok(user) => {
+                userinfo.insert("user".to_string(), user);
+            }
+            Err(e) => tracing::warn!("Ignoring invalid user field in auth response: {}", e),
+        }
+    }
+
+    let auth_time = auth_time(idinfo.as_ref());
+    let mut account = map_oauth2_account(
+        provider,
+        idinfo.as_ref(),
+        &userinfo,
+        tokens.id_token.as_deref(),
+    )?;
+    // Without a scope in the token response the requested scope is granted (RFC 6749, 5.1)
+    let granted_scopes: Vec<&str> = tokens
+        .scope
+        .as_deref()
+        .unwrap_or(&state_in_response.scope)
+        // GitHub separates the scopes by commas
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|scope| !scope.is_empty())
+        .collect();
+    if let Some(metadata) = account.metadata.as_object_mut() {
+        metadata.insert(
+            "granted_scopes".to_string(),
+            serde_json::json!(granted_scopes),
+        );
+    }
+    verify_domain(
+        &provider.config().allowed_domains,
+        idinfo.as_ref().and_then(|idinfo| idinfo.hd.as_deref()),
+        &account,
+    )?;
+
+    let idp_session = idinfo
+        .zip(tokens.id_token.clone())
+        .map(|(idinfo, id_token)| IdpSession {
+            provider: provider.name().to_string(),
+            sub: idinfo.sub,
+            sid: idinfo.sid,
+            id_token,
+        });
+    Ok((account, tokens, auth_time, idp_session))
+}
+
+/// Provider accepting ID tokens the client obtained itself
+///
+/// Only providers with `OAUTH2_{NAME}_ID_TOKEN_AUDIENCES` set accept these tokens.
+fn id_token_provider(provider_name: &str) -> Result<&'static dyn OAuth2Provider, OAuth2Error> {
+    let provider = get_provider(provider_name)?;
+    if !provider.issues_id_token() || provider.config().id_token_audiences.is_empty() {
+        return Err(OAuth2Error::UnsupportedProvider(format!(
+            "{} does not accept ID tokens",
+            provider_name
+        )));
+    }
+    Ok(provider)
+}
 
-    let idinfo = verify_idtoken(id_token, OAUTH2_GOOGLE_CLIENT_ID.to_string())
+/// Issue a nonce for a native app to obtain an ID token with
+///
+/// The app passes it to the provider's SDK and posts it along with the ID token, which
+/// must carry it as its `nonce` claim. Each nonce is accepted once.
+pub async fn issue_id_token_nonce(provider_name: &str) -> Result<String, OAuth2Error> {
+    let provider = id_token_provider(provider_name)?;
+    let nonce = gen_random_string(32)?;
+    GENERIC_CACHE_STORE
+        .lock()
         .await
-        .map_err(|e| OAuth2Error::IdToken(e.to_string()))?;
+        .put_with_ttl(
+            ID_TOKEN_NONCE_PREFIX,
+            &format!("{}:{}", provider.name(), nonce),
+            CacheData {
+                value: Utc::now().timestamp().to_string(),
+            },
+            ID_TOKEN_NONCE_TTL as usize,
+        )
+        .await
+        .map_err(|e| OAuth2Error::Storage(e.to_string()))?;
+    Ok(nonce)
+}
 
-    verify_nonce(auth_response, idinfo.clone()).await?;
+/// Take a nonce issued by `issue_id_token_nonce` out of the store
+async fn take_id_token_nonce(provider_name: &str, nonce: &str) -> Result<(), OAuth2Error> {
+    let key = format!("{}:{}", provider_name, nonce);
+    let mut store = GENERIC_CACHE_STORE.lock().await;
+    let issued = store
+        .get(ID_TOKEN_NONCE_PREFIX, &key)
+        .await
+        .map_err(|e| OAuth2Error::Storage(e.to_string()))?;
+    if issued.is_none() {
+        return Err(OAuth2Error::NonceExpired);
+    }
+    store
+        .remove(ID_TOKEN_NONCE_PREFIX, &key)
+        .await
+        .map_err(|e| OAuth2Error::Storage(e.to_string()))
+}
 
-    let userinfo = fetch_user_data_from_google(access_token).await?;
+/// Verify an ID token the client obtained itself, e.g. from Google One Tap or a native app,
+/// and map it into an account
+///
+/// The token may be issued to any of the client IDs in `OAUTH2_{NAME}_ID_TOKEN_AUDIENCES`.
+/// With a `nonce` from `issue_id_token_nonce`, the token must carry it. A token is accepted
+/// once only if it has a `jti`.
+pub async fn get_oauth2_account_from_id_token(
+    provider_name: &str,
+    id_token: &str,
+    nonce: Option<&str>,
+) -> Result<(OAuth2Account, DateTime<Utc>, IdpSession), OAuth2Error> {
+    let provider = id_token_provider(provider_name)?;
+    let config = provider.config();
+    // The nonce is used up even if the token turns out invalid
+    if let Some(nonce) = nonce {
+        take_id_token_nonce(provider.name(), nonce).await?;
+    }
+    let metadata = provider.metadata().await?;
+
+    let idinfo = verify_idtoken(
+        id_token.to_string(),
+        None,
+        &config.id_token_audiences,
+        &metadata,
+        config,
+    )
+    .await
+    .map_err(|e| OAuth2Error::IdToken(e.to_string()))?;
+    if nonce.is_some() && idinfo.nonce.as_deref() != nonce {
+        return Err(OAuth2Error::NonceMismatch);
+    }
+    verify_tenant(&config.allowed_tenants, &idinfo)?;
+
+    if let Some(jti) = &idinfo.jti {
+        // The token is remembered until it expires, after which it is refused anyway
+        let key = format!("{}:{}", provider.name(), jti);
+        let ttl = (idinfo.exp - Utc::now().timestamp()).max(0) as u64 + config.clock_skew;
+        let mut store = GENERIC_CACHE_STORE.lock().await;
+        if store
+            .get(ID_TOKEN_JTI_PREFIX, &key)
+            .await
+            .map_err(|e| OAuth2Error::Storage(e.to_string()))?
+            .is_some()
+        {
+            return Err(OAuth2Error::IdToken("ID token replayed".to_string()));
+        }
+        store
+            .put_with_ttl(
+                ID_TOKEN_JTI_PREFIX,
+                &key,
+                CacheData {
+                    value: idinfo.exp.to_string(),
+                },
+                ttl.max(1) as usize,
+            )
+            .await
+            .map_err(|e| OAuth2Error::Storage(e.to_string()))?;
+    }
 
-    if idinfo.sub != userinfo.id {
-        tracing::error!(
-            "Id mismatch in IdInfo and Userinfo: \nIdInfo: {:#?}\nUserInfo: {:#?}",
-            idinfo,
-            userinfo
-        );
-        return Err(OAuth2Error::IdMismatch);
+    let auth_time = auth_time(Some(&idinfo));
+    let account = map_oauth2_account(
+        provider,
+        Some(&idinfo),
+        &serde_json::json!({}),
+        Some(id_token),
+    )?;
+    verify_domain(&config.allowed_domains, idinfo.hd.as_deref(), &account)?;
+
+    let idp_session = IdpSession {
+        provider: provider.name().to_string(),
+        sub: idinfo.sub,
+        sid: idinfo.sid,
+        id_token: id_token.to_string(),
+    };
+    Ok((account, auth_time, idp_session))
+}
+
+/// Time the provider authenticated the user, now if the ID token has no auth_time
+fn auth_time(idinfo: Option<&IdInfo>) -> DateTime<Utc> {
+    idinfo
+        .and_then(|idinfo| idinfo.auth_time)
+        .and_then(|auth_time| DateTime::from_timestamp(auth_time, 0))
+        .unwrap_or_else(Utc::now)
+}
+
+/// Map the claims into an account, with the configured claim mapping over the provider's own
+fn map_oauth2_account(
+    provider: &dyn OAuth2Provider,
+    idinfo: Option<&IdInfo>,
+    userinfo: &serde_json::Value,
+    id_token: Option<&str>,
+) -> Result<OAuth2Account, OAuth2Error> {
+    let mut account = provider.to_oauth2_account(idinfo, userinfo)?;
+    let claim_map = &provider.config().claim_map;
+    if !claim_map.is_empty() {
+        // ID token claims take precedence over the userinfo response
+        let mut claims = userinfo.clone();
+        if let (Some(claims), Some(serde_json::Value::Object(id_claims))) =
+            (claims.as_object_mut(), id_token.and_then(decode_claims))
+        {
+            claims.extend(id_claims);
+        }
+        apply_claim_map(&mut account, claim_map, &claims);
+    }
+    Ok(account)
+}
+
+/// Look up a claim by its name, or as a dotted path into nested claims
+fn lookup_claim<'a>(claims: &'a serde_json::Value, claim: &str) -> Option<&'a serde_json::Value> {
+    if let Some(value) = claims.get(claim) {
+        return Some(value);
+    }
+    claim
+        .split('.')
+        .try_fold(claims, |value, key| value.get(key))
+}
+
+/// Override the provider's mapping of the account with the configured claims
+///
+/// Missing claims leave the field as it is. A remapped email is unverified unless
+/// `metadata.verified_email` is mapped as well, so it never counts for domain checks
+/// or auto linking on its own.
+fn apply_claim_map(
+    account: &mut OAuth2Account,
+    claim_map: &[ClaimMapping],
+    claims: &serde_json::Value,
+) {
+    let maps_verified_email = claim_map.iter().any(
+        |mapping| matches!(&mapping.target, ClaimTarget::Metadata(key) if key == "verified_email"),
+    );
+    for mapping in claim_map {
+        let Some(value) = lookup_claim(claims, &mapping.claim) else {
+            continue;
+        };
+        // Name, email and picture are only taken from non-empty strings
+        let text = value.as_str().filter(|s| !s.is_empty()).map(str::to_string);
+        match (&mapping.target, text) {
+            (ClaimTarget::Metadata(key), _) => {
+                if let Some(metadata) = account.metadata.as_object_mut() {
+                    metadata.insert(key.clone(), value.clone());
+                }
+            }
+            (_, None) => {}
+            (ClaimTarget::Name, Some(name)) => account.name = name,
+            (ClaimTarget::Email, Some(email)) => {
+                if account.email != email
+                    && !maps_verified_email
+                    && let Some(metadata) = account.metadata.as_object_mut()
+                {
+                    metadata.insert("verified_email".to_string(), serde_json::json!(false));
+                }
+                account.email = email;
+            }
+            (ClaimTarget::Picture, Some(picture)) => account.picture = Some(picture),
+        }
     }
-    Ok((idinfo, userinfo))
+}
+
+/// Accept tokens of multi-tenant providers from the allowed tenants only
+fn verify_tenant(allowed_tenants: &[String], idinfo: &IdInfo) -> Result<(), OAuth2Error> {
+    if allowed_tenants.is_empty() {
+        return Ok(());
+    }
+    match &idinfo.tid {
+        Some(tid) if allowed_tenants.contains(tid) => Ok(()),
+        tid => {
+            tracing::error!("Tenant not allowed: {:?}", tid);
+            Err(OAuth2Error::TenantNotAllowed(
+                tid.clone().unwrap_or_default(),
+            ))
+        }
+    }
+}
+
+/// Accept accounts of the allowed Google Workspace domains (`hd` claim of the ID token)
+/// or with a verified email address of the allowed domains only
+fn verify_domain(
+    allowed_domains: &[String],
+    hd: Option<&str>,
+    account: &OAuth2Account,
+) -> Result<(), OAuth2Error> {
+    if allowed_domains.is_empty() {
+        return Ok(());
+    }
+    let email_verified = account.email_verified();
+    let email_domain = account
+        .email
+        .rsplit_once('@')
+        .filter(|_| email_verified)
+        .map(|(_, domain)| domain);
+
+    if [hd, email_domain]
+        .into_iter()
+        .flatten()
+        .any(|domain| allowed_domains.contains(&domain.to_lowercase()))
+    {
+        return Ok(());
+    }
+    tracing::error!(
+        "Domain not allowed, hd: {:?}, email: {}, verified: {}",
+        hd,
+        account.email,
+        email_verified
+    );
+    Err(OAuth2Error::DomainNotAllowed(
+        hd.or(email_domain).unwrap_or_default().to_string(),
+    ))
 }
 
 async fn get_pkce_verifier(auth_response: &AuthResponse) -> Result<String, OAuth2Error> {
@@ -124,10 +618,7 @@ async fn get_pkce_verifier(auth_response: &AuthResponse) -> Result<String, OAuth
     Ok(pkce_session.token)
 }
 
-async fn verify_nonce(
-    auth_response: &AuthResponse,
-    idinfo: GoogleIdInfo,
-) -> Result<(), OAuth2Error> {
+async fn verify_nonce(auth_response: &AuthResponse, idinfo: IdInfo) -> Result<(), OAuth2Error> {
     let state_in_response = decode_state(&auth_response.state)?;
 
     let nonce_session: StoredToken =
@@ -151,6 +642,43 @@ async fn verify_nonce(
     Ok(())
 }
 
+/// Turn an error response of the authorization endpoint (RFC 6749, 4.1.2.1) into an error
+///
+/// The CSRF token, nonce, PKCE verifier and session reference stored for the flow are
+/// removed, the user's session is left as it is.
+pub async fn check_error_response(auth_response: &AuthResponse) -> Result<(), OAuth2Error> {
+    let Some(error) = &auth_response.error else {
+        return Ok(());
+    };
+    let state_in_response = decode_state(&auth_response.state)?;
+    remove_token_from_store("csrf", &state_in_response.csrf_id).await?;
+    remove_token_from_store("nonce", &state_in_response.nonce_id).await?;
+    remove_token_from_store("pkce", &state_in_response.pkce_id).await?;
+    if let Some(misc_id) = &state_in_response.misc_id {
+        remove_token_from_store("misc_session", misc_id).await?;
+    }
+
+    let description = match &auth_response.error_description {
+        Some(description) => format!("{}: {}", error, description),
+        None => error.clone(),
+    };
+    tracing::info!(
+        "Error response from {}: {}",
+        state_in_response.provider,
+        description
+    );
+    Err(match error.as_str() {
+        // Sign in with Apple reports a cancelled authorization as user_cancelled_authorize
+        "access_denied" | "user_cancelled_authorize" => OAuth2Error::AccessDenied(description),
+        // Errors of prompt=none requests (OIDC Core 1.0, 3.1.2.6)
+        "interaction_required"
+        | "login_required"
+        | "consent_required"
+        | "account_selection_required" => OAuth2Error::InteractionRequired(description),
+        _ => OAuth2Error::AuthorizationError(description),
+    })
+}
+
 pub async fn csrf_checks(
     cookies: Cookie,
     query: &AuthResponse,
@@ -201,3 +729,244 @@ pub async fn csrf_checks(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oauth2::types::Prompt;
+    use crate::test_utils::init_test_env;
+
+    #[test]
+    fn test_verify_tenant() {
+        let idinfo = |tid: Option<&str>| -> IdInfo {
+            serde_json::from_value(serde_json::json!({
+                "iss": "https://login.example.com/tenant-a/v2.0",
+                "sub": "alice",
+                "aud": "test-client",
+                "iat": 0,
+                "exp": 0,
+                "tid": tid,
+            }))
+            .unwrap()
+        };
+
+        // Any tenant without an allowlist
+        assert!(verify_tenant(&[], &idinfo(Some("tenant-b"))).is_ok());
+        assert!(verify_tenant(&[], &idinfo(None)).is_ok());
+
+        let allowed = ["tenant-a".to_string(), "tenant-c".to_string()];
+        assert!(verify_tenant(&allowed, &idinfo(Some("tenant-a"))).is_ok());
+        assert!(matches!(
+            verify_tenant(&allowed, &idinfo(Some("tenant-b"))),
+            Err(OAuth2Error::TenantNotAllowed(tid)) if tid == "tenant-b"
+        ));
+        assert!(matches!(
+            verify_tenant(&allowed, &idinfo(None)),
+            Err(OAuth2Error::TenantNotAllowed(tid)) if tid.is_empty()
+        ));
+    }
+
+    #[test]
+    fn test_validate_scope() {
+        let drive = "https://www.googleapis.com/auth/drive.file";
+        let extra_scopes = [drive, "read:user", "", "openid email", "a\"b"].map(String::from);
+        assert!(validate_scope(drive, &extra_scopes).is_ok());
+        assert!(validate_scope("read:user", &extra_scopes).is_ok());
+        assert!(validate_scope("", &extra_scopes).is_err());
+        assert!(validate_scope("openid email", &extra_scopes).is_err());
+        assert!(validate_scope("a\"b", &extra_scopes).is_err());
+        // Only configured scopes can be added
+        assert!(validate_scope("repo", &extra_scopes).is_err());
+        assert!(validate_scope(drive, &[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_prepare_oauth2_auth_request_options() {
+        init_test_env();
+        let options = AuthRequestOptions {
+            prompt: Some(Prompt::SelectAccount),
+            login_hint: Some("alice+work@example.com".to_string()),
+            scopes: vec![
+                "https://api.example.com/files.read".to_string(),
+                "groups".to_string(),
+                // Already configured, not added twice
+                "email".to_string(),
+            ],
+            include_granted_scopes: true,
+            ..Default::default()
+        };
+        let (request, _) = prepare_oauth2_auth_request(HeaderMap::new(), "test-idp", &options)
+            .await
+            .unwrap();
+        let query = request.auth_url.split_once('?').unwrap().1;
+        let params: Vec<&str> = query.split('&').collect();
+        for param in [
+            "prompt=select_account",
+            "login_hint=alice%2Bwork%40example.com",
+            "include_granted_scopes=true",
+        ] {
+            assert!(params.contains(&param), "{} in {}", param, query);
+        }
+        let scope = params
+            .iter()
+            .find_map(|p| p.strip_prefix("scope="))
+            .unwrap();
+        assert!(scope.ends_with("+https%3A%2F%2Fapi.example.com%2Ffiles.read+groups"));
+        assert_eq!(scope.matches("email").count(), 1);
+
+        // Scopes not configured as extra scopes are refused
+        let options = AuthRequestOptions {
+            scopes: vec!["admin".to_string()],
+            ..Default::default()
+        };
+        let result = prepare_oauth2_auth_request(HeaderMap::new(), "test-idp", &options).await;
+        assert!(matches!(result, Err(OAuth2Error::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn test_apply_claim_map() {
+        let mapping = |target: ClaimTarget, claim: &str| ClaimMapping {
+            target,
+            claim: claim.to_string(),
+        };
+        let claims = serde_json::json!({
+            "preferred_username": "jdoe",
+            "upn": "jdoe@corp.example.com",
+            "avatar_url": "",
+            "groups": ["admins"],
+            "address": { "country": "JP" },
+        });
+        let mut account = OAuth2Account {
+            name: "John".to_string(),
+            email: "john@example.com".to_string(),
+            picture: Some("https://example.com/john.png".to_string()),
+            metadata: serde_json::json!({ "verified_email": true }),
+            ..Default::default()
+        };
+
+        apply_claim_map(
+            &mut account,
+            &[
+                mapping(ClaimTarget::Name, "preferred_username"),
+                mapping(ClaimTarget::Email, "upn"),
+                mapping(ClaimTarget::Picture, "avatar_url"),
+                mapping(ClaimTarget::Metadata("groups".to_string()), "groups"),
+                mapping(
+                    ClaimTarget::Metadata("country".to_string()),
+                    "address.country",
+                ),
+                mapping(ClaimTarget::Metadata("missing".to_string()), "missing"),
+            ],
+            &claims,
+        );
+
+        assert_eq!(account.name, "jdoe");
+        assert_eq!(account.email, "jdoe@corp.example.com");
+        assert_eq!(
+            account.picture.as_deref(),
+            Some("https://example.com/john.png")
+        );
+        assert_eq!(
+            account.metadata,
+            serde_json::json!({
+                "verified_email": false,
+                "groups": ["admins"],
+                "country": "JP",
+            })
+        );
+    }
+
+    #[test]
+    fn test_verify_domain() {
+        let account = |email: &str, verified: bool| OAuth2Account {
+            email: email.to_string(),
+            metadata: serde_json::json!({ "verified_email": verified }),
+            ..Default::default()
+        };
+        let allowed = vec!["example.com".to_string()];
+
+        assert!(verify_domain(&[], None, &account("a@other.com", false)).is_ok());
+        assert!(verify_domain(&allowed, None, &account("a@Example.com", true)).is_ok());
+        assert!(verify_domain(&allowed, Some("example.com"), &account("a@x.com", false)).is_ok());
+        assert!(verify_domain(&allowed, None, &account("a@example.com", false)).is_err());
+        assert!(verify_domain(&allowed, Some("other.com"), &account("a@other.com", true)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check_error_response() {
+        init_test_env();
+        let expires_at = Utc::now() + Duration::seconds(600);
+        let (csrf_token, csrf_id) = generate_store_token("csrf", 600, expires_at, None)
+            .await
+            .unwrap();
+        let (_, nonce_id) = generate_store_token("nonce", 600, expires_at, None)
+            .await
+            .unwrap();
+        let (_, pkce_id) = generate_store_token("pkce", 600, expires_at, None)
+            .await
+            .unwrap();
+        let state = encode_state(StateParams {
+            provider: "test-idp".to_string(),
+            csrf_token,
+            csrf_id: csrf_id.clone(),
+            nonce_id: nonce_id.clone(),
+            pkce_id: pkce_id.clone(),
+            misc_id: None,
+            callback_mode: CallbackMode::Popup,
+            scope: "openid".to_string(),
+            return_to: None,
+            expires_at: expires_at.timestamp(),
+        })
+        .unwrap();
+        let response = |error: Option<&str>, description: Option<&str>| -> AuthResponse {
+            serde_json::from_value(serde_json::json!({
+                "state": state,
+                "code": error.is_none().then_some("code"),
+                "error": error,
+                "error_description": description,
+            }))
+            .unwrap()
+        };
+
+        assert!(check_error_response(&response(None, None)).await.is_ok());
+        assert!(
+            get_token_from_store::<StoredToken>("nonce", &nonce_id)
+                .await
+                .is_ok()
+        );
+
+        let result =
+            check_error_response(&response(Some("access_denied"), Some("Cancelled"))).await;
+        assert!(matches!(
+            result,
+            Err(OAuth2Error::AccessDenied(description)) if description == "access_denied: Cancelled"
+        ));
+        // The tokens of the flow are gone
+        for (token_type, token_id) in [("csrf", &csrf_id), ("nonce", &nonce_id), ("pkce", &pkce_id)]
+        {
+            assert!(
+                get_token_from_store::<StoredToken>(token_type, token_id)
+                    .await
+                    .is_err()
+            );
+        }
+
+        let result = check_error_response(&response(Some("user_cancelled_authorize"), None)).await;
+        assert!(matches!(result, Err(OAuth2Error::AccessDenied(_))));
+
+        // Errors of prompt=none requests
+        for error in ["login_required", "consent_required", "interaction_required"] {
+            let result = check_error_response(&response(Some(error), None)).await;
+            assert!(matches!(
+                result,
+                Err(OAuth2Error::InteractionRequired(description)) if description == error
+            ));
+        }
+
+        let result = check_error_response(&response(Some("server_error"), None)).await;
+        assert!(matches!(
+            result,
+            Err(OAuth2Error::AuthorizationError(description)) if description == "server_error"
+        ));
+    }
+}
@@ -2,19 +2,21 @@ use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
 use jsonwebtoken::{Algorithm, DecodingKey};
 use pkcs1::{EncodeRsaPublicKey, LineEnding};
 use rsa::RsaPublicKey;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, de::DeserializeOwned};
+use sha2::{Digest, Sha256, Sha384, Sha512};
 use std::{
     collections::HashMap,
-    sync::Arc,
+    sync::LazyLock,
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use thiserror::Error;
 
-use dashmap::DashMap;
-use moka::future::Cache;
-use once_cell::sync::Lazy;
 use tokio::sync::RwLock;
 
+use crate::oauth2::provider::{ProviderConfig, ProviderMetadata};
+
+use super::utils::get_client;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct Jwks {
     keys: Vec<Jwk>,
@@ -23,8 +25,8 @@ struct Jwks {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct Jwk {
     kty: String,
-    kid: String,
-    alg: String,
+    kid: Option<String>,
+    alg: Option<String>,
     n: Option<String>,
     e: Option<String>,
     x: Option<String>,
@@ -38,22 +40,88 @@ struct Jwk {
 pub struct IdInfo {
     pub iss: String,
     pub sub: String,
-    pub azp: String,
-    pub aud: String,
-    pub email: String,
-    pub email_verified: bool,
-    pub name: String,
+    pub azp: Option<String>,
+    #[serde(deserialize_with = "deserialize_string_or_vec")]
+    pub aud: Vec<String>,
+    pub email: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_bool_or_string")]
+    pub email_verified: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_bool_or_string")]
+    pub is_private_email: Option<bool>,
+    pub name: Option<String>,
     pub picture: Option<String>,
-    pub given_name: String,
-    pub family_name: String,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
     pub locale: Option<String>,
     pub iat: i64,
     pub exp: i64,
+    pub auth_time: Option<i64>,
     pub nbf: Option<i64>,
     pub jti: Option<String>,
     pub nonce: Option<String>,
     pub hd: Option<String>,
+    pub tid: Option<String>,
     pub at_hash: Option<String>,
+    /// Session ID at the provider, for back-channel logout
+    pub sid: Option<String>,
+}
+
+/// Event member of a logout token (OIDC Back-Channel Logout 1.0, 2.4)
+const BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+
+/// Claims of a back-channel logout token (OIDC Back-Channel Logout 1.0, 2.4)
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct LogoutToken {
+    pub(crate) iss: String,
+    pub(crate) sub: Option<String>,
+    #[serde(deserialize_with = "deserialize_string_or_vec")]
+    pub(crate) aud: Vec<String>,
+    pub(crate) iat: i64,
+    pub(crate) exp: i64,
+    pub(crate) jti: String,
+    pub(crate) events: HashMap<String, serde_json::Value>,
+    pub(crate) sid: Option<String>,
+    /// Tenant ID of a multi-tenant provider, filling the issuer's placeholder
+    tid: Option<String>,
+    nonce: Option<String>,
+}
+
+/// Apple sends boolean claims as "true"/"false" strings
+fn deserialize_bool_or_string<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrString {
+        Bool(bool),
+        String(String),
+    }
+
+    Ok(
+        Option::<BoolOrString>::deserialize(deserializer)?.map(|value| match value {
+            BoolOrString::Bool(b) => b,
+            BoolOrString::String(s) => s == "true",
+        }),
+    )
+}
+
+/// The audience is a single string or an array of strings (RFC 7519, 4.1.3)
+fn deserialize_string_or_vec<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrVec {
+        String(String),
+        Vec(Vec<String>),
+    }
+
+    Ok(match StringOrVec::deserialize(deserializer)? {
+        StringOrVec::String(s) => vec![s],
+        StringOrVec::Vec(v) => v,
+    })
 }
 
 #[derive(Error, Debug)]
@@ -74,6 +142,10 @@ pub enum TokenVerificationError {
     InvalidTokenSignature,
     #[error("Invalid token audience, expected: {0}, actual: {1}")]
     InvalidTokenAudience(String, String),
+    #[error("Invalid authorized party, expected: {0}, actual: {1:?}")]
+    InvalidAuthorizedParty(String, Option<String>),
+    #[error("Access token hash mismatch")]
+    InvalidAccessTokenHash,
     #[error("Invalid token issuer, expected: {0}, actual: {1}")]
     InvalidTokenIssuer(String, String),
     #[error("Token expired")]
@@ -82,127 +154,149 @@ pub enum TokenVerificationError {
     TokenNotYetValidNotBeFore(u64, u64),
     #[error("Token not yet valid, now: {0}, iat: {1}")]
     TokenNotYetValidIssuedAt(u64, u64),
+    #[error("No auth_time in token, required with max_age")]
+    AuthTimeMissing,
+    #[error("Authentication too old, auth_time: {0}, max_age: {1}")]
+    AuthTimeTooOld(u64, u64),
+    #[error("Invalid logout token: {0}")]
+    InvalidLogoutToken(String),
     #[error("No matching key found in JWKS")]
     NoMatchingKey,
     #[error("Missing key component: {0}")]
     MissingKeyComponent(String),
     #[error("Unsupported algorithm: {0}")]
     UnsupportedAlgorithm(String),
-    #[error("UTF-8 conversion error: {0}")]
-    Utf8Error(#[from] std::str::Utf8Error),
     #[error("System time error: {0}")]
     SystemTimeError(#[from] std::time::SystemTimeError),
     #[error("PKCS1 error: {0}")]
     Pkcs1Error(#[from] pkcs1::Error),
 }
 
-// Define a struct to hold the JWKS and its expiration time
+/// Lifetime of a key set whose response carries no usable `Cache-Control: max-age`
+const JWKS_DEFAULT_MAX_AGE: Duration = Duration::from_secs(3600);
+/// Bounds of the lifetime taken from `Cache-Control: max-age`
+const JWKS_MIN_MAX_AGE: Duration = Duration::from_secs(60);
+const JWKS_MAX_MAX_AGE: Duration = Duration::from_secs(86400);
+/// Minimum interval between fetches of a key set, limiting refetches on unknown key IDs
+const JWKS_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+
 struct CachedJwks {
     jwks: Jwks,
     expiration: Instant,
+    fetched_at: Instant,
 }
 
-const CACHE_MODE: &str = "moka";
-const CACHE_EXPIRATION: Duration = Duration::from_secs(600);
-
-async fn fetch_jwks(jwks_url: &str) -> Result<Jwks, TokenVerificationError> {
-    match CACHE_MODE {
-        "nocache" => fetch_jwks_nocache(jwks_url).await,
-        "dashmap" => fetch_jwks_dashmap(jwks_url).await,
-        "arc_rwlock_hashmap" => fetch_jwks_arh(jwks_url).await,
-        "moka" => fetch_jwks_moka(jwks_url).await,
-        _ => fetch_jwks_moka(jwks_url).await,
-    }
-}
-
-// 0. Without caching:
-async fn fetch_jwks_nocache(jwks_url: &str) -> Result<Jwks, TokenVerificationError> {
-    let resp = reqwest::get(jwks_url).await?;
-    let jwks: Jwks = resp.json().await?;
-    Ok(jwks)
-}
-
-// 1. DashMap + Lazy + RwLock:
-static JWKS_CACHE_DASHMAP: Lazy<DashMap<String, RwLock<CachedJwks>>> = Lazy::new(DashMap::new);
-
-async fn fetch_jwks_dashmap(jwks_url: &str) -> Result<Jwks, TokenVerificationError> {
-    // Check if the JWKS is in the cache and not expired
-    if let Some(cached) = JWKS_CACHE_DASHMAP.get(jwks_url) {
-        let cached_jwks = cached.value().read().await;
-        if cached_jwks.expiration > Instant::now() {
-            return Ok(cached_jwks.jwks.clone());
-        }
-    }
-
-    // If not in cache or expired, fetch from the URL
-    let resp = reqwest::get(jwks_url).await?;
-    let jwks: Jwks = resp.json().await?;
+static JWKS_CACHE: LazyLock<RwLock<HashMap<String, CachedJwks>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
 
-    // Update the cache
-    let cached_jwks = CachedJwks {
-        jwks: jwks.clone(),
-        expiration: Instant::now() + CACHE_EXPIRATION,
-    };
-    JWKS_CACHE_DASHMAP.insert(jwks_url.to_string(), RwLock::new(cached_jwks));
-
-    Ok(jwks)
-}
-
-// 2. Arc<RwLock<HashMap>> + tokio::time::Instant:
-static JWKS_CACHE_ARC_RWLOCK_HASHMAP: Lazy<Arc<RwLock<HashMap<String, CachedJwks>>>> =
-    Lazy::new(|| Arc::new(RwLock::new(HashMap::new())));
-
-async fn fetch_jwks_arh(jwks_url: &str) -> Result<Jwks, TokenVerificationError> {
-    // Try to get from cache first
+/// Find the key of the token in the provider's key set
+///
+/// The key set is cached for its `Cache-Control: max-age`. An unknown key ID triggers
+/// a refetch, at most once per `JWKS_REFETCH_INTERVAL`, to pick up rotated keys. If the
+/// provider can't be reached the last key set is used.
+async fn get_jwk(jwks_url: &str, kid: &str) -> Result<Jwk, TokenVerificationError> {
     {
-        let cache = JWKS_CACHE_ARC_RWLOCK_HASHMAP.read().await;
-        if let Some(cached) = cache.get(jwks_url) {
-            if cached.expiration > Instant::now() {
-                return Ok(cached.jwks.clone());
+        let cache = JWKS_CACHE.read().await;
+        if let Some(cached) = cache.get(jwks_url)
+            && cached.expiration > Instant::now()
+        {
+            if let Some(jwk) = find_jwk(&cached.jwks, kid) {
+                return Ok(jwk.clone());
+            }
+            if cached.fetched_at.elapsed() < JWKS_REFETCH_INTERVAL {
+                return Err(TokenVerificationError::NoMatchingKey);
             }
+            tracing::info!("Key {} not in cached JWKS of {}, refetching", kid, jwks_url);
         }
     } // The RwLock read guard is dropped here
 
-    // If not in cache or expired, fetch from the URL
-    let resp = reqwest::get(jwks_url).await?;
-    let jwks: Jwks = resp.json().await?;
+    // Fetching under the write lock lets concurrent requests share one refetch
+    let mut cache = JWKS_CACHE.write().await;
+    if let Some(cached) = cache.get(jwks_url)
+        && cached.fetched_at.elapsed() < JWKS_REFETCH_INTERVAL
+    {
+        return find_jwk(&cached.jwks, kid)
+            .cloned()
+            .ok_or(TokenVerificationError::NoMatchingKey);
+    }
+
+    match fetch_jwks(jwks_url).await {
+        Ok((jwks, max_age)) => {
+            let jwk = find_jwk(&jwks, kid).cloned();
+            let now = Instant::now();
+            cache.insert(
+                jwks_url.to_string(),
+                CachedJwks {
+                    jwks,
+                    expiration: now + max_age,
+                    fetched_at: now,
+                },
+            );
+            jwk.ok_or(TokenVerificationError::NoMatchingKey)
+        }
+        Err(e) => {
+            let Some(cached) = cache.get_mut(jwks_url) else {
+                return Err(e);
+            };
+            tracing::warn!("Failed to fetch JWKS {}, using stale keys: {}", jwks_url, e);
+            // Retry after the refetch interval rather than on every request
+            let now = Instant::now();
+            cached.fetched_at = now;
+            cached.expiration = cached.expiration.max(now + JWKS_REFETCH_INTERVAL);
+            find_jwk(&cached.jwks, kid)
+                .cloned()
+                .ok_or(TokenVerificationError::NoMatchingKey)
+        }
+    }
+}
 
-    // Update the cache
-    let mut cache = JWKS_CACHE_ARC_RWLOCK_HASHMAP.write().await;
-    cache.insert(
+/// Cache a key set as if it had just been fetched, for tests verifying tokens offline
+#[cfg(test)]
+pub(crate) async fn cache_test_jwks(jwks_url: &str, jwks: serde_json::Value) {
+    let now = Instant::now();
+    JWKS_CACHE.write().await.insert(
         jwks_url.to_string(),
         CachedJwks {
-            jwks: jwks.clone(),
-            expiration: Instant::now() + CACHE_EXPIRATION,
+            jwks: serde_json::from_value(jwks).expect("Invalid JWKS"),
+            expiration: now + JWKS_MAX_MAX_AGE,
+            fetched_at: now,
         },
     );
-
-    Ok(jwks)
 }
 
-// 3. moka::future::Cache:
-static JWKS_CACHE_MOKA: Lazy<Cache<String, Jwks>> = Lazy::new(|| {
-    Cache::builder()
-        .time_to_live(Duration::from_secs(3600))
-        .build()
-});
-
-async fn fetch_jwks_moka(jwks_url: &str) -> Result<Jwks, TokenVerificationError> {
-    if let Some(jwks) = JWKS_CACHE_MOKA.get(jwks_url).await {
-        return Ok(jwks);
-    }
-
-    let resp = reqwest::get(jwks_url).await?;
+/// Fetch the key set and its lifetime from the `Cache-Control` header
+async fn fetch_jwks(jwks_url: &str) -> Result<(Jwks, Duration), TokenVerificationError> {
+    let resp = get_client()
+        .get(jwks_url)
+        .send()
+        .await?
+        .error_for_status()?;
+    let max_age = resp
+        .headers()
+        .get(http::header::CACHE_CONTROL)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_max_age)
+        .map(|max_age| max_age.clamp(JWKS_MIN_MAX_AGE, JWKS_MAX_MAX_AGE))
+        .unwrap_or(JWKS_DEFAULT_MAX_AGE);
     let jwks: Jwks = resp.json().await?;
+    tracing::debug!("Fetched JWKS {}, max-age: {:?}", jwks_url, max_age);
+    Ok((jwks, max_age))
+}
 
-    JWKS_CACHE_MOKA
-        .insert(jwks_url.to_string(), jwks.clone())
-        .await;
-    Ok(jwks)
+/// Parse the `max-age` directive of a `Cache-Control` header value
+fn parse_max_age(cache_control: &str) -> Option<Duration> {
+    cache_control.split(',').find_map(|directive| {
+        let (name, value) = directive.trim().split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("max-age")
+            .then(|| value.trim().trim_matches('"').parse().ok())
+            .flatten()
+            .map(Duration::from_secs)
+    })
 }
 
 fn find_jwk<'a>(jwks: &'a Jwks, kid: &str) -> Option<&'a Jwk> {
-    jwks.keys.iter().find(|key| key.kid == kid)
+    jwks.keys.iter().find(|key| key.kid.as_deref() == Some(kid))
 }
 
 fn decode_base64_url_safe(input: &str) -> Result<Vec<u8>, TokenVerificationError> {
@@ -211,9 +305,35 @@ fn decode_base64_url_safe(input: &str) -> Result<Vec<u8>, TokenVerificationError
         .map_err(TokenVerificationError::from)
 }
 
-fn convert_jwk_to_decoding_key(jwk: &Jwk) -> Result<DecodingKey, TokenVerificationError> {
-    match jwk.alg.as_str() {
-        "RS256" | "RS384" | "RS512" => {
+fn convert_jwk_to_decoding_key(
+    jwk: &Jwk,
+    alg: Algorithm,
+) -> Result<DecodingKey, TokenVerificationError> {
+    // "alg" is optional in a JWK, but the key must not be used with another algorithm
+    if let Some(jwk_alg) = &jwk.alg
+        && jwk_alg.parse::<Algorithm>().ok() != Some(alg)
+    {
+        return Err(TokenVerificationError::UnsupportedAlgorithm(format!(
+            "{:?} with a {} key",
+            alg, jwk_alg
+        )));
+    }
+    // The curve has to match the algorithm as well
+    let expected_crv = match alg {
+        Algorithm::ES256 => Some("P-256"),
+        Algorithm::ES384 => Some("P-384"),
+        Algorithm::EdDSA => Some("Ed25519"),
+        _ => None,
+    };
+    if expected_crv.is_some() && jwk.crv.as_deref() != expected_crv {
+        return Err(TokenVerificationError::UnsupportedAlgorithm(format!(
+            "{:?} with curve {:?}",
+            alg, jwk.crv
+        )));
+    }
+
+    match jwk.kty.as_str() {
+        "RSA" => {
             let n = decode_base64_url_safe(
                 jwk.n
                     .as_ref()
@@ -231,44 +351,46 @@ fn convert_jwk_to_decoding_key(jwk: &Jwk) -> Result<DecodingKey, TokenVerificati
             let pem = rsa_public_key.to_pkcs1_pem(LineEnding::default())?;
             Ok(DecodingKey::from_rsa_pem(pem.as_bytes())?)
         }
-        "ES256" | "ES384" | "ES512" => {
-            let x = decode_base64_url_safe(
-                jwk.x
-                    .as_ref()
-                    .ok_or(TokenVerificationError::MissingKeyComponent("x".to_string()))?,
-            )?;
-            let x_str = std::str::from_utf8(&x)?;
-            let y = decode_base64_url_safe(
-                jwk.y
-                    .as_ref()
-                    .ok_or(TokenVerificationError::MissingKeyComponent("y".to_string()))?,
-            )?;
-            let y_str = std::str::from_utf8(&y)?;
-            Ok(DecodingKey::from_ec_components(x_str, y_str)?)
+        "EC" => {
+            // jsonwebtoken takes the base64url encoded coordinates as they are
+            let x = jwk
+                .x
+                .as_ref()
+                .ok_or(TokenVerificationError::MissingKeyComponent("x".to_string()))?;
+            let y = jwk
+                .y
+                .as_ref()
+                .ok_or(TokenVerificationError::MissingKeyComponent("y".to_string()))?;
+            Ok(DecodingKey::from_ec_components(x, y)?)
         }
-        "HS256" | "HS384" | "HS512" => {
-            let k = decode_base64_url_safe(
-                jwk.k
-                    .as_ref()
-                    .ok_or(TokenVerificationError::MissingKeyComponent("k".to_string()))?,
-            )?;
-            Ok(DecodingKey::from_secret(&k))
+        "OKP" => {
+            let x = jwk
+                .x
+                .as_ref()
+                .ok_or(TokenVerificationError::MissingKeyComponent("x".to_string()))?;
+            Ok(DecodingKey::from_ed_components(x)?)
         }
-        alg => Err(TokenVerificationError::UnsupportedAlgorithm(
-            alg.to_string(),
+        // Symmetric ("oct") keys are refused, ID tokens are verified with public keys only
+        kty => Err(TokenVerificationError::UnsupportedAlgorithm(
+            kty.to_string(),
         )),
     }
 }
 
-fn decode_token(token: &str) -> Result<IdInfo, TokenVerificationError> {
+/// Claims of an already verified ID token, for the configurable claim mapping
+pub(super) fn decode_claims(token: &str) -> Option<serde_json::Value> {
+    decode_token(token).ok()
+}
+
+fn decode_token<T: DeserializeOwned>(token: &str) -> Result<T, TokenVerificationError> {
     let parts: Vec<&str> = token.split('.').collect();
     if parts.len() != 3 {
         return Err(TokenVerificationError::InvalidTokenFormat);
     }
     let payload = parts[1];
     let decoded_payload = decode_base64_url_safe(payload)?;
-    let idinfo: IdInfo = serde_json::from_slice(&decoded_payload)?;
-    Ok(idinfo)
+    let claims: T = serde_json::from_slice(&decoded_payload)?;
+    Ok(claims)
 }
 
 fn verify_signature(
@@ -291,11 +413,50 @@ fn verify_signature(
     }
 }
 
-pub async fn verify_idtoken(
-    token: String,
-    audience: String,
-) -> Result<IdInfo, TokenVerificationError> {
-    let header = jsonwebtoken::decode_header(&token)?;
+/// Left half of the access token's hash, with the hash function of the signature
+/// algorithm, base64url encoded (OIDC Core 1.0, 3.1.3.6)
+fn access_token_hash(access_token: &str, alg: Algorithm) -> Result<String, TokenVerificationError> {
+    let digest = match alg {
+        Algorithm::RS256 | Algorithm::PS256 | Algorithm::ES256 => {
+            Sha256::digest(access_token.as_bytes()).to_vec()
+        }
+        Algorithm::RS384 | Algorithm::PS384 | Algorithm::ES384 => {
+            Sha384::digest(access_token.as_bytes()).to_vec()
+        }
+        // Ed25519 hashes with SHA-512
+        Algorithm::RS512 | Algorithm::PS512 | Algorithm::EdDSA => {
+            Sha512::digest(access_token.as_bytes()).to_vec()
+        }
+        alg => {
+            return Err(TokenVerificationError::UnsupportedAlgorithm(format!(
+                "{:?}",
+                alg
+            )));
+        }
+    };
+    Ok(URL_SAFE_NO_PAD.encode(&digest[..digest.len() / 2]))
+}
+
+/// Fill the tenant placeholder of a multi-tenant issuer with the `tid` claim,
+/// e.g. "https://login.microsoftonline.com/{tenantid}/v2.0" of Microsoft Entra ID
+fn expected_issuer(issuer: &str, tid: Option<&str>) -> String {
+    match tid {
+        Some(tid) => issuer.replace("{tid}", tid).replace("{tenantid}", tid),
+        None => issuer.to_string(),
+    }
+}
+
+/// Verify the signature of a JWT issued by the provider with its published keys
+///
+/// Only the provider's `id_token_signing_algs` are accepted, further narrowed down by the
+/// ones it advertises. Returns the algorithm of the signature.
+async fn verify_jwt_signature(
+    token: &str,
+    metadata: &ProviderMetadata,
+    config: &ProviderConfig,
+) -> Result<Algorithm, TokenVerificationError> {
+    let allowed_algs = &config.id_token_signing_algs;
+    let header = jsonwebtoken::decode_header(token)?;
 
     let kid = header
         .kid
@@ -303,59 +464,624 @@ pub async fn verify_idtoken(
             "kid".to_string(),
         ))?;
     let alg = header.alg;
-    let idinfo: IdInfo = decode_token(&token)?;
 
     tracing::debug!("Algorithm from JWT header: {:?}", alg);
-    tracing::debug!("Decoded id_token payload: {:#?}", idinfo);
 
-    let jwks_url = "https://www.googleapis.com/oauth2/v3/certs";
-    let jwks = fetch_jwks(jwks_url).await?;
-    let jwk = find_jwk(&jwks, &kid).ok_or(TokenVerificationError::NoMatchingKey)?;
+    // The header is untrusted, so the algorithm has to be one we expect
+    let supported_algs = &metadata.id_token_signing_alg_values_supported;
+    if !allowed_algs.contains(&alg)
+        || (!supported_algs.is_empty()
+            && !supported_algs
+                .iter()
+                .any(|supported| supported.parse::<Algorithm>().ok() == Some(alg)))
+    {
+        return Err(TokenVerificationError::UnsupportedAlgorithm(format!(
+            "{:?}",
+            alg
+        )));
+    }
+
+    let jwks_uri =
+        metadata
+            .jwks_uri
+            .as_deref()
+            .ok_or(TokenVerificationError::MissingKeyComponent(
+                "jwks_uri".to_string(),
+            ))?;
+    let jwk = get_jwk(jwks_uri, &kid).await?;
 
-    let decoding_key = convert_jwk_to_decoding_key(jwk)?;
+    let decoding_key = convert_jwk_to_decoding_key(&jwk, alg)?;
 
-    let signature_valid = verify_signature(&token, &decoding_key, alg)?;
+    let signature_valid = verify_signature(token, &decoding_key, alg)?;
     if !signature_valid {
         return Err(TokenVerificationError::InvalidTokenSignature);
     }
+    Ok(alg)
+}
 
-    if idinfo.aud != audience {
+/// Verify an ID token as specified in OIDC Core 1.0, 3.1.3.7
+///
+/// The token must be issued to one of the `audiences`, normally just the client ID.
+/// `at_hash` is checked against the access token if there is one, and
+/// `auth_time` against the provider's `max_age` if one is configured.
+pub(crate) async fn verify_idtoken(
+    token: String,
+    access_token: Option<&str>,
+    audiences: &[String],
+    metadata: &ProviderMetadata,
+    config: &ProviderConfig,
+) -> Result<IdInfo, TokenVerificationError> {
+    let issuer = metadata.issuer.as_str();
+    let alg = verify_jwt_signature(&token, metadata, config).await?;
+    let idinfo: IdInfo = decode_token(&token)?;
+
+    tracing::debug!("Decoded id_token payload: {:#?}", idinfo);
+
+    if !idinfo.aud.iter().any(|aud| audiences.contains(aud)) {
         return Err(TokenVerificationError::InvalidTokenAudience(
-            audience,
-            idinfo.aud.to_string(),
+            audiences.join(" "),
+            idinfo.aud.join(" "),
         ));
     }
 
-    let supplied_issuer = "https://accounts.google.com";
-    if idinfo.iss != supplied_issuer {
+    // The authorized party is required with multiple audiences and must be a client
+    if (idinfo.aud.len() > 1 || idinfo.azp.is_some())
+        && !idinfo
+            .azp
+            .as_ref()
+            .is_some_and(|azp| audiences.contains(azp))
+    {
+        return Err(TokenVerificationError::InvalidAuthorizedParty(
+            audiences.join(" "),
+            idinfo.azp.clone(),
+        ));
+    }
+
+    if let Some(at_hash) = &idinfo.at_hash
+        && let Some(access_token) = access_token
+        && *at_hash != access_token_hash(access_token, alg)?
+    {
+        return Err(TokenVerificationError::InvalidAccessTokenHash);
+    }
+
+    let issuer = expected_issuer(issuer, idinfo.tid.as_deref());
+    if idinfo.iss != issuer {
         return Err(TokenVerificationError::InvalidTokenIssuer(
+            issuer,
             idinfo.iss.to_string(),
-            supplied_issuer.to_string(),
         ));
     }
 
     let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-    let skew: u64 = 2; // allow 2 seconds of skew
-
-    if let Some(nbf) = idinfo.nbf {
-        if now + skew < nbf.try_into().unwrap() {
-            // tolerate the system clock to be the skew seconds behind
-            return Err(TokenVerificationError::TokenNotYetValidNotBeFore(
-                now,
-                nbf.try_into().unwrap(),
-            ));
-        }
+    let skew = config.clock_skew;
+
+    // Times before the epoch aren't valid NumericDates
+    let timestamp =
+        |t: i64| u64::try_from(t).map_err(|_| TokenVerificationError::InvalidTokenFormat);
+
+    if let Some(nbf) = idinfo.nbf.map(timestamp).transpose()?
+        && now + skew < nbf
+    {
+        // tolerate the system clock to be the skew seconds behind
+        return Err(TokenVerificationError::TokenNotYetValidNotBeFore(now, nbf));
     }
 
-    if now + skew < idinfo.iat.try_into().unwrap() {
+    let iat = timestamp(idinfo.iat)?;
+    if now + skew < iat {
         // tolerate the system clock to be the skew seconds behind
+        return Err(TokenVerificationError::TokenNotYetValidIssuedAt(now, iat));
+    } else if now > idinfo.exp.try_into().unwrap_or(0) + skew {
+        return Err(TokenVerificationError::TokenExpired);
+    }
+
+    // The IdP session must not be older than max_age (OIDC Core 1.0, 3.1.2.1)
+    if let Some(max_age) = config.max_age {
+        let auth_time = idinfo
+            .auth_time
+            .and_then(|t| u64::try_from(t).ok())
+            .ok_or(TokenVerificationError::AuthTimeMissing)?;
+        if now > auth_time + max_age + skew {
+            return Err(TokenVerificationError::AuthTimeTooOld(auth_time, max_age));
+        }
+    }
+
+    Ok(idinfo)
+}
+
+/// Verify a back-channel logout token as specified in OIDC Back-Channel Logout 1.0, 2.6
+///
+/// The token is signed like the provider's ID tokens. Replays are detected by the caller,
+/// by the `jti` claim.
+pub(crate) async fn verify_logout_token(
+    token: &str,
+    metadata: &ProviderMetadata,
+    config: &ProviderConfig,
+) -> Result<LogoutToken, TokenVerificationError> {
+    let invalid = |reason: &str| TokenVerificationError::InvalidLogoutToken(reason.to_string());
+
+    // Logout tokens may be explicitly typed, keeping them apart from ID tokens (2.4)
+    let header = jsonwebtoken::decode_header(token)?;
+    if header.typ.as_deref().is_some_and(|typ| {
+        !typ.eq_ignore_ascii_case("logout+jwt") && !typ.eq_ignore_ascii_case("JWT")
+    }) {
+        return Err(invalid("unexpected typ"));
+    }
+
+    verify_jwt_signature(token, metadata, config).await?;
+    let logout_token: LogoutToken = decode_token(token)?;
+
+    let issuer = expected_issuer(&metadata.issuer, logout_token.tid.as_deref());
+    if logout_token.iss != issuer {
+        return Err(TokenVerificationError::InvalidTokenIssuer(
+            issuer,
+            logout_token.iss,
+        ));
+    }
+    if !logout_token.aud.contains(&config.client_id) {
+        return Err(TokenVerificationError::InvalidTokenAudience(
+            config.client_id.clone(),
+            logout_token.aud.join(" "),
+        ));
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let skew = config.clock_skew;
+    if now + skew < logout_token.iat.try_into().unwrap_or(0) {
         return Err(TokenVerificationError::TokenNotYetValidIssuedAt(
             now,
-            idinfo.iat.try_into().unwrap(),
+            logout_token.iat.try_into().unwrap_or(0),
         ));
-    } else if now > idinfo.exp.try_into().unwrap() {
+    }
+    if now > logout_token.exp.try_into().unwrap_or(0) + skew {
         return Err(TokenVerificationError::TokenExpired);
     }
 
-    Ok(idinfo)
+    if !logout_token
+        .events
+        .get(BACKCHANNEL_LOGOUT_EVENT)
+        .is_some_and(|event| event.is_object())
+    {
+        return Err(invalid("no back-channel logout event"));
+    }
+    if logout_token.sub.is_none() && logout_token.sid.is_none() {
+        return Err(invalid("neither sub nor sid"));
+    }
+    // A nonce would make it an ID token
+    if logout_token.nonce.is_some() {
+        return Err(invalid("nonce present"));
+    }
+
+    Ok(logout_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oauth2::provider::get_provider;
+    use crate::test_utils::{TEST_EC_KEY_PEM, init_test_env, serve_http, test_ec_jwk};
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::{Value, json};
+
+    /// Metadata and configuration of test-idp, publishing the test key at its own JWKS URL
+    async fn setup(jwks_uri: &str) -> (ProviderMetadata, ProviderConfig) {
+        init_test_env();
+        let provider = get_provider("test-idp").unwrap();
+        let mut metadata = provider.metadata().await.unwrap();
+        metadata.jwks_uri = Some(jwks_uri.to_string());
+        cache_test_jwks(jwks_uri, json!({ "keys": [test_ec_jwk("test-key")] })).await;
+        (metadata, provider.config().clone())
+    }
+
+    fn now() -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+
+    fn id_claims() -> Value {
+        json!({
+            "iss": "https://idp.example.com",
+            "aud": "test-client",
+            "sub": "alice",
+            "iat": now(),
+            "exp": now() + 300,
+        })
+    }
+
+    fn sign(claims: &Value) -> String {
+        let header = Header {
+            kid: Some("test-key".to_string()),
+            ..Header::new(Algorithm::ES256)
+        };
+        let key = EncodingKey::from_ec_pem(TEST_EC_KEY_PEM.as_bytes()).unwrap();
+        jsonwebtoken::encode(&header, claims, &key).unwrap()
+    }
+
+    async fn verify(
+        claims: &Value,
+        metadata: &ProviderMetadata,
+        config: &ProviderConfig,
+    ) -> Result<IdInfo, TokenVerificationError> {
+        let audiences = [config.client_id.clone()];
+        verify_idtoken(sign(claims), None, &audiences, metadata, config).await
+    }
+
+    #[test]
+    fn test_expected_issuer() {
+        let issuer = "https://login.microsoftonline.com/{tenantid}/v2.0";
+        assert_eq!(
+            expected_issuer(issuer, Some("tenant-a")),
+            "https://login.microsoftonline.com/tenant-a/v2.0"
+        );
+        assert_eq!(
+            expected_issuer("https://sts.example.com/{tid}/", Some("tenant-a")),
+            "https://sts.example.com/tenant-a/"
+        );
+        assert_eq!(expected_issuer(issuer, None), issuer);
+        assert_eq!(
+            expected_issuer("https://idp.example.com", Some("tenant-a")),
+            "https://idp.example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_idtoken_tenant_issuer() {
+        let (mut metadata, config) = setup("https://idp.example.com/jwks/tenant").await;
+        metadata.issuer = "https://login.example.com/{tenantid}/v2.0".to_string();
+        let claims = |iss: &str, tid: Option<&str>| {
+            let mut claims = id_claims();
+            claims["iss"] = json!(iss);
+            claims["tid"] = json!(tid);
+            claims
+        };
+        let tenant_issuer = "https://login.example.com/tenant-a/v2.0";
+
+        let idinfo = verify(&claims(tenant_issuer, Some("tenant-a")), &metadata, &config)
+            .await
+            .unwrap();
+        assert_eq!(idinfo.tid.as_deref(), Some("tenant-a"));
+
+        // The issuer must be the one of the token's own tenant
+        for tid in [Some("tenant-b"), None] {
+            assert!(matches!(
+                verify(&claims(tenant_issuer, tid), &metadata, &config).await,
+                Err(TokenVerificationError::InvalidTokenIssuer(..))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_logout_token_tenant_issuer() {
+        let (mut metadata, config) = setup("https://idp.example.com/jwks/logout-tenant").await;
+        metadata.issuer = "https://login.example.com/{tenantid}/v2.0".to_string();
+        let claims = |iss: &str, tid: Option<&str>| {
+            json!({
+                "iss": iss,
+                "aud": "test-client",
+                "iat": now(),
+                "exp": now() + 120,
+                "jti": "logout-tenant",
+                "events": { BACKCHANNEL_LOGOUT_EVENT: {} },
+                "sid": "sid-1",
+                "tid": tid,
+            })
+        };
+        let tenant_issuer = "https://login.example.com/tenant-a/v2.0";
+
+        let token = sign(&claims(tenant_issuer, Some("tenant-a")));
+        let logout_token = verify_logout_token(&token, &metadata, &config)
+            .await
+            .unwrap();
+        assert_eq!(logout_token.tid.as_deref(), Some("tenant-a"));
+
+        for tid in [Some("tenant-b"), None] {
+            let token = sign(&claims(tenant_issuer, tid));
+            assert!(matches!(
+                verify_logout_token(&token, &metadata, &config).await,
+                Err(TokenVerificationError::InvalidTokenIssuer(..))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_idtoken_authorized_party() {
+        let (metadata, config) = setup("https://idp.example.com/jwks/azp").await;
+        let claims = |aud: Value, azp: Option<&str>| {
+            let mut claims = id_claims();
+            claims["aud"] = aud;
+            if let Some(azp) = azp {
+                claims["azp"] = json!(azp);
+            }
+            claims
+        };
+        let audiences = json!(["test-client", "other-client"]);
+
+        let idinfo = verify(
+            &claims(audiences.clone(), Some("test-client")),
+            &metadata,
+            &config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(idinfo.azp.as_deref(), Some("test-client"));
+
+        // Required with multiple audiences, and must be one of our client IDs
+        for (aud, azp) in [
+            (audiences.clone(), None),
+            (audiences, Some("other-client")),
+            (json!("test-client"), Some("other-client")),
+        ] {
+            assert!(matches!(
+                verify(&claims(aud, azp), &metadata, &config).await,
+                Err(TokenVerificationError::InvalidAuthorizedParty(..))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_idtoken_header_alg() {
+        let (metadata, config) = setup("https://idp.example.com/jwks/alg").await;
+
+        // ES256 is valid for the key, but not one of the provider's algorithms
+        let rs256_only = ProviderConfig {
+            id_token_signing_algs: vec![Algorithm::RS256],
+            ..config.clone()
+        };
+        assert!(matches!(
+            verify(&id_claims(), &metadata, &rs256_only).await,
+            Err(TokenVerificationError::UnsupportedAlgorithm(_))
+        ));
+
+        // An HMAC token keyed with something public is never accepted
+        let header = Header {
+            kid: Some("test-key".to_string()),
+            ..Header::new(Algorithm::HS256)
+        };
+        let key = EncodingKey::from_secret(b"test-client");
+        let token = jsonwebtoken::encode(&header, &id_claims(), &key).unwrap();
+        let audiences = [config.client_id.clone()];
+        assert!(matches!(
+            verify_idtoken(token, None, &audiences, &metadata, &config).await,
+            Err(TokenVerificationError::UnsupportedAlgorithm(_))
+        ));
+    }
+
+    #[test]
+    fn test_convert_jwk_to_decoding_key() {
+        let jwk = |changes: Value| {
+            let mut jwk = test_ec_jwk("test-key");
+            jwk.as_object_mut()
+                .unwrap()
+                .extend(changes.as_object().unwrap().clone());
+            serde_json::from_value::<Jwk>(jwk).unwrap()
+        };
+        assert!(convert_jwk_to_decoding_key(&jwk(json!({})), Algorithm::ES256).is_ok());
+        // No "alg" in the key is fine
+        assert!(
+            convert_jwk_to_decoding_key(&jwk(json!({ "alg": null })), Algorithm::ES256).is_ok()
+        );
+
+        for (changes, alg) in [
+            // The key's algorithm doesn't match the header's
+            (json!({}), Algorithm::ES384),
+            (json!({ "alg": "ES384" }), Algorithm::ES256),
+            // nor does its curve
+            (json!({ "crv": "P-384" }), Algorithm::ES256),
+            (json!({ "alg": null, "crv": "P-256" }), Algorithm::ES384),
+            // Symmetric keys are refused
+            (
+                json!({ "kty": "oct", "alg": "HS256", "k": "dGVzdC1zZWNyZXQ" }),
+                Algorithm::HS256,
+            ),
+        ] {
+            assert!(
+                matches!(
+                    convert_jwk_to_decoding_key(&jwk(changes.clone()), alg),
+                    Err(TokenVerificationError::UnsupportedAlgorithm(_))
+                ),
+                "{} with {:?}",
+                changes,
+                alg
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_idtoken_time_claims() {
+        let (metadata, config) = setup("https://idp.example.com/jwks/time").await;
+        let skew = config.clock_skew as i64;
+        let claims = |changes: Value| {
+            let mut claims = id_claims();
+            claims
+                .as_object_mut()
+                .unwrap()
+                .extend(changes.as_object().unwrap().clone());
+            claims
+        };
+
+        // A clock up to the skew behind the provider's is tolerated
+        let ahead = now() + skew - 2;
+        let claims_ok = claims(json!({ "iat": ahead, "nbf": ahead }));
+        assert!(verify(&claims_ok, &metadata, &config).await.is_ok());
+
+        let ahead = now() + skew + 10;
+        assert!(matches!(
+            verify(&claims(json!({ "nbf": ahead })), &metadata, &config).await,
+            Err(TokenVerificationError::TokenNotYetValidNotBeFore(..))
+        ));
+        assert!(matches!(
+            verify(&claims(json!({ "iat": ahead })), &metadata, &config).await,
+            Err(TokenVerificationError::TokenNotYetValidIssuedAt(..))
+        ));
+
+        // as is a token expired within the skew
+        let expired = now() - skew + 2;
+        assert!(
+            verify(&claims(json!({ "exp": expired })), &metadata, &config)
+                .await
+                .is_ok()
+        );
+        let expired = now() - skew - 10;
+        assert!(matches!(
+            verify(&claims(json!({ "exp": expired })), &metadata, &config).await,
+            Err(TokenVerificationError::TokenExpired)
+        ));
+
+        // Negative times are refused rather than panicking
+        for changes in [json!({ "iat": -1 }), json!({ "nbf": -1 })] {
+            assert!(matches!(
+                verify(&claims(changes), &metadata, &config).await,
+                Err(TokenVerificationError::InvalidTokenFormat)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_idtoken_max_age() {
+        let (metadata, config) = setup("https://idp.example.com/jwks/max-age").await;
+        let config = ProviderConfig {
+            max_age: Some(600),
+            ..config
+        };
+        let skew = config.clock_skew as i64;
+        let claims = |auth_time: Option<i64>| {
+            let mut claims = id_claims();
+            claims["auth_time"] = json!(auth_time);
+            claims
+        };
+
+        let auth_time = now() - 600 - skew + 10;
+        let idinfo = verify(&claims(Some(auth_time)), &metadata, &config)
+            .await
+            .unwrap();
+        assert_eq!(idinfo.auth_time, Some(auth_time));
+
+        // auth_time is required once max_age was requested
+        assert!(matches!(
+            verify(&claims(None), &metadata, &config).await,
+            Err(TokenVerificationError::AuthTimeMissing)
+        ));
+
+        // and the authentication must be recent enough, give or take the skew
+        let auth_time = now() - 600 - skew - 10;
+        assert!(matches!(
+            verify(&claims(Some(auth_time)), &metadata, &config).await,
+            Err(TokenVerificationError::AuthTimeTooOld(_, 600))
+        ));
+    }
+
+    /// Cache a key set with the given key ID as fetched `fetched_ago`, expiring `expires_in`
+    /// from now or, if negative, expired that long ago
+    async fn cache_jwks(jwks_url: &str, kid: &str, fetched_ago: u64, expires_in: i64) {
+        let now = Instant::now();
+        let expiration = match expires_in {
+            secs if secs >= 0 => now + Duration::from_secs(secs as u64),
+            secs => now - Duration::from_secs(secs.unsigned_abs()),
+        };
+        JWKS_CACHE.write().await.insert(
+            jwks_url.to_string(),
+            CachedJwks {
+                jwks: serde_json::from_value(json!({ "keys": [test_ec_jwk(kid)] })).unwrap(),
+                expiration,
+                fetched_at: now - Duration::from_secs(fetched_ago),
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_jwk_refetch_unknown_kid() {
+        let keys = json!({ "keys": [test_ec_jwk("new-key")] });
+        let (base_url, server) = serve_http(vec![(200, keys)]).await;
+        let jwks_url = format!("{}/jwks", base_url);
+        cache_jwks(&jwks_url, "old-key", 120, 3600).await;
+
+        // The provider rotated its keys
+        let jwk = get_jwk(&jwks_url, "new-key").await.unwrap();
+        assert_eq!(jwk.kid.as_deref(), Some("new-key"));
+        let requests = server.await.unwrap();
+        assert_eq!(requests[0].line, "GET /jwks HTTP/1.1");
+
+        // The refetched key set replaced the old one
+        assert!(matches!(
+            get_jwk(&jwks_url, "old-key").await,
+            Err(TokenVerificationError::NoMatchingKey)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_jwk_refetch_interval() {
+        let keys = json!({ "keys": [test_ec_jwk("new-key")] });
+        let (base_url, server) = serve_http(vec![(200, keys)]).await;
+        let jwks_url = format!("{}/jwks", base_url);
+        cache_jwks(&jwks_url, "old-key", 10, 3600).await;
+
+        // Fetched within JWKS_REFETCH_INTERVAL, an unknown key isn't looked for again
+        assert!(matches!(
+            get_jwk(&jwks_url, "new-key").await,
+            Err(TokenVerificationError::NoMatchingKey)
+        ));
+        assert!(get_jwk(&jwks_url, "old-key").await.is_ok());
+        assert!(!server.is_finished());
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_get_jwk_stale_on_fetch_failure() {
+        let (base_url, server) = serve_http(vec![(500, json!({}))]).await;
+        let jwks_url = format!("{}/jwks", base_url);
+        cache_jwks(&jwks_url, "test-key", 3600, -10).await;
+
+        // The expired key set is used while the provider fails
+        let jwk = get_jwk(&jwks_url, "test-key").await.unwrap();
+        assert_eq!(jwk.kid.as_deref(), Some("test-key"));
+        server.await.unwrap();
+
+        // and kept for the refetch interval, without another request
+        assert!(get_jwk(&jwks_url, "test-key").await.is_ok());
+
+        // Without a cached key set the failure is returned
+        let (base_url, server) = serve_http(vec![(500, json!({}))]).await;
+        assert!(matches!(
+            get_jwk(&format!("{}/jwks", base_url), "test-key").await,
+            Err(TokenVerificationError::HttpError(_))
+        ));
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_parse_max_age() {
+        assert_eq!(
+            parse_max_age("public, max-age=19302, must-revalidate, no-transform"),
+            Some(Duration::from_secs(19302))
+        );
+        assert_eq!(
+            parse_max_age("Max-Age=\"60\""),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(parse_max_age("s-maxage=60, no-cache"), None);
+        assert_eq!(parse_max_age("max-age=soon"), None);
+    }
+
+    #[test]
+    fn test_access_token_hash() {
+        // Example of OIDC Core 1.0, A.3
+        let access_token = "jHkWEdUXMU1BwAsC4vtUsZwnNvTIxEl0z9K3vx5KF0Y";
+        assert_eq!(
+            access_token_hash(access_token, Algorithm::RS256)
+                .ok()
+                .as_deref(),
+            Some("77QmUPtjPfzWtF2AnpK9RQ")
+        );
+    }
+
+    #[test]
+    fn test_audience_string_or_array() {
+        let aud = |json: &str| {
+            let mut de = serde_json::Deserializer::from_str(json);
+            deserialize_string_or_vec(&mut de).ok()
+        };
+        assert_eq!(aud(r#""client""#), Some(vec!["client".to_string()]));
+        assert_eq!(
+            aud(r#"["client", "api"]"#),
+            Some(vec!["client".to_string(), "api".to_string()])
+        );
+    }
 }
@@ -0,0 +1,194 @@
+use chrono::Utc;
+use url::Url;
+
+use crate::oauth2::config::OAUTH2_POST_LOGOUT_REDIRECT_URI;
+use crate::oauth2::errors::OAuth2Error;
+use crate::oauth2::provider::get_provider;
+use crate::session::IdpSession;
+use crate::storage::{CacheData, GENERIC_CACHE_STORE};
+
+use super::idtoken::{LogoutToken, verify_logout_token};
+
+/// Cache prefix of the `jti` of the logout tokens received, to refuse replays
+const LOGOUT_JTI_PREFIX: &str = "logout_jti";
+
+/// Verify a logout token posted to the provider's back-channel logout endpoint
+pub(crate) async fn verify_backchannel_logout(
+    provider_name: &str,
+    logout_token: &str,
+) -> Result<LogoutToken, OAuth2Error> {
+    let provider = get_provider(provider_name)?;
+    if !provider.issues_id_token() {
+        return Err(OAuth2Error::UnsupportedProvider(provider_name.to_string()));
+    }
+    let config = provider.config();
+    let metadata = provider.metadata().await?;
+
+    let logout_token = verify_logout_token(logout_token, &metadata, config)
+        .await
+        .map_err(|e| OAuth2Error::InvalidLogoutToken(e.to_string()))?;
+
+    // The token is remembered until it expires, after which it is refused anyway
+    let key = format!("{}:{}", provider_name, logout_token.jti);
+    let ttl = (logout_token.exp - Utc::now().timestamp()).max(0) as u64 + config.clock_skew;
+    let mut store = GENERIC_CACHE_STORE.lock().await;
+    if store
+        .get(LOGOUT_JTI_PREFIX, &key)
+        .await
+        .map_err(|e| OAuth2Error::Storage(e.to_string()))?
+        .is_some()
+    {
+        return Err(OAuth2Error::InvalidLogoutToken(
+            "Logout token replayed".to_string(),
+        ));
+    }
+    store
+        .put_with_ttl(
+            LOGOUT_JTI_PREFIX,
+            &key,
+            CacheData {
+                value: logout_token.exp.to_string(),
+            },
+            ttl.max(1) as usize,
+        )
+        .await
+        .map_err(|e| OAuth2Error::Storage(e.to_string()))?;
+
+    Ok(logout_token)
+}
+
+/// URL of the provider's end_session_endpoint ending the IdP session of a login
+/// (OIDC RP-Initiated Logout 1.0, 2)
+///
+/// `None` unless the provider has RP-initiated logout enabled and an end_session_endpoint.
+pub(crate) async fn end_session_url(
+    idp_session: &IdpSession,
+) -> Result<Option<String>, OAuth2Error> {
+    let Ok(provider) = get_provider(&idp_session.provider) else {
+        return Ok(None);
+    };
+    let config = provider.config();
+    if !config.rp_initiated_logout {
+        return Ok(None);
+    }
+    let Some(end_session_endpoint) = provider.metadata().await?.end_session_endpoint else {
+        tracing::warn!("No end_session_endpoint for {}", provider.name());
+        return Ok(None);
+    };
+
+    let mut url = Url::parse(&end_session_endpoint)
+        .map_err(|e| OAuth2Error::Discovery(format!("Invalid end_session_endpoint: {}", e)))?;
+    {
+        let mut query = url.query_pairs_mut();
+        query.append_pair("id_token_hint", &idp_session.id_token);
+        query.append_pair("client_id", &config.client_id);
+        if let Some(redirect_uri) = OAUTH2_POST_LOGOUT_REDIRECT_URI.as_deref() {
+            query.append_pair("post_logout_redirect_uri", redirect_uri);
+        }
+    }
+    Ok(Some(url.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oauth2::main::idtoken::cache_test_jwks;
+    use crate::oauth2::provider::{ProviderConfig, ProviderMetadata};
+    use crate::test_utils::{TEST_EC_KEY_PEM, init_test_env, test_ec_jwk};
+    use crate::utils::gen_random_string;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use serde_json::{Value, json};
+
+    async fn setup() -> (ProviderMetadata, &'static ProviderConfig) {
+        init_test_env();
+        let provider = get_provider("test-idp").unwrap();
+        let metadata = provider.metadata().await.unwrap();
+        let jwks_uri = metadata.jwks_uri.as_deref().unwrap();
+        cache_test_jwks(jwks_uri, json!({ "keys": [test_ec_jwk("test-key")] })).await;
+        (metadata, provider.config())
+    }
+
+    fn logout_claims() -> Value {
+        let now = Utc::now().timestamp();
+        json!({
+            "iss": "https://idp.example.com",
+            "aud": "test-client",
+            "iat": now,
+            "exp": now + 120,
+            "jti": gen_random_string(16).unwrap(),
+            "events": { "http://schemas.openid.net/event/backchannel-logout": {} },
+            "sub": "alice",
+            "sid": "sid-1",
+        })
+    }
+
+    fn sign(claims: &Value) -> String {
+        let header = Header {
+            typ: Some("logout+jwt".to_string()),
+            kid: Some("test-key".to_string()),
+            ..Header::new(Algorithm::ES256)
+        };
+        let key = EncodingKey::from_ec_pem(TEST_EC_KEY_PEM.as_bytes()).unwrap();
+        jsonwebtoken::encode(&header, claims, &key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_verify_logout_token() {
+        let (metadata, config) = setup().await;
+        let verify = |claims: Value| {
+            let metadata = metadata.clone();
+            async move { verify_logout_token(&sign(&claims), &metadata, config).await }
+        };
+
+        let logout_token = verify(logout_claims()).await.unwrap();
+        assert_eq!(logout_token.sub.as_deref(), Some("alice"));
+        assert_eq!(logout_token.sid.as_deref(), Some("sid-1"));
+
+        // Either of sub and sid is enough
+        let mut claims = logout_claims();
+        claims.as_object_mut().unwrap().remove("sub");
+        assert!(verify(claims).await.is_ok());
+
+        // A nonce makes it an ID token
+        let mut claims = logout_claims();
+        claims["nonce"] = json!("nonce");
+        assert!(verify(claims).await.is_err());
+
+        let mut claims = logout_claims();
+        claims.as_object_mut().unwrap().remove("events");
+        assert!(verify(claims).await.is_err());
+
+        let mut claims = logout_claims();
+        claims["events"] = json!({ "http://schemas.openid.net/event/other": {} });
+        assert!(verify(claims).await.is_err());
+
+        let mut claims = logout_claims();
+        claims.as_object_mut().unwrap().remove("sub");
+        claims.as_object_mut().unwrap().remove("sid");
+        assert!(verify(claims).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_logout_token_replay() {
+        setup().await;
+        let logout_token = sign(&logout_claims());
+
+        assert!(
+            verify_backchannel_logout("test-idp", &logout_token)
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            verify_backchannel_logout("test-idp", &logout_token).await,
+            Err(OAuth2Error::InvalidLogoutToken(_))
+        ));
+
+        // Another token of the same session is accepted
+        let logout_token = sign(&logout_claims());
+        assert!(
+            verify_backchannel_logout("test-idp", &logout_token)
+                .await
+                .is_ok()
+        );
+    }
+}
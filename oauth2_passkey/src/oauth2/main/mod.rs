@@ -1,10 +1,22 @@
 mod core;
-mod google;
 mod idtoken;
+mod logout;
+mod par;
+mod token;
 mod utils;
 
-pub use core::{csrf_checks, get_idinfo_userinfo, prepare_oauth2_auth_request};
+pub use core::{
+    check_error_response, csrf_checks, get_callback_mode, get_oauth2_account,
+    get_oauth2_account_from_id_token, get_return_to, issue_id_token_nonce,
+    prepare_oauth2_auth_request,
+};
 pub(crate) use idtoken::IdInfo;
+#[cfg(test)]
+pub(crate) use idtoken::cache_test_jwks;
+pub(crate) use logout::{end_session_url, verify_backchannel_logout};
+pub use token::get_fresh_access_token;
+pub(crate) use token::{revoke_oauth2_tokens, store_oauth2_tokens};
+pub(crate) use utils::get_client;
 pub use utils::{
     decode_state, delete_session_and_misc_token_from_store,
     get_uid_from_stored_session_by_state_param, validate_origin,
@@ -0,0 +1,203 @@
+use serde::Deserialize;
+use serde_json::Value;
+use url::form_urlencoded::{byte_serialize, parse};
+
+use crate::oauth2::errors::OAuth2Error;
+use crate::oauth2::provider::{OAuth2Provider, ProviderMetadata, client_auth_request};
+
+/// Response of the pushed authorization request endpoint (RFC 9126, 2.2)
+#[derive(Debug, Deserialize)]
+struct PushedAuthResponse {
+    request_uri: String,
+}
+
+/// Whether the authorization parameters are pushed to the provider first
+///
+/// Without a pushed_authorization_request_endpoint they are sent in the authorization
+/// URL as usual.
+pub(super) fn uses_pushed_auth_requests(
+    provider: &dyn OAuth2Provider,
+    metadata: &ProviderMetadata,
+) -> bool {
+    if !provider.config().pushed_auth_requests && !metadata.require_pushed_authorization_requests {
+        return false;
+    }
+    if metadata.pushed_authorization_request_endpoint.is_none() {
+        tracing::warn!(
+            "No pushed_authorization_request_endpoint for {}, not pushing the request",
+            provider.name()
+        );
+        return false;
+    }
+    true
+}
+
+/// Push the form-urlencoded authorization parameters to the provider (RFC 9126) and
+/// return the authorization URL carrying only `client_id` and the `request_uri`
+pub(super) async fn push_authorization_request(
+    provider: &dyn OAuth2Provider,
+    metadata: &ProviderMetadata,
+    params: &str,
+) -> Result<String, OAuth2Error> {
+    let endpoint = metadata
+        .pushed_authorization_request_endpoint
+        .as_deref()
+        .ok_or_else(|| {
+            OAuth2Error::Discovery("No pushed_authorization_request_endpoint".to_string())
+        })?;
+
+    // The client authentication adds the client_id again where the method sends it
+    let form: Vec<(String, String)> = parse(params.as_bytes())
+        .into_owned()
+        .filter(|(key, _)| key != "client_id")
+        .collect();
+    let response = client_auth_request(provider, metadata, endpoint, form)?
+        .header(http::header::ACCEPT, "application/json")
+        .send()
+        .await
+        .map_err(|e| OAuth2Error::PushedAuthRequest(e.to_string()))?;
+
+    let status = response.status();
+    let response_body = response
+        .text()
+        .await
+        .map_err(|e| OAuth2Error::PushedAuthRequest(e.to_string()))?;
+
+    if !status.is_success() {
+        let error = serde_json::from_str::<Value>(&response_body)
+            .ok()
+            .and_then(|error| {
+                let code = error.get("error")?.as_str()?.to_string();
+                let description = error.get("error_description").and_then(|v| v.as_str());
+                Some(match description {
+                    Some(description) => format!("{}: {}", code, description),
+                    None => code,
+                })
+            })
+            .unwrap_or_else(|| status.to_string());
+        return Err(OAuth2Error::PushedAuthRequest(error));
+    }
+
+    let pushed: PushedAuthResponse = serde_json::from_str(&response_body)
+        .map_err(|e| OAuth2Error::PushedAuthRequest(e.to_string()))?;
+    tracing::debug!("Pushed authorization request: {:?}", pushed);
+
+    let encode = |s: &str| byte_serialize(s.as_bytes()).collect::<String>();
+    Ok(format!(
+        "{}?client_id={}&request_uri={}",
+        metadata.authorization_endpoint,
+        encode(&provider.config().client_id),
+        encode(&pushed.request_uri)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oauth2::provider::get_provider;
+    use crate::test_utils::init_test_env;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Serve one pushed authorization request, returning the endpoint and the posted form
+    async fn serve_par_endpoint(
+        request_uri: &str,
+    ) -> (String, tokio::task::JoinHandle<Vec<(String, String)>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/par", listener.local_addr().unwrap());
+        let body = serde_json::json!({ "request_uri": request_uri, "expires_in": 60 }).to_string();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            // Read the headers, then the body of Content-Length
+            let form = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                let Some((head, form)) = text.split_once("\r\n\r\n") else {
+                    continue;
+                };
+                let length = head
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().ok())?
+                    })
+                    .unwrap_or(0);
+                if form.len() >= length {
+                    break form.to_string();
+                }
+            };
+            let response = format!(
+                "HTTP/1.1 201 Created\r\nContent-Type: application/json\r\n\
+                Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            parse(form.as_bytes()).into_owned().collect()
+        });
+        (endpoint, server)
+    }
+
+    #[tokio::test]
+    async fn test_push_authorization_request() {
+        init_test_env();
+        let provider = get_provider("test-par").unwrap();
+        let mut metadata = provider.metadata().await.unwrap();
+        let request_uri = "urn:ietf:params:oauth:request_uri:abc";
+        let (endpoint, server) = serve_par_endpoint(request_uri).await;
+        metadata.pushed_authorization_request_endpoint = Some(endpoint);
+        assert!(uses_pushed_auth_requests(provider, &metadata));
+
+        let params = "response_type=code&scope=openid+email&client_id=test-client&state=xyz";
+        let auth_url = push_authorization_request(provider, &metadata, params)
+            .await
+            .unwrap();
+
+        // Only client_id and request_uri go through the browser
+        assert_eq!(
+            auth_url,
+            "https://idp.example.com/authorize?client_id=test-client\
+            &request_uri=urn%3Aietf%3Aparams%3Aoauth%3Arequest_uri%3Aabc"
+        );
+        let form = server.await.unwrap();
+        let value = |key: &str| {
+            form.iter()
+                .filter(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(value("response_type"), vec!["code"]);
+        assert_eq!(value("scope"), vec!["openid email"]);
+        assert_eq!(value("state"), vec!["xyz"]);
+        assert_eq!(value("client_id"), vec!["test-client"]);
+        assert_eq!(value("client_secret"), vec!["test-secret"]);
+    }
+
+    #[tokio::test]
+    async fn test_pushed_auth_requests_without_endpoint() {
+        init_test_env();
+        let provider = get_provider("test-par").unwrap();
+        let mut metadata = provider.metadata().await.unwrap();
+        assert!(metadata.pushed_authorization_request_endpoint.is_none());
+
+        // Configured, but the provider has no endpoint to push to
+        assert!(!uses_pushed_auth_requests(provider, &metadata));
+        metadata.require_pushed_authorization_requests = true;
+        assert!(!uses_pushed_auth_requests(provider, &metadata));
+
+        // Not configured
+        let provider = get_provider("test-idp").unwrap();
+        metadata.require_pushed_authorization_requests = false;
+        metadata.pushed_authorization_request_endpoint = Some("https://idp/par".to_string());
+        assert!(!uses_pushed_auth_requests(provider, &metadata));
+        metadata.require_pushed_authorization_requests = true;
+        assert!(uses_pushed_auth_requests(provider, &metadata));
+    }
+}
@@ -0,0 +1,344 @@
+use chrono::{Duration, Utc};
+use serde_json::Value;
+
+use crate::oauth2::config::OAUTH2_REDIRECT_URI;
+use crate::oauth2::errors::OAuth2Error;
+use crate::oauth2::provider::{
+    OAuth2Provider, ProviderMetadata, client_auth_request, get_provider,
+};
+use crate::oauth2::storage::OAuth2Store;
+use crate::oauth2::types::{OAuth2Account, OidcTokenResponse, StoredOAuth2Tokens};
+use crate::utils::{decrypt_string, encrypt_string};
+
+/// Purpose of the key encrypting the stored tokens
+const TOKEN_ENCRYPTION_PURPOSE: &str = "oauth2_tokens";
+
+pub(super) async fn exchange_code_for_token(
+    provider: &dyn OAuth2Provider,
+    metadata: &ProviderMetadata,
+    code: String,
+    code_verifier: String,
+) -> Result<OidcTokenResponse, OAuth2Error> {
+    request_token(
+        provider,
+        metadata,
+        vec![
+            ("code", code),
+            ("redirect_uri", OAUTH2_REDIRECT_URI.to_string()),
+            ("grant_type", "authorization_code".to_string()),
+            ("code_verifier", code_verifier),
+        ],
+    )
+    .await
+}
+
+async fn refresh_access_token(
+    provider: &dyn OAuth2Provider,
+    metadata: &ProviderMetadata,
+    refresh_token: String,
+) -> Result<OidcTokenResponse, OAuth2Error> {
+    request_token(
+        provider,
+        metadata,
+        vec![
+            ("refresh_token", refresh_token),
+            ("grant_type", "refresh_token".to_string()),
+        ],
+    )
+    .await
+}
+
+async fn request_token(
+    provider: &dyn OAuth2Provider,
+    metadata: &ProviderMetadata,
+    form: Vec<(&'static str, String)>,
+) -> Result<OidcTokenResponse, OAuth2Error> {
+    let response = client_auth_request(provider, metadata, &metadata.token_endpoint, form)?
+        // GitHub responds with a form encoded body unless JSON is requested
+        .header(http::header::ACCEPT, "application/json")
+        .send()
+        .await
+        .map_err(|e| OAuth2Error::TokenExchange(e.to_string()))?;
+
+    let status = response.status();
+    tracing::debug!("Token Response: {:#?}", response);
+
+    let response_body = response
+        .text()
+        .await
+        .map_err(|e| OAuth2Error::TokenExchange(e.to_string()))?;
+
+    // Error responses (RFC 6749, 5.2), which GitHub sends with 200 OK
+    if let Ok(error) = serde_json::from_str::<Value>(&response_body)
+        && let Some(code) = error.get("error").and_then(|v| v.as_str())
+    {
+        let description = error
+            .get("error_description")
+            .and_then(|v| v.as_str())
+            .unwrap_or(code)
+            .to_string();
+        tracing::debug!("Token Error Response: {}: {}", code, description);
+        return Err(match code {
+            "invalid_grant" | "bad_refresh_token" => OAuth2Error::InvalidGrant(description),
+            _ => OAuth2Error::TokenExchange(format!("{}: {}", code, description)),
+        });
+    }
+
+    if !status.is_success() {
+        return Err(OAuth2Error::TokenExchange(status.to_string()));
+    }
+
+    let response_json: OidcTokenResponse = serde_json::from_str(&response_body)
+        .map_err(|e| OAuth2Error::TokenExchange(e.to_string()))?;
+
+    tracing::debug!("Response JSON: {:#?}", response_json);
+    Ok(response_json)
+}
+
+/// Keep the tokens issued for the account if the provider is configured for offline access
+pub(crate) async fn store_oauth2_tokens(
+    account: &OAuth2Account,
+    tokens: &OidcTokenResponse,
+) -> Result<(), OAuth2Error> {
+    if !get_provider(&account.provider)?.config().offline_access {
+        return Ok(());
+    }
+
+    let stored_account =
+        OAuth2Store::get_oauth2_account_by_provider(&account.provider, &account.provider_user_id)
+            .await?
+            .ok_or_else(|| OAuth2Error::Storage("OAuth2 account not stored".to_string()))?;
+
+    save_tokens(&stored_account.id, tokens).await
+}
+
+async fn save_tokens(account_id: &str, tokens: &OidcTokenResponse) -> Result<(), OAuth2Error> {
+    let encrypt = |token: &str| encrypt_string(token, TOKEN_ENCRYPTION_PURPOSE);
+
+    OAuth2Store::upsert_oauth2_tokens(StoredOAuth2Tokens {
+        account_id: account_id.to_string(),
+        access_token: encrypt(&tokens.access_token)?,
+        refresh_token: tokens.refresh_token.as_deref().map(encrypt).transpose()?,
+        expires_at: tokens
+            .expires_in
+            .map(|secs| Utc::now() + Duration::seconds(secs as i64)),
+        scope: tokens.scope.clone(),
+        updated_at: Utc::now(),
+    })
+    .await
+}
+
+/// Get an access token for calling the provider's APIs on behalf of the user
+///
+/// The stored access token is returned while it is valid, otherwise it is refreshed
+/// with the stored refresh token. Requires `OAUTH2_{PROVIDER}_OFFLINE_ACCESS=true`.
+///
+/// # Errors
+/// * `OAuth2Error::InvalidGrant` - The refresh token is missing, expired or revoked,
+///   so the user has to authorize again
+pub async fn get_fresh_access_token(user_id: &str, provider: &str) -> Result<String, OAuth2Error> {
+    fresh_access_token(get_provider(provider)?, user_id).await
+}
+
+async fn fresh_access_token(
+    oauth2_provider: &dyn OAuth2Provider,
+    user_id: &str,
+) -> Result<String, OAuth2Error> {
+    let provider = oauth2_provider.name();
+    let account = OAuth2Store::get_oauth2_accounts(user_id)
+        .await?
+        .into_iter()
+        .find(|account| account.provider == provider)
+        .ok_or_else(|| {
+            OAuth2Error::SecurityTokenNotFound(format!("No {} account for the user", provider))
+        })?;
+    let stored = OAuth2Store::get_oauth2_tokens(&account.id)
+        .await?
+        .ok_or_else(|| {
+            OAuth2Error::SecurityTokenNotFound(format!("No {} tokens for the user", provider))
+        })?;
+
+    // Leave a minute for the caller to use the token
+    if stored
+        .expires_at
+        .is_none_or(|expires_at| expires_at > Utc::now() + Duration::seconds(60))
+    {
+        return Ok(decrypt_string(
+            &stored.access_token,
+            TOKEN_ENCRYPTION_PURPOSE,
+        )?);
+    }
+
+    let refresh_token = stored
+        .refresh_token
+        .as_deref()
+        .map(|token| decrypt_string(token, TOKEN_ENCRYPTION_PURPOSE))
+        .transpose()?
+        .ok_or_else(|| OAuth2Error::InvalidGrant("No refresh token stored".to_string()))?;
+
+    let metadata = oauth2_provider.metadata().await?;
+    match refresh_access_token(oauth2_provider, &metadata, refresh_token).await {
+        Ok(tokens) => {
+            save_tokens(&account.id, &tokens).await?;
+            Ok(tokens.access_token)
+        }
+        Err(OAuth2Error::InvalidGrant(e)) => {
+            // The grant is gone for good, e.g. the user revoked access
+            tracing::info!("Refresh token of account {} rejected: {}", account.id, e);
+            OAuth2Store::delete_oauth2_tokens(&account.id).await?;
+            Err(OAuth2Error::InvalidGrant(e))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Revoke the stored tokens of the account at the provider, before the account is deleted
+///
+/// A failure is returned for the caller to report, the local deletion goes ahead regardless.
+pub(crate) async fn revoke_oauth2_tokens(account: &OAuth2Account) -> Result<(), OAuth2Error> {
+    revoke_stored_tokens(account).await.inspect_err(|e| {
+        tracing::error!(
+            "Failed to revoke the tokens of OAuth2 account {}: {}",
+            account.id,
+            e
+        )
+    })
+}
+
+async fn revoke_stored_tokens(account: &OAuth2Account) -> Result<(), OAuth2Error> {
+    let Some(stored) = OAuth2Store::get_oauth2_tokens(&account.id).await? else {
+        return Ok(());
+    };
+    let access_token = decrypt_string(&stored.access_token, TOKEN_ENCRYPTION_PURPOSE)?;
+    let refresh_token = stored
+        .refresh_token
+        .as_deref()
+        .map(|token| decrypt_string(token, TOKEN_ENCRYPTION_PURPOSE))
+        .transpose()?;
+
+    let provider = get_provider(&account.provider)?;
+    let metadata = provider.metadata().await?;
+    provider
+        .revoke_tokens(&metadata, &access_token, refresh_token.as_deref())
+        .await?;
+
+    tracing::info!("Revoked the tokens of OAuth2 account {}", account.id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oauth2::provider::test_oidc_provider;
+    use crate::test_utils::{init_test_stores, serve_http};
+    use crate::userdb::{User, UserStore};
+    use serde_json::json;
+
+    /// Store an account of test-idp with tokens expiring in `expires_in` seconds
+    async fn account_with_tokens(user_id: &str, expires_in: u64) -> OAuth2Account {
+        init_test_stores().await;
+        UserStore::upsert_user(User {
+            id: user_id.to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let account = OAuth2Store::upsert_oauth2_account(OAuth2Account {
+            user_id: user_id.to_string(),
+            provider: "test-idp".to_string(),
+            provider_user_id: format!("test-idp_{}", user_id),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let tokens = serde_json::from_value(json!({
+            "access_token": "access-1",
+            "token_type": "Bearer",
+            "expires_in": expires_in,
+            "refresh_token": "refresh-1",
+        }))
+        .unwrap();
+        save_tokens(&account.id, &tokens).await.unwrap();
+        account
+    }
+
+    /// test-idp with its token endpoint at the local server
+    fn provider(base_url: &str) -> Box<dyn OAuth2Provider> {
+        test_oidc_provider("test-idp", |config| {
+            config.endpoints.token_url = Some(format!("{}/token", base_url));
+        })
+    }
+
+    #[tokio::test]
+    async fn test_fresh_access_token_valid() {
+        account_with_tokens("fresh-valid", 3600).await;
+
+        // Nothing listens at the token endpoint, the stored token is returned
+        let provider = provider("http://127.0.0.1:1");
+        let token = fresh_access_token(provider.as_ref(), "fresh-valid")
+            .await
+            .unwrap();
+        assert_eq!(token, "access-1");
+    }
+
+    #[tokio::test]
+    async fn test_fresh_access_token_refresh() {
+        let account = account_with_tokens("fresh-refresh", 30).await;
+        let response = json!({
+            "access_token": "access-2",
+            "token_type": "Bearer",
+            "expires_in": 3600,
+            "refresh_token": "refresh-2",
+        });
+        let (base_url, server) = serve_http(vec![(200, response)]).await;
+        let provider = provider(&base_url);
+
+        // Expiring within a minute, so refreshed
+        let token = fresh_access_token(provider.as_ref(), "fresh-refresh")
+            .await
+            .unwrap();
+        assert_eq!(token, "access-2");
+        let requests = server.await.unwrap();
+        assert_eq!(requests[0].line, "POST /token HTTP/1.1");
+        assert!(requests[0].body.contains("grant_type=refresh_token"));
+        assert!(requests[0].body.contains("refresh_token=refresh-1"));
+
+        // The new tokens are stored, encrypted
+        let stored = OAuth2Store::get_oauth2_tokens(&account.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(stored.access_token, "access-2");
+        let refresh_token = stored.refresh_token.as_deref().unwrap();
+        assert_eq!(
+            decrypt_string(refresh_token, TOKEN_ENCRYPTION_PURPOSE).unwrap(),
+            "refresh-2"
+        );
+        let token = fresh_access_token(provider.as_ref(), "fresh-refresh")
+            .await
+            .unwrap();
+        assert_eq!(token, "access-2");
+    }
+
+    #[tokio::test]
+    async fn test_fresh_access_token_invalid_grant() {
+        let account = account_with_tokens("fresh-revoked", 0).await;
+        let response = json!({ "error": "invalid_grant", "error_description": "Revoked" });
+        let (base_url, server) = serve_http(vec![(400, response)]).await;
+        let provider = provider(&base_url);
+
+        assert!(matches!(
+            fresh_access_token(provider.as_ref(), "fresh-revoked").await,
+            Err(OAuth2Error::InvalidGrant(e)) if e == "Revoked"
+        ));
+        server.await.unwrap();
+
+        // The user has to authorize again
+        assert!(
+            OAuth2Store::get_oauth2_tokens(&account.id)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -1,32 +1,37 @@
-use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
 use chrono::{DateTime, Utc};
 use http::header::HeaderMap;
 use std::time::Duration;
 use url::Url;
 
 use crate::oauth2::OAuth2Error;
+use crate::oauth2::provider::get_provider;
 use crate::oauth2::{StateParams, StoredToken};
 
 use crate::session::{
     User as SessionUser, delete_session_from_store_by_session_id, get_user_from_session,
 };
 use crate::storage::{CacheData, GENERIC_CACHE_STORE};
-use crate::utils::gen_random_string;
+use crate::utils::{decrypt_string, encrypt_string, gen_random_string};
 
+/// AEAD purpose of the state parameter, see `encrypt_string`
+const STATE_PURPOSE: &str = "oauth2_state";
+
+/// Encrypt the state parameter, keeping the IDs of the cached tokens from the browser and
+/// the IdP and detecting any modification
 pub(super) fn encode_state(state_params: StateParams) -> Result<String, OAuth2Error> {
     let state_json =
         serde_json::to_string(&state_params).map_err(|e| OAuth2Error::Serde(e.to_string()))?;
-    Ok(URL_SAFE_NO_PAD.encode(state_json))
+    Ok(encrypt_string(&state_json, STATE_PURPOSE)?)
 }
 
 pub fn decode_state(state: &str) -> Result<StateParams, OAuth2Error> {
-    let decoded_bytes = URL_SAFE_NO_PAD
-        .decode(state)
-        .map_err(|e| OAuth2Error::DecodeState(format!("Failed to decode base64: {}", e)))?;
-    let decoded_state_string = String::from_utf8(decoded_bytes)
-        .map_err(|e| OAuth2Error::DecodeState(format!("Failed to decode UTF-8: {}", e)))?;
-    let state_in_response: StateParams = serde_json::from_str(&decoded_state_string)
-        .map_err(|e| OAuth2Error::Serde(e.to_string()))?;
+    let state_json = decrypt_string(state, STATE_PURPOSE)
+        .map_err(|e| OAuth2Error::DecodeState(format!("Invalid state: {}", e)))?;
+    let state_in_response: StateParams =
+        serde_json::from_str(&state_json).map_err(|e| OAuth2Error::Serde(e.to_string()))?;
+    if state_in_response.expires_at < Utc::now().timestamp() {
+        return Err(OAuth2Error::StateExpired);
+    }
     Ok(state_in_response)
 }
 
@@ -118,8 +123,11 @@ pub(crate) async fn remove_token_from_store(
         .map_err(|e| OAuth2Error::Storage(e.to_string()))
 }
 
-pub async fn validate_origin(headers: &HeaderMap, auth_url: &str) -> Result<(), OAuth2Error> {
-    let parsed_url = Url::parse(auth_url).expect("Invalid URL");
+/// Validate that the callback request comes from the authorization server of the provider
+pub async fn validate_origin(headers: &HeaderMap, provider_name: &str) -> Result<(), OAuth2Error> {
+    let metadata = get_provider(provider_name)?.metadata().await?;
+    let parsed_url = Url::parse(&metadata.authorization_endpoint)
+        .map_err(|e| OAuth2Error::InvalidOrigin(format!("Invalid auth URL: {}", e)))?;
     let scheme = parsed_url.scheme();
     let host = parsed_url.host_str().unwrap_or_default();
     let port = parsed_url
@@ -220,3 +228,45 @@ pub async fn delete_session_and_misc_token_from_store(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_params(expires_at: i64) -> StateParams {
+        StateParams {
+            provider: "google".to_string(),
+            csrf_token: "csrf".to_string(),
+            csrf_id: "csrf_id".to_string(),
+            nonce_id: "nonce".to_string(),
+            pkce_id: "pkce".to_string(),
+            misc_id: None,
+            callback_mode: Default::default(),
+            scope: "openid".to_string(),
+            return_to: None,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_state() {
+        let state = encode_state(state_params(Utc::now().timestamp() + 60)).unwrap();
+        assert!(!state.contains("nonce"));
+        assert_eq!(decode_state(&state).unwrap().pkce_id, "pkce");
+
+        let mut tampered = state.into_bytes();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert!(matches!(
+            decode_state(&tampered),
+            Err(OAuth2Error::DecodeState(_))
+        ));
+
+        let expired = encode_state(state_params(Utc::now().timestamp() - 1)).unwrap();
+        assert!(matches!(
+            decode_state(&expired),
+            Err(OAuth2Error::StateExpired)
+        ));
+    }
+}
@@ -1,26 +1,43 @@
 mod config;
 mod errors;
 mod main;
+mod provider;
 mod storage;
 mod types;
 
 pub(super) use types::{StateParams, StoredToken};
 
-pub use config::{OAUTH2_AUTH_URL, OAUTH2_CSRF_COOKIE_NAME};
+pub use config::OAUTH2_CSRF_COOKIE_NAME;
 
 pub use errors::OAuth2Error;
+#[cfg(test)]
+pub(crate) use main::cache_test_jwks;
 pub use main::{
-    csrf_checks, decode_state, delete_session_and_misc_token_from_store, get_idinfo_userinfo,
-    get_uid_from_stored_session_by_state_param, prepare_oauth2_auth_request, validate_origin,
+    check_error_response, csrf_checks, decode_state, delete_session_and_misc_token_from_store,
+    get_callback_mode, get_fresh_access_token, get_oauth2_account,
+    get_oauth2_account_from_id_token, get_return_to, get_uid_from_stored_session_by_state_param,
+    issue_id_token_nonce, prepare_oauth2_auth_request, validate_origin,
 };
+pub(crate) use main::{
+    end_session_url, revoke_oauth2_tokens, store_oauth2_tokens, verify_backchannel_logout,
+};
+pub(crate) use provider::{AutoLinkPolicy, SigningKey, auto_link_policy, signing_key_from_env};
+pub use provider::{get_oauth2_client_jwks, list_oauth2_providers};
 pub use storage::OAuth2Store;
-pub use types::{AccountSearchField, AuthResponse, OAuth2Account};
+pub use types::{
+    AccountSearchField, AuthRequestOptions, AuthResponse, CallbackMode, OAuth2Account,
+    OAuth2AuthRequest, Prompt,
+};
 
 pub async fn init() -> Result<(), errors::OAuth2Error> {
     // Validate required environment variables early
     let _ = *config::OAUTH2_REDIRECT_URI; // This will validate ORIGIN
-    let _ = *config::OAUTH2_GOOGLE_CLIENT_ID;
-    let _ = *config::OAUTH2_GOOGLE_CLIENT_SECRET;
+    let _ = *provider::OAUTH2_PROVIDERS; // This will validate provider credentials and endpoints
+    let offline_access = provider::OAUTH2_PROVIDERS
+        .values()
+        .any(|provider| provider.config().offline_access);
+    check_auth_server_secret(&crate::config::AUTH_SERVER_SECRET, offline_access)?;
+    provider::prefetch_provider_metadata().await;
 
     // Initialize the storage layer
     crate::storage::init()
@@ -32,3 +49,38 @@ pub async fn init() -> Result<(), errors::OAuth2Error> {
 
     Ok(())
 }
+
+/// Refuse to store tokens for offline access under a key derived from the public default
+/// of AUTH_SERVER_SECRET, and warn about the default otherwise
+fn check_auth_server_secret(
+    secret: &[u8],
+    offline_access: bool,
+) -> Result<(), errors::OAuth2Error> {
+    if secret != crate::config::DEFAULT_AUTH_SERVER_SECRET.as_bytes() {
+        return Ok(());
+    }
+    if offline_access {
+        return Err(errors::OAuth2Error::Crypto(
+            "AUTH_SERVER_SECRET must be set to store tokens for offline access".to_string(),
+        ));
+    }
+    tracing::warn!("AUTH_SERVER_SECRET is not set, the public default is used");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DEFAULT_AUTH_SERVER_SECRET;
+
+    #[test]
+    fn test_check_auth_server_secret() {
+        let default = DEFAULT_AUTH_SERVER_SECRET.as_bytes();
+        assert!(matches!(
+            check_auth_server_secret(default, true),
+            Err(errors::OAuth2Error::Crypto(_))
+        ));
+        assert!(check_auth_server_secret(default, false).is_ok());
+        assert!(check_auth_server_secret(b"a-secret", true).is_ok());
+    }
+}
@@ -0,0 +1,282 @@
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::{fs, sync::Mutex};
+
+use crate::oauth2::errors::OAuth2Error;
+use crate::oauth2::main::IdInfo;
+use crate::oauth2::types::OAuth2Account;
+
+use super::config::{
+    provider_allowed_domains, provider_auto_link, provider_claim_map, provider_clock_skew,
+    provider_env, provider_env_flag, provider_env_list, provider_env_secs, provider_id_token_algs,
+    required_provider_env,
+};
+use super::traits::OAuth2Provider;
+use super::types::{AppleProvider, ProviderConfig, ProviderEndpoints, TokenAuthMethod};
+
+const APPLE_AUTH_URL: &str = "https://appleid.apple.com/auth/authorize";
+const APPLE_TOKEN_URL: &str = "https://appleid.apple.com/auth/token";
+const APPLE_JWKS_URL: &str = "https://appleid.apple.com/auth/keys";
+const APPLE_REVOCATION_URL: &str = "https://appleid.apple.com/auth/revoke";
+const APPLE_ISSUER: &str = "https://appleid.apple.com";
+const APPLE_PRIVATE_RELAY_DOMAIN: &str = "@privaterelay.appleid.com";
+
+/// Lifetime of the client secret JWT, renewed a minute before it expires
+const CLIENT_SECRET_LIFETIME: i64 = 3600;
+
+/// Claims of the client secret JWT
+#[derive(Serialize)]
+struct ClientSecretClaims<'a> {
+    iss: &'a str,
+    sub: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+impl AppleProvider {
+    pub(super) fn new() -> Self {
+        let name = "apple";
+        // The private key (.p8) is given either as PEM or as a path to the file
+        let private_key = provider_env(name, "PRIVATE_KEY")
+            .map(|pem| pem.replace("\\n", "\n"))
+            .unwrap_or_else(|| {
+                let path = required_provider_env(name, "PRIVATE_KEY_PATH");
+                fs::read_to_string(&path)
+                    .unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e))
+            });
+        let config = ProviderConfig {
+            name: name.to_string(),
+            client_id: required_provider_env(name, "CLIENT_ID"),
+            // Signed with the private key on demand, see client_secret()
+            client_secret: String::new(),
+            scope: provider_env(name, "SCOPE").unwrap_or("name+email".to_string()),
+            // Apple only posts the name and email scopes back, whatever OAUTH2_RESPONSE_MODE is
+            extra_scopes: provider_env_list(name, "EXTRA_SCOPES"),
+            extra_params: "&response_mode=form_post".to_string(),
+            // Sign in with Apple has no prompt parameter
+            prompt: None,
+            offline_access: provider_env_flag(name, "OFFLINE_ACCESS"),
+            issuer_url: None,
+            endpoints: ProviderEndpoints {
+                auth_url: Some(provider_env(name, "AUTH_URL").unwrap_or(APPLE_AUTH_URL.into())),
+                token_url: Some(provider_env(name, "TOKEN_URL").unwrap_or(APPLE_TOKEN_URL.into())),
+                userinfo_url: None,
+                jwks_url: Some(provider_env(name, "JWKS_URL").unwrap_or(APPLE_JWKS_URL.into())),
+                issuer: Some(provider_env(name, "ISSUER").unwrap_or(APPLE_ISSUER.into())),
+                revocation_url: Some(
+                    provider_env(name, "REVOCATION_URL").unwrap_or(APPLE_REVOCATION_URL.into()),
+                ),
+                end_session_url: None,
+                par_url: None,
+            },
+            allowed_tenants: Vec::new(),
+            allowed_domains: provider_allowed_domains(name),
+            auto_link: provider_auto_link(name),
+            claim_map: provider_claim_map(name),
+            id_token_audiences: provider_env_list(name, "ID_TOKEN_AUDIENCES"),
+            token_auth_method: TokenAuthMethod::ClientSecretPost,
+            id_token_signing_algs: provider_id_token_algs(name, &[Algorithm::RS256]),
+            max_age: provider_env_secs(name, "MAX_AGE"),
+            clock_skew: provider_clock_skew(name),
+            rp_initiated_logout: false,
+            pushed_auth_requests: false,
+        };
+        Self {
+            config,
+            team_id: required_provider_env(name, "TEAM_ID"),
+            key_id: required_provider_env(name, "KEY_ID"),
+            private_key: EncodingKey::from_ec_pem(private_key.as_bytes())
+                .unwrap_or_else(|e| panic!("Invalid OAUTH2_APPLE private key: {}", e)),
+            client_secret: Mutex::new(None),
+        }
+    }
+}
+
+impl OAuth2Provider for AppleProvider {
+    fn config(&self) -> &ProviderConfig {
+        &self.config
+    }
+
+    // Apple requires an ES256 JWT signed with the private key of the team
+    fn client_secret(&self) -> Result<String, OAuth2Error> {
+        let now = Utc::now().timestamp();
+        let mut cached = self
+            .client_secret
+            .lock()
+            .map_err(|e| OAuth2Error::Internal(e.to_string()))?;
+        if let Some((secret, exp)) = cached.as_ref()
+            && *exp > now + 60
+        {
+            return Ok(secret.clone());
+        }
+
+        let header = Header {
+            kid: Some(self.key_id.clone()),
+            ..Header::new(Algorithm::ES256)
+        };
+        let claims = ClientSecretClaims {
+            iss: &self.team_id,
+            sub: &self.config.client_id,
+            aud: APPLE_ISSUER,
+            iat: now,
+            exp: now + CLIENT_SECRET_LIFETIME,
+        };
+        let secret = jsonwebtoken::encode(&header, &claims, &self.private_key)
+            .map_err(|e| OAuth2Error::Crypto(e.to_string()))?;
+
+        *cached = Some((secret.clone(), claims.exp));
+        Ok(secret)
+    }
+
+    // The name is only posted on the first authorization, as the `user` form field, so it
+    // is left empty afterwards instead of replacing the stored one
+    fn to_oauth2_account(
+        &self,
+        idinfo: Option<&IdInfo>,
+        userinfo: &Value,
+    ) -> Result<OAuth2Account, OAuth2Error> {
+        let idinfo = idinfo.ok_or_else(|| OAuth2Error::IdToken("No ID token".to_string()))?;
+        let user_name = |key: &str| {
+            userinfo
+                .pointer(&format!("/user/name/{}", key))
+                .and_then(|v| v.as_str())
+                .filter(|v| !v.is_empty())
+                .map(String::from)
+        };
+        let given_name = user_name("firstName");
+        let family_name = user_name("lastName");
+
+        let email = idinfo.email.clone().unwrap_or_default();
+        let full_name = [given_name.as_deref(), family_name.as_deref()]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(" ");
+        let is_private_email =
+            idinfo.is_private_email.unwrap_or(false) || email.ends_with(APPLE_PRIVATE_RELAY_DOMAIN);
+
+        Ok(OAuth2Account {
+            id: String::new(),      // Will be set during storage
+            user_id: String::new(), // Will be set during upsert process
+            name: full_name,
+            email,
+            picture: None,
+            provider: self.name().to_string(),
+            provider_user_id: format!("{}_{}", self.name(), idinfo.sub),
+            metadata: json!({
+                "family_name": family_name,
+                "given_name": given_name,
+                "verified_email": idinfo.email_verified.unwrap_or(false),
+                "is_private_email": is_private_email,
+            }),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oauth2::config::response_mode_param;
+    use crate::test_utils::{init_test_env, test_ec_jwk};
+    use jsonwebtoken::{DecodingKey, Validation, jwk::Jwk};
+
+    /// Verify the client secret with the public key of the test key, returning its claims
+    fn secret_claims(secret: &str) -> Value {
+        let header = jsonwebtoken::decode_header(secret).unwrap();
+        assert_eq!(header.kid.as_deref(), Some("KEYID"));
+        let jwk: Jwk = serde_json::from_value(test_ec_jwk("KEYID")).unwrap();
+        let mut validation = Validation::new(Algorithm::ES256);
+        validation.set_audience(&[APPLE_ISSUER]);
+        jsonwebtoken::decode(secret, &DecodingKey::from_jwk(&jwk).unwrap(), &validation)
+            .unwrap()
+            .claims
+    }
+
+    #[test]
+    fn test_client_secret() {
+        init_test_env();
+        let provider = AppleProvider::new();
+
+        let secret = provider.client_secret().unwrap();
+        let claims = secret_claims(&secret);
+        assert_eq!(claims["iss"], "TEAMID");
+        assert_eq!(claims["sub"], "com.example.app");
+        let iat = claims["iat"].as_i64().unwrap();
+        assert_eq!(
+            claims["exp"].as_i64().unwrap() - iat,
+            CLIENT_SECRET_LIFETIME
+        );
+
+        // Cached while it is valid for more than a minute
+        assert_eq!(provider.client_secret().unwrap(), secret);
+
+        // Renewed when about to expire
+        let expiring = ("expiring-secret".to_string(), Utc::now().timestamp() + 30);
+        *provider.client_secret.lock().unwrap() = Some(expiring);
+        let renewed = provider.client_secret().unwrap();
+        assert_ne!(renewed, "expiring-secret");
+        assert!(secret_claims(&renewed)["exp"].as_i64().unwrap() > Utc::now().timestamp() + 60);
+    }
+
+    #[test]
+    fn test_response_mode() {
+        init_test_env();
+        let provider = AppleProvider::new();
+
+        // Apple's own response mode replaces OAUTH2_RESPONSE_MODE
+        assert_eq!(response_mode_param(&provider.config.extra_params), "");
+        assert!(
+            provider
+                .config
+                .extra_params
+                .contains("&response_mode=form_post")
+        );
+        assert_eq!(
+            response_mode_param("&ui_locales=en"),
+            "&response_mode=form_post"
+        );
+    }
+
+    #[test]
+    fn test_private_relay_email() {
+        init_test_env();
+        let provider = AppleProvider::new();
+        let account = |email: &str, is_private_email: Option<&str>| {
+            let idinfo: IdInfo = serde_json::from_value(json!({
+                "iss": APPLE_ISSUER,
+                "sub": "001234.abcd",
+                "aud": "com.example.app",
+                "iat": 0,
+                "exp": 0,
+                "email": email,
+                "email_verified": "true",
+                "is_private_email": is_private_email,
+            }))
+            .unwrap();
+            provider
+                .to_oauth2_account(Some(&idinfo), &json!({}))
+                .unwrap()
+        };
+        let is_private = |account: &OAuth2Account| account.metadata["is_private_email"] == true;
+
+        // Apple sends the claim as a string, or not at all
+        let relay = account("abc123@privaterelay.appleid.com", Some("true"));
+        assert!(is_private(&relay));
+        assert!(relay.email_verified());
+        assert!(is_private(&account(
+            "abc123@privaterelay.appleid.com",
+            None
+        )));
+        assert!(!is_private(&account("jane@example.com", Some("false"))));
+        assert!(!is_private(&account("jane@example.com", None)));
+
+        // The relay address is kept, it is how the user is reached
+        assert_eq!(relay.email, "abc123@privaterelay.appleid.com");
+        assert_eq!(relay.provider_user_id, "apple_001234.abcd");
+    }
+}
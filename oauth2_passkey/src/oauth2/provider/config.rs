@@ -0,0 +1,224 @@
+use jsonwebtoken::Algorithm;
+use std::{collections::HashMap, env, sync::LazyLock};
+
+use crate::oauth2::errors::OAuth2Error;
+use crate::oauth2::types::Prompt;
+
+use super::{
+    traits::OAuth2Provider,
+    types::{
+        AppleProvider, AutoLinkPolicy, ClaimMapping, ClaimTarget, GitHubProvider, GoogleProvider,
+        OidcProvider,
+    },
+};
+
+/// Names of the enabled providers, e.g. "google,keycloak"
+/// Default: "google"
+pub(super) static OAUTH2_PROVIDER_NAMES: LazyLock<Vec<String>> = LazyLock::new(|| {
+    parse_provider_names(&env::var("OAUTH2_PROVIDERS").unwrap_or_else(|_| "google".to_string()))
+});
+
+fn parse_provider_names(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Registry of the enabled providers keyed by name
+///
+/// "google", "github" and "apple" get their endpoints as defaults. Any other name is treated
+/// as a generic OpenID Connect provider configured by `OAUTH2_{NAME}_*` variables.
+pub(crate) static OAUTH2_PROVIDERS: LazyLock<HashMap<String, Box<dyn OAuth2Provider>>> =
+    LazyLock::new(|| {
+        OAUTH2_PROVIDER_NAMES
+            .iter()
+            .map(|name| {
+                let provider: Box<dyn OAuth2Provider> = match name.as_str() {
+                    "google" => Box::new(GoogleProvider::new()),
+                    "github" => Box::new(GitHubProvider::new()),
+                    "apple" => Box::new(AppleProvider::new()),
+                    _ => Box::new(OidcProvider::new(name)),
+                };
+                tracing::info!("Configured OAuth2 provider: {}", name);
+                (name.clone(), provider)
+            })
+            .collect()
+    });
+
+pub(crate) fn get_provider(name: &str) -> Result<&'static dyn OAuth2Provider, OAuth2Error> {
+    OAUTH2_PROVIDERS
+        .get(name)
+        .map(|provider| provider.as_ref())
+        .ok_or_else(|| OAuth2Error::UnsupportedProvider(name.to_string()))
+}
+
+/// Read a per-provider setting, e.g. `OAUTH2_GOOGLE_CLIENT_ID` for ("google", "CLIENT_ID")
+pub(super) fn provider_env(name: &str, key: &str) -> Option<String> {
+    env::var(provider_env_name(name, key)).ok()
+}
+
+/// Read a per-provider "true"/"false" setting, false if not set
+pub(super) fn provider_env_flag(name: &str, key: &str) -> bool {
+    provider_env(name, key).is_some_and(|value| value == "true")
+}
+
+/// Read a per-provider comma separated list, empty if not set
+pub(super) fn provider_env_list(name: &str, key: &str) -> Vec<String> {
+    provider_env(name, key)
+        .map(|values| {
+            values
+                .split(',')
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Read `OAUTH2_{NAME}_ALLOWED_DOMAINS`, lowercased
+pub(super) fn provider_allowed_domains(name: &str) -> Vec<String> {
+    provider_env_list(name, "ALLOWED_DOMAINS")
+        .into_iter()
+        .map(|domain| domain.to_lowercase())
+        .collect()
+}
+
+/// Read `OAUTH2_{NAME}_CLAIM_MAP`, comma separated `target=claim` pairs where the target
+/// is "name", "email", "picture" or "metadata.{key}",
+/// e.g. "name=preferred_username,metadata.groups=groups"
+pub(super) fn provider_claim_map(name: &str) -> Vec<ClaimMapping> {
+    provider_env_list(name, "CLAIM_MAP")
+        .iter()
+        .map(|mapping| {
+            let invalid = || format!("Invalid claim mapping for {}: {}", name, mapping);
+            let Some((target, claim)) = mapping.split_once('=') else {
+                panic!("{}", invalid());
+            };
+            let target = match target.trim() {
+                "name" => ClaimTarget::Name,
+                "email" => ClaimTarget::Email,
+                "picture" => ClaimTarget::Picture,
+                target => match target.strip_prefix("metadata.") {
+                    Some(key) if !key.is_empty() => ClaimTarget::Metadata(key.to_string()),
+                    _ => panic!("{}", invalid()),
+                },
+            };
+            let claim = claim.trim();
+            if claim.is_empty() {
+                panic!("{}", invalid());
+            }
+            ClaimMapping {
+                target,
+                claim: claim.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Read `OAUTH2_{NAME}_AUTO_LINK`, "true", "confirm" or "false" (default)
+pub(super) fn provider_auto_link(name: &str) -> AutoLinkPolicy {
+    match provider_env(name, "AUTO_LINK").as_deref() {
+        None | Some("false") => AutoLinkPolicy::Off,
+        Some("true") => AutoLinkPolicy::Auto,
+        Some("confirm") => AutoLinkPolicy::Confirm,
+        Some(policy) => panic!("Invalid auto link policy for {}: {}", name, policy),
+    }
+}
+
+/// Read `OAUTH2_{NAME}_PROMPT`, where an empty value sends no prompt
+pub(super) fn provider_prompt(name: &str, default: Option<Prompt>) -> Option<Prompt> {
+    match provider_env(name, "PROMPT") {
+        None => default,
+        Some(prompt) if prompt.is_empty() => None,
+        Some(prompt) => Some(
+            prompt
+                .parse()
+                .unwrap_or_else(|_| panic!("Invalid prompt for {}: {}", name, prompt)),
+        ),
+    }
+}
+
+/// Default tolerated clock difference in seconds, see `ProviderConfig::clock_skew`
+const DEFAULT_CLOCK_SKEW: u64 = 2;
+
+/// Read a per-provider number of seconds, e.g. `OAUTH2_GOOGLE_MAX_AGE`
+pub(super) fn provider_env_secs(name: &str, key: &str) -> Option<u64> {
+    provider_env(name, key).map(|value| {
+        value.parse().unwrap_or_else(|_| {
+            panic!(
+                "{} must be a number of seconds",
+                provider_env_name(name, key)
+            )
+        })
+    })
+}
+
+/// Read `OAUTH2_{NAME}_CLOCK_SKEW`
+pub(super) fn provider_clock_skew(name: &str) -> u64 {
+    provider_env_secs(name, "CLOCK_SKEW").unwrap_or(DEFAULT_CLOCK_SKEW)
+}
+
+/// Read `OAUTH2_{NAME}_ID_TOKEN_SIGNING_ALGS`, the comma separated algorithms accepted
+/// for ID tokens
+///
+/// Symmetric algorithms (HS*) are refused, ID tokens have to be signed with the
+/// provider's published keys.
+pub(super) fn provider_id_token_algs(name: &str, default: &[Algorithm]) -> Vec<Algorithm> {
+    let Some(algs) = provider_env(name, "ID_TOKEN_SIGNING_ALGS") else {
+        return default.to_vec();
+    };
+    algs.split(',')
+        .map(str::trim)
+        .filter(|alg| !alg.is_empty())
+        .map(|alg| match alg.parse::<Algorithm>() {
+            Ok(Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) | Err(_) => {
+                panic!(
+                    "Unsupported ID token signing algorithm for {}: {}",
+                    name, alg
+                )
+            }
+            Ok(alg) => alg,
+        })
+        .collect()
+}
+
+pub(super) fn required_provider_env(name: &str, key: &str) -> String {
+    let var = provider_env_name(name, key);
+    env::var(&var).unwrap_or_else(|_| panic!("{} must be set", var))
+}
+
+fn provider_env_name(name: &str, key: &str) -> String {
+    format!("OAUTH2_{}_{}", name.to_uppercase().replace('-', "_"), key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::init_test_env;
+
+    #[test]
+    fn test_parse_provider_names() {
+        assert_eq!(parse_provider_names("google"), vec!["google"]);
+        assert_eq!(
+            parse_provider_names(" Google, keycloak ,,GitHub,"),
+            vec!["google", "keycloak", "github"]
+        );
+        assert!(parse_provider_names(" , ").is_empty());
+    }
+
+    #[test]
+    fn test_get_provider() {
+        init_test_env();
+
+        assert_eq!(get_provider("github").unwrap().name(), "github");
+        assert_eq!(get_provider("test-idp").unwrap().name(), "test-idp");
+        assert!(matches!(
+            get_provider("no-such-provider"),
+            Err(OAuth2Error::UnsupportedProvider(name)) if name == "no-such-provider"
+        ));
+        // Names are matched as configured, in lowercase
+        assert!(get_provider("GitHub").is_err());
+    }
+}
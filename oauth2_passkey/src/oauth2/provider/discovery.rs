@@ -0,0 +1,269 @@
+use std::{
+    collections::HashMap,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+
+use crate::oauth2::errors::OAuth2Error;
+use crate::oauth2::main::get_client;
+
+use super::types::{ProviderEndpoints, ProviderMetadata};
+
+const DISCOVERY_CACHE_EXPIRATION: Duration = Duration::from_secs(3600);
+
+struct CachedMetadata {
+    metadata: ProviderMetadata,
+    expiration: Instant,
+}
+
+static DISCOVERY_CACHE: LazyLock<RwLock<HashMap<String, CachedMetadata>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Fetch the discovery document of the issuer, cached for an hour
+pub(super) async fn fetch_provider_metadata(
+    issuer_url: &str,
+) -> Result<ProviderMetadata, OAuth2Error> {
+    {
+        let cache = DISCOVERY_CACHE.read().await;
+        if let Some(cached) = cache.get(issuer_url)
+            && cached.expiration > Instant::now()
+        {
+            return Ok(cached.metadata.clone());
+        }
+    }
+
+    let discovery_url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer_url.trim_end_matches('/')
+    );
+    tracing::debug!("Fetching OIDC discovery document: {}", discovery_url);
+
+    let response = get_client()
+        .get(&discovery_url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| OAuth2Error::Discovery(format!("{}: {}", discovery_url, e)))?;
+    let metadata: ProviderMetadata = response
+        .json()
+        .await
+        .map_err(|e| OAuth2Error::Discovery(format!("{}: {}", discovery_url, e)))?;
+
+    // The issuer in the document must be the one it was fetched for (OIDC Discovery 1.0, 4.3)
+    if !issuer_matches(&metadata.issuer, issuer_url) {
+        return Err(OAuth2Error::Discovery(format!(
+            "Issuer mismatch, expected: {}, actual: {}",
+            issuer_url, metadata.issuer
+        )));
+    }
+
+    DISCOVERY_CACHE.write().await.insert(
+        issuer_url.to_string(),
+        CachedMetadata {
+            metadata: metadata.clone(),
+            expiration: Instant::now() + DISCOVERY_CACHE_EXPIRATION,
+        },
+    );
+
+    Ok(metadata)
+}
+
+/// Compare the discovered issuer with the issuer URL, ignoring a trailing slash
+///
+/// Multi-tenant issuers like "https://login.microsoftonline.com/{tenantid}/v2.0" are
+/// published for tenant independent URLs like ".../organizations/v2.0", so the
+/// placeholder matches any path segment.
+fn issuer_matches(issuer: &str, issuer_url: &str) -> bool {
+    let issuer = issuer.trim_end_matches('/');
+    let issuer_url = issuer_url.trim_end_matches('/');
+    let placeholder = ["{tid}", "{tenantid}"]
+        .into_iter()
+        .find_map(|p| issuer.split_once(p));
+
+    match placeholder {
+        Some((prefix, suffix)) => issuer_url
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix))
+            .is_some_and(|tenant| !tenant.is_empty() && !tenant.contains('/')),
+        None => issuer == issuer_url,
+    }
+}
+
+impl ProviderEndpoints {
+    /// Combine the explicitly configured endpoints with the discovered ones
+    pub(super) fn resolve(
+        &self,
+        discovered: Option<ProviderMetadata>,
+    ) -> Result<ProviderMetadata, OAuth2Error> {
+        let missing = |key: &str| OAuth2Error::Discovery(format!("No {} configured", key));
+        let id_token_signing_alg_values_supported = discovered
+            .as_ref()
+            .map(|m| m.id_token_signing_alg_values_supported.clone())
+            .unwrap_or_default();
+
+        Ok(ProviderMetadata {
+            issuer: self
+                .issuer
+                .clone()
+                .or_else(|| discovered.as_ref().map(|m| m.issuer.clone()))
+                .ok_or_else(|| missing("issuer"))?,
+            authorization_endpoint: self
+                .auth_url
+                .clone()
+                .or_else(|| {
+                    discovered
+                        .as_ref()
+                        .map(|m| m.authorization_endpoint.clone())
+                })
+                .ok_or_else(|| missing("authorization endpoint"))?,
+            token_endpoint: self
+                .token_url
+                .clone()
+                .or_else(|| discovered.as_ref().map(|m| m.token_endpoint.clone()))
+                .ok_or_else(|| missing("token endpoint"))?,
+            userinfo_endpoint: self.userinfo_url.clone().or_else(|| {
+                discovered
+                    .as_ref()
+                    .and_then(|m| m.userinfo_endpoint.clone())
+            }),
+            jwks_uri: self
+                .jwks_url
+                .clone()
+                .or_else(|| discovered.as_ref().and_then(|m| m.jwks_uri.clone())),
+            revocation_endpoint: self.revocation_url.clone().or_else(|| {
+                discovered
+                    .as_ref()
+                    .and_then(|m| m.revocation_endpoint.clone())
+            }),
+            end_session_endpoint: self.end_session_url.clone().or_else(|| {
+                discovered
+                    .as_ref()
+                    .and_then(|m| m.end_session_endpoint.clone())
+            }),
+            pushed_authorization_request_endpoint: self.par_url.clone().or_else(|| {
+                discovered
+                    .as_ref()
+                    .and_then(|m| m.pushed_authorization_request_endpoint.clone())
+            }),
+            require_pushed_authorization_requests: discovered
+                .as_ref()
+                .is_some_and(|m| m.require_pushed_authorization_requests),
+            id_token_signing_alg_values_supported,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::serve_http;
+    use serde_json::json;
+
+    fn discovery_document(issuer: &str) -> serde_json::Value {
+        json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+            "id_token_signing_alg_values_supported": ["RS256"],
+        })
+    }
+
+    #[test]
+    fn test_issuer_matches() {
+        let issuer = "https://idp.example.com";
+        assert!(issuer_matches(issuer, "https://idp.example.com"));
+        assert!(issuer_matches("https://idp.example.com/", issuer));
+        assert!(!issuer_matches(issuer, "https://other.example.com"));
+        assert!(!issuer_matches(issuer, "https://idp.example.com/tenant"));
+
+        // The tenant placeholder stands for one path segment
+        let entra = "https://login.microsoftonline.com/{tenantid}/v2.0";
+        let tenant_url = "https://login.microsoftonline.com/organizations/v2.0";
+        assert!(issuer_matches(entra, tenant_url));
+        assert!(issuer_matches(
+            "https://login.microsoftonline.com/{tid}/v2.0",
+            tenant_url
+        ));
+        assert!(!issuer_matches(
+            entra,
+            "https://login.microsoftonline.com//v2.0"
+        ));
+        assert!(!issuer_matches(
+            entra,
+            "https://login.microsoftonline.com/a/b/v2.0"
+        ));
+        assert!(!issuer_matches(
+            entra,
+            "https://evil.example.com/organizations/v2.0"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_provider_metadata() {
+        let (issuer_url, server) = serve_http(vec![(200, discovery_document("{base_url}"))]).await;
+        let metadata = fetch_provider_metadata(&issuer_url).await.unwrap();
+        assert_eq!(metadata.issuer, issuer_url);
+        assert_eq!(metadata.token_endpoint, format!("{}/token", issuer_url));
+        let requests = server.await.unwrap();
+        assert_eq!(
+            requests[0].line,
+            "GET /.well-known/openid-configuration HTTP/1.1"
+        );
+
+        // Cached, no other request is served
+        assert!(fetch_provider_metadata(&issuer_url).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_provider_metadata_issuer_mismatch() {
+        let document = discovery_document("https://other.example.com");
+        let (issuer_url, server) = serve_http(vec![(200, document)]).await;
+        let result = fetch_provider_metadata(&issuer_url).await;
+        assert!(matches!(
+            result,
+            Err(OAuth2Error::Discovery(e)) if e.starts_with("Issuer mismatch")
+        ));
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_resolve() {
+        let discovered: ProviderMetadata =
+            serde_json::from_value(discovery_document("https://idp.example.com")).unwrap();
+        let endpoints = ProviderEndpoints {
+            auth_url: Some("https://login.example.com/authorize".to_string()),
+            jwks_url: Some("https://keys.example.com/jwks".to_string()),
+            ..Default::default()
+        };
+
+        // Configured endpoints take precedence over the discovered ones
+        let metadata = endpoints.resolve(Some(discovered.clone())).unwrap();
+        assert_eq!(
+            metadata.authorization_endpoint,
+            "https://login.example.com/authorize"
+        );
+        assert_eq!(
+            metadata.jwks_uri.as_deref(),
+            Some("https://keys.example.com/jwks")
+        );
+        assert_eq!(metadata.issuer, "https://idp.example.com");
+        assert_eq!(metadata.token_endpoint, "https://idp.example.com/token");
+        assert_eq!(metadata.id_token_signing_alg_values_supported, ["RS256"]);
+
+        // Without discovery every required endpoint must be configured
+        assert!(matches!(
+            endpoints.resolve(None),
+            Err(OAuth2Error::Discovery(e)) if e == "No issuer configured"
+        ));
+        let endpoints = ProviderEndpoints {
+            issuer: Some("https://idp.example.com".to_string()),
+            ..endpoints
+        };
+        assert!(matches!(
+            endpoints.resolve(None),
+            Err(OAuth2Error::Discovery(e)) if e == "No token endpoint configured"
+        ));
+    }
+}
@@ -0,0 +1,241 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use http::header::{ACCEPT, USER_AGENT};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::oauth2::errors::OAuth2Error;
+use crate::oauth2::main::{IdInfo, get_client};
+use crate::oauth2::types::OAuth2Account;
+
+use super::config::{
+    provider_allowed_domains, provider_auto_link, provider_claim_map, provider_env,
+    provider_env_flag, provider_env_list, provider_prompt, required_provider_env,
+};
+use super::traits::OAuth2Provider;
+use super::types::{
+    GitHubProvider, ProviderConfig, ProviderEndpoints, ProviderMetadata, TokenAuthMethod,
+};
+
+const GITHUB_AUTH_URL: &str = "https://github.com/login/oauth/authorize";
+const GITHUB_TOKEN_URL: &str = "https://github.com/login/oauth/access_token";
+const GITHUB_API_URL: &str = "https://api.github.com";
+const GITHUB_ISSUER: &str = "https://github.com";
+
+/// An entry of the `/user/emails` response
+#[derive(Debug, Deserialize)]
+struct GitHubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+impl GitHubProvider {
+    pub(super) fn new() -> Self {
+        let name = "github";
+        // OAUTH2_GITHUB_API_URL allows GitHub Enterprise Server, e.g. "https://github.example.com/api/v3"
+        let api_url = provider_env(name, "API_URL").unwrap_or(GITHUB_API_URL.to_string());
+        let config = ProviderConfig {
+            name: name.to_string(),
+            client_id: required_provider_env(name, "CLIENT_ID"),
+            client_secret: required_provider_env(name, "CLIENT_SECRET"),
+            scope: provider_env(name, "SCOPE").unwrap_or("read:user+user:email".to_string()),
+            extra_scopes: provider_env_list(name, "EXTRA_SCOPES"),
+            extra_params: provider_env(name, "EXTRA_PARAMS").unwrap_or_default(),
+            prompt: provider_prompt(name, None),
+            offline_access: provider_env_flag(name, "OFFLINE_ACCESS"),
+            issuer_url: None,
+            endpoints: ProviderEndpoints {
+                auth_url: Some(provider_env(name, "AUTH_URL").unwrap_or(GITHUB_AUTH_URL.into())),
+                token_url: Some(provider_env(name, "TOKEN_URL").unwrap_or(GITHUB_TOKEN_URL.into())),
+                userinfo_url: Some(format!("{}/user", api_url)),
+                jwks_url: None,
+                issuer: Some(provider_env(name, "ISSUER").unwrap_or(GITHUB_ISSUER.into())),
+                revocation_url: None,
+                end_session_url: None,
+                par_url: None,
+            },
+            allowed_tenants: Vec::new(),
+            allowed_domains: provider_allowed_domains(name),
+            auto_link: provider_auto_link(name),
+            claim_map: provider_claim_map(name),
+            id_token_audiences: Vec::new(),
+            token_auth_method: TokenAuthMethod::ClientSecretPost,
+            // GitHub issues no ID token
+            id_token_signing_algs: Vec::new(),
+            max_age: None,
+            clock_skew: 0,
+            rp_initiated_logout: false,
+            pushed_auth_requests: false,
+        };
+        Self {
+            grant_url: format!("{}/applications/{}/grant", api_url, config.client_id),
+            config,
+            emails_url: format!("{}/user/emails", api_url),
+        }
+    }
+}
+
+/// GET a GitHub REST API resource, which requires a User-Agent header
+async fn fetch_github_api(url: &str, access_token: &str) -> Result<Value, OAuth2Error> {
+    get_client()
+        .get(url)
+        .bearer_auth(access_token)
+        .header(USER_AGENT, "oauth2_passkey")
+        .header(ACCEPT, "application/vnd.github+json")
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| OAuth2Error::FetchUserInfo(e.to_string()))?
+        .json()
+        .await
+        .map_err(|e| OAuth2Error::Serde(format!("Failed to deserialize response body: {}", e)))
+}
+
+#[async_trait]
+impl OAuth2Provider for GitHubProvider {
+    fn config(&self) -> &ProviderConfig {
+        &self.config
+    }
+
+    fn issues_id_token(&self) -> bool {
+        false
+    }
+
+    // The profile email is optional and may be unverified, so the primary verified
+    // address from /user/emails replaces it
+    async fn fetch_userinfo(
+        &self,
+        userinfo_url: &str,
+        access_token: &str,
+    ) -> Result<Value, OAuth2Error> {
+        let mut userinfo = fetch_github_api(userinfo_url, access_token).await?;
+        let emails: Vec<GitHubEmail> =
+            serde_json::from_value(fetch_github_api(&self.emails_url, access_token).await?)
+                .map_err(|e| OAuth2Error::Serde(e.to_string()))?;
+        tracing::debug!("GitHub user: {:#?}, emails: {:#?}", userinfo, emails);
+
+        let primary = emails.into_iter().find(|e| e.primary && e.verified);
+        if let Some(obj) = userinfo.as_object_mut() {
+            obj.insert("email_verified".to_string(), json!(primary.is_some()));
+            if let Some(primary) = primary {
+                obj.insert("email".to_string(), json!(primary.email));
+            }
+        }
+        Ok(userinfo)
+    }
+
+    // GitHub has no RFC 7009 endpoint, deleting the grant revokes all tokens of the app
+    async fn revoke_tokens(
+        &self,
+        _metadata: &ProviderMetadata,
+        access_token: &str,
+        _refresh_token: Option<&str>,
+    ) -> Result<(), OAuth2Error> {
+        get_client()
+            .delete(&self.grant_url)
+            .basic_auth(&self.config.client_id, Some(&self.config.client_secret))
+            .header(USER_AGENT, "oauth2_passkey")
+            .header(ACCEPT, "application/vnd.github+json")
+            .json(&json!({ "access_token": access_token }))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| OAuth2Error::TokenRevocation(e.to_string()))?;
+        Ok(())
+    }
+
+    fn to_oauth2_account(
+        &self,
+        _idinfo: Option<&IdInfo>,
+        userinfo: &Value,
+    ) -> Result<OAuth2Account, OAuth2Error> {
+        let claim = |key: &str| userinfo.get(key).and_then(|v| v.as_str()).map(String::from);
+
+        let id = userinfo
+            .get("id")
+            .and_then(|v| v.as_u64())
+            .ok_or_else(|| OAuth2Error::FetchUserInfo("No id in GitHub user".to_string()))?;
+        let login = claim("login").unwrap_or_default();
+
+        Ok(OAuth2Account {
+            id: String::new(),      // Will be set during storage
+            user_id: String::new(), // Will be set during upsert process
+            name: claim("name")
+                .filter(|name| !name.is_empty())
+                .unwrap_or(login.clone()),
+            email: claim("email").unwrap_or_default(),
+            picture: claim("avatar_url"),
+            provider: self.name().to_string(),
+            provider_user_id: format!("{}_{}", self.name(), id),
+            metadata: json!({
+                "login": login,
+                "verified_email": userinfo
+                    .get("email_verified")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false),
+            }),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{init_test_env, serve_http};
+
+    /// Sign in a GitHub user with these `/user/emails` entries through a local API
+    async fn github_account(emails: Value) -> OAuth2Account {
+        init_test_env();
+        let user = json!({
+            "id": 42,
+            "login": "octocat",
+            "name": "The Octocat",
+            "email": "public@example.com",
+        });
+        let (api_url, server) = serve_http(vec![(200, user), (200, emails)]).await;
+        let provider = GitHubProvider {
+            emails_url: format!("{}/user/emails", api_url),
+            ..GitHubProvider::new()
+        };
+
+        let userinfo = provider
+            .fetch_userinfo(&format!("{}/user", api_url), "access")
+            .await
+            .unwrap();
+        let requests = server.await.unwrap();
+        assert_eq!(requests[1].line, "GET /user/emails HTTP/1.1");
+        provider.to_oauth2_account(None, &userinfo).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_primary_verified_email() {
+        let account = github_account(json!([
+            { "email": "other@example.com", "primary": false, "verified": true },
+            { "email": "primary@example.com", "primary": true, "verified": true },
+        ]))
+        .await;
+
+        assert_eq!(account.email, "primary@example.com");
+        assert!(account.email_verified());
+        assert_eq!(account.provider_user_id, "github_42");
+        assert_eq!(account.name, "The Octocat");
+        assert_eq!(account.metadata["login"], "octocat");
+    }
+
+    #[tokio::test]
+    async fn test_no_verified_primary_email() {
+        // A verified address that isn't the primary one isn't taken either
+        let account = github_account(json!([
+            { "email": "other@example.com", "primary": false, "verified": true },
+            { "email": "primary@example.com", "primary": true, "verified": false },
+        ]))
+        .await;
+
+        // The profile email stays, unverified
+        assert_eq!(account.email, "public@example.com");
+        assert!(!account.email_verified());
+    }
+}
@@ -0,0 +1,152 @@
+use chrono::Utc;
+use jsonwebtoken::Algorithm;
+use serde_json::{Value, json};
+use std::env;
+use url::form_urlencoded::byte_serialize;
+
+use crate::oauth2::errors::OAuth2Error;
+use crate::oauth2::main::IdInfo;
+use crate::oauth2::types::{OAuth2Account, Prompt};
+
+use super::config::{
+    provider_allowed_domains, provider_auto_link, provider_claim_map, provider_clock_skew,
+    provider_env, provider_env_flag, provider_env_list, provider_env_secs, provider_id_token_algs,
+    provider_prompt, required_provider_env,
+};
+use super::traits::OAuth2Provider;
+use super::types::{GoogleProvider, ProviderConfig, ProviderEndpoints, TokenAuthMethod};
+
+const GOOGLE_AUTH_URL: &str = "https://accounts.google.com/o/oauth2/v2/auth";
+const GOOGLE_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const GOOGLE_USERINFO_URL: &str = "https://www.googleapis.com/userinfo/v2/me";
+const GOOGLE_JWKS_URL: &str = "https://www.googleapis.com/oauth2/v3/certs";
+const GOOGLE_REVOCATION_URL: &str = "https://oauth2.googleapis.com/revoke";
+const GOOGLE_ISSUER: &str = "https://accounts.google.com";
+
+impl GoogleProvider {
+    pub(super) fn new() -> Self {
+        let name = "google";
+        // OAUTH2_AUTH_URL, OAUTH2_TOKEN_URL and OAUTH2_SCOPE are kept for backward compatibility
+        let issuer_url = provider_env(name, "ISSUER_URL");
+        // Google's endpoints are the defaults unless they are discovered from OAUTH2_GOOGLE_ISSUER_URL
+        let default = |url: &str| issuer_url.is_none().then(|| url.to_string());
+        let offline_access = provider_env_flag(name, "OFFLINE_ACCESS");
+        let allowed_domains = provider_allowed_domains(name);
+        let config = ProviderConfig {
+            name: name.to_string(),
+            client_id: required_provider_env(name, "CLIENT_ID"),
+            client_secret: required_provider_env(name, "CLIENT_SECRET"),
+            scope: provider_env(name, "SCOPE")
+                .or_else(|| env::var("OAUTH2_SCOPE").ok())
+                .unwrap_or("openid+email+profile".to_string()),
+            extra_scopes: provider_env_list(name, "EXTRA_SCOPES"),
+            extra_params: format!(
+                "&access_type={}{}",
+                if offline_access { "offline" } else { "online" },
+                hd_hint(&allowed_domains)
+            ),
+            // Consent has to be asked again for Google to issue another refresh token
+            prompt: provider_prompt(name, Some(Prompt::Consent)),
+            offline_access,
+            endpoints: ProviderEndpoints {
+                auth_url: provider_env(name, "AUTH_URL")
+                    .or_else(|| env::var("OAUTH2_AUTH_URL").ok())
+                    .or_else(|| default(GOOGLE_AUTH_URL)),
+                token_url: provider_env(name, "TOKEN_URL")
+                    .or_else(|| env::var("OAUTH2_TOKEN_URL").ok())
+                    .or_else(|| default(GOOGLE_TOKEN_URL)),
+                userinfo_url: provider_env(name, "USERINFO_URL")
+                    .or_else(|| default(GOOGLE_USERINFO_URL)),
+                jwks_url: provider_env(name, "JWKS_URL").or_else(|| default(GOOGLE_JWKS_URL)),
+                issuer: provider_env(name, "ISSUER").or_else(|| default(GOOGLE_ISSUER)),
+                revocation_url: provider_env(name, "REVOCATION_URL")
+                    .or_else(|| default(GOOGLE_REVOCATION_URL)),
+                // Google has no end_session_endpoint
+                end_session_url: None,
+                par_url: None,
+            },
+            issuer_url,
+            allowed_tenants: Vec::new(),
+            allowed_domains,
+            auto_link: provider_auto_link(name),
+            claim_map: provider_claim_map(name),
+            id_token_audiences: provider_env_list(name, "ID_TOKEN_AUDIENCES"),
+            token_auth_method: TokenAuthMethod::from_env(name),
+            id_token_signing_algs: provider_id_token_algs(name, &[Algorithm::RS256]),
+            max_age: provider_env_secs(name, "MAX_AGE"),
+            clock_skew: provider_clock_skew(name),
+            rp_initiated_logout: false,
+            pushed_auth_requests: false,
+        };
+        Self { config }
+    }
+}
+
+/// `hd` parameter restricting the account chooser, which only takes a single domain
+fn hd_hint(allowed_domains: &[String]) -> String {
+    match allowed_domains {
+        [domain] => format!(
+            "&hd={}",
+            byte_serialize(domain.as_bytes()).collect::<String>()
+        ),
+        _ => String::new(),
+    }
+}
+
+impl OAuth2Provider for GoogleProvider {
+    fn config(&self) -> &ProviderConfig {
+        &self.config
+    }
+
+    // The v2 userinfo endpoint returns the subject as "id"
+    fn userinfo_subject(&self, userinfo: &Value) -> Option<String> {
+        userinfo
+            .get("id")
+            .or_else(|| userinfo.get("sub"))
+            .and_then(|v| v.as_str())
+            .map(String::from)
+    }
+
+    fn to_oauth2_account(
+        &self,
+        idinfo: Option<&IdInfo>,
+        _userinfo: &Value,
+    ) -> Result<OAuth2Account, OAuth2Error> {
+        let idinfo = idinfo.ok_or_else(|| OAuth2Error::IdToken("No ID token".to_string()))?;
+        Ok(OAuth2Account {
+            id: String::new(),      // Will be set during storage
+            user_id: String::new(), // Will be set during upsert process
+            name: idinfo.name.clone().unwrap_or_default(),
+            email: idinfo.email.clone().unwrap_or_default(),
+            picture: idinfo.picture.clone(),
+            provider: self.name().to_string(),
+            provider_user_id: format!("{}_{}", self.name(), idinfo.sub),
+            metadata: json!({
+                "family_name": idinfo.family_name,
+                "given_name": idinfo.given_name,
+                "hd": idinfo.hd,
+                "verified_email": idinfo.email_verified.unwrap_or(false),
+            }),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hd_hint() {
+        let domains = |domains: &[&str]| domains.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+        assert_eq!(hd_hint(&domains(&["example.com"])), "&hd=example.com");
+        // The domain comes from the environment and can't add parameters
+        assert_eq!(
+            hd_hint(&domains(&["example.com&prompt=none"])),
+            "&hd=example.com%26prompt%3Dnone"
+        );
+        assert_eq!(hd_hint(&domains(&["example.com", "example.org"])), "");
+        assert_eq!(hd_hint(&[]), "");
+    }
+}
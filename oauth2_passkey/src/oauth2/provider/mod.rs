@@ -0,0 +1,54 @@
+mod apple;
+mod client_auth;
+mod config;
+mod discovery;
+mod github;
+mod google;
+mod oidc;
+mod traits;
+mod types;
+
+pub use client_auth::get_oauth2_client_jwks;
+pub(crate) use client_auth::{SigningKey, client_auth_request, signing_key_from_env};
+pub(crate) use config::{OAUTH2_PROVIDERS, get_provider};
+pub(crate) use traits::OAuth2Provider;
+pub(crate) use types::{
+    AutoLinkPolicy, ClaimMapping, ClaimTarget, ProviderConfig, ProviderMetadata,
+};
+
+/// List the names of the configured OAuth2 providers, in configuration order
+pub fn list_oauth2_providers() -> Vec<String> {
+    config::OAUTH2_PROVIDER_NAMES.clone()
+}
+
+/// Auto link policy of a provider, `Off` for providers no longer configured
+pub(crate) fn auto_link_policy(name: &str) -> AutoLinkPolicy {
+    get_provider(name)
+        .map(|provider| provider.config().auto_link)
+        .unwrap_or(AutoLinkPolicy::Off)
+}
+
+/// An OpenID Connect provider configured like the named one, with changes, for tests
+#[cfg(test)]
+pub(crate) fn test_oidc_provider(
+    name: &str,
+    configure: impl FnOnce(&mut ProviderConfig),
+) -> Box<dyn OAuth2Provider> {
+    let mut provider = types::OidcProvider::new(name);
+    configure(&mut provider.config);
+    Box::new(provider)
+}
+
+/// Fetch the discovery documents up front so misconfigured issuers show up at startup
+///
+/// A failure is only logged, as the provider may be temporarily unreachable.
+pub(crate) async fn prefetch_provider_metadata() {
+    for (name, provider) in OAUTH2_PROVIDERS.iter() {
+        if provider.config().issuer_url.is_none() {
+            continue;
+        }
+        if let Err(e) = provider.metadata().await {
+            tracing::error!("Failed to discover OAuth2 provider {}: {}", name, e);
+        }
+    }
+}
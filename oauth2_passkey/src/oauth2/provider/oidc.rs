@@ -0,0 +1,131 @@
+use chrono::Utc;
+use jsonwebtoken::Algorithm;
+use serde_json::{Value, json};
+
+use crate::oauth2::errors::OAuth2Error;
+use crate::oauth2::main::IdInfo;
+use crate::oauth2::types::OAuth2Account;
+
+use super::config::{
+    provider_allowed_domains, provider_auto_link, provider_claim_map, provider_clock_skew,
+    provider_env, provider_env_flag, provider_env_list, provider_env_secs, provider_id_token_algs,
+    provider_prompt, required_provider_env,
+};
+use super::traits::OAuth2Provider;
+use super::types::{OidcProvider, ProviderConfig, ProviderEndpoints, TokenAuthMethod};
+
+/// Asymmetric algorithms jsonwebtoken verifies, accepted unless configured otherwise
+const DEFAULT_ID_TOKEN_SIGNING_ALGS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+impl OidcProvider {
+    pub(super) fn new(name: &str) -> Self {
+        // Endpoints are required unless they are discovered from OAUTH2_{NAME}_ISSUER_URL
+        let issuer_url = provider_env(name, "ISSUER_URL");
+        let endpoint = |key: &str| match issuer_url {
+            Some(_) => provider_env(name, key),
+            None => Some(required_provider_env(name, key)),
+        };
+        let offline_access = provider_env_flag(name, "OFFLINE_ACCESS");
+        let mut scope = provider_env(name, "SCOPE").unwrap_or("openid+email+profile".to_string());
+        if offline_access && !scope.split('+').any(|s| s == "offline_access") {
+            scope.push_str("+offline_access");
+        }
+        let token_auth_method = TokenAuthMethod::from_env(name);
+        let config = ProviderConfig {
+            name: name.to_string(),
+            client_id: required_provider_env(name, "CLIENT_ID"),
+            // Not needed when the client authenticates with its signing key
+            client_secret: match token_auth_method {
+                TokenAuthMethod::PrivateKeyJwt => {
+                    provider_env(name, "CLIENT_SECRET").unwrap_or_default()
+                }
+                _ => required_provider_env(name, "CLIENT_SECRET"),
+            },
+            scope,
+            extra_scopes: provider_env_list(name, "EXTRA_SCOPES"),
+            extra_params: provider_env(name, "EXTRA_PARAMS").unwrap_or_default(),
+            prompt: provider_prompt(name, None),
+            offline_access,
+            endpoints: ProviderEndpoints {
+                auth_url: endpoint("AUTH_URL"),
+                token_url: endpoint("TOKEN_URL"),
+                userinfo_url: provider_env(name, "USERINFO_URL"),
+                jwks_url: endpoint("JWKS_URL"),
+                issuer: endpoint("ISSUER"),
+                revocation_url: provider_env(name, "REVOCATION_URL"),
+                end_session_url: provider_env(name, "END_SESSION_URL"),
+                par_url: provider_env(name, "PAR_URL"),
+            },
+            issuer_url,
+            allowed_tenants: provider_env_list(name, "ALLOWED_TENANTS"),
+            allowed_domains: provider_allowed_domains(name),
+            auto_link: provider_auto_link(name),
+            claim_map: provider_claim_map(name),
+            id_token_audiences: provider_env_list(name, "ID_TOKEN_AUDIENCES"),
+            token_auth_method,
+            id_token_signing_algs: provider_id_token_algs(name, DEFAULT_ID_TOKEN_SIGNING_ALGS),
+            max_age: provider_env_secs(name, "MAX_AGE"),
+            clock_skew: provider_clock_skew(name),
+            rp_initiated_logout: provider_env_flag(name, "RP_INITIATED_LOGOUT"),
+            pushed_auth_requests: provider_env_flag(name, "PAR"),
+        };
+        Self { config }
+    }
+}
+
+impl OAuth2Provider for OidcProvider {
+    fn config(&self) -> &ProviderConfig {
+        &self.config
+    }
+
+    // Standard claims from the ID token take precedence over the userinfo response
+    fn to_oauth2_account(
+        &self,
+        idinfo: Option<&IdInfo>,
+        userinfo: &Value,
+    ) -> Result<OAuth2Account, OAuth2Error> {
+        let idinfo = idinfo.ok_or_else(|| OAuth2Error::IdToken("No ID token".to_string()))?;
+        let claim = |key: &str| userinfo.get(key).and_then(|v| v.as_str()).map(String::from);
+
+        let email = idinfo.email.clone().or_else(|| claim("email"));
+        let name = idinfo
+            .name
+            .clone()
+            .or_else(|| claim("name"))
+            .or_else(|| claim("preferred_username"))
+            .or_else(|| email.clone())
+            .unwrap_or_default();
+        let email_verified = idinfo
+            .email_verified
+            .or_else(|| userinfo.get("email_verified").and_then(|v| v.as_bool()))
+            .unwrap_or(false);
+
+        Ok(OAuth2Account {
+            id: String::new(),      // Will be set during storage
+            user_id: String::new(), // Will be set during upsert process
+            name,
+            email: email.unwrap_or_default(),
+            picture: idinfo.picture.clone().or_else(|| claim("picture")),
+            provider: self.name().to_string(),
+            provider_user_id: format!("{}_{}", self.name(), idinfo.sub),
+            metadata: json!({
+                "family_name": idinfo.family_name.clone().or_else(|| claim("family_name")),
+                "given_name": idinfo.given_name.clone().or_else(|| claim("given_name")),
+                "verified_email": email_verified,
+                "tid": idinfo.tid,
+            }),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })
+    }
+}
@@ -0,0 +1,120 @@
+use async_trait::async_trait;
+use serde_json::Value;
+
+use crate::oauth2::errors::OAuth2Error;
+use crate::oauth2::main::{IdInfo, get_client};
+use crate::oauth2::types::OAuth2Account;
+
+use super::client_auth::client_auth_request;
+use super::discovery::fetch_provider_metadata;
+use super::types::{ProviderConfig, ProviderMetadata};
+
+/// An OAuth2/OIDC identity provider
+///
+/// Endpoints and credentials come from `config()`, while the implementor decides
+/// how the provider's claims are turned into an `OAuth2Account`.
+#[async_trait]
+pub(crate) trait OAuth2Provider: Send + Sync + 'static {
+    /// Credentials, scopes and endpoint configuration of the provider
+    fn config(&self) -> &ProviderConfig;
+
+    /// Map the verified ID token claims and the userinfo response into an OAuth2Account
+    ///
+    /// `idinfo` is only `None` for providers that don't issue ID tokens.
+    fn to_oauth2_account(
+        &self,
+        idinfo: Option<&IdInfo>,
+        userinfo: &Value,
+    ) -> Result<OAuth2Account, OAuth2Error>;
+
+    /// Name of the provider, used in routes, state and `OAuth2Account.provider`
+    fn name(&self) -> &str {
+        &self.config().name
+    }
+
+    /// Client secret sent to the token endpoint
+    fn client_secret(&self) -> Result<String, OAuth2Error> {
+        Ok(self.config().client_secret.clone())
+    }
+
+    /// Whether the token response carries an ID token, i.e. the provider speaks OpenID Connect
+    fn issues_id_token(&self) -> bool {
+        true
+    }
+
+    /// Subject identifier in the userinfo response, compared against `sub` of the ID token
+    fn userinfo_subject(&self, userinfo: &Value) -> Option<String> {
+        userinfo
+            .get("sub")
+            .and_then(|v| v.as_str())
+            .map(String::from)
+    }
+
+    /// Endpoints and issuer of the provider, resolved through OIDC Discovery if configured
+    async fn metadata(&self) -> Result<ProviderMetadata, OAuth2Error> {
+        let config = self.config();
+        let discovered = match &config.issuer_url {
+            Some(issuer_url) => Some(fetch_provider_metadata(issuer_url).await?),
+            None => None,
+        };
+        config.endpoints.resolve(discovered)
+    }
+
+    /// Fetch the userinfo of the authenticated user with the access token
+    async fn fetch_userinfo(
+        &self,
+        userinfo_url: &str,
+        access_token: &str,
+    ) -> Result<Value, OAuth2Error> {
+        let response = get_client()
+            .get(userinfo_url)
+            .bearer_auth(access_token)
+            .send()
+            .await
+            .map_err(|e| OAuth2Error::FetchUserInfo(e.to_string()))?;
+
+        let response_body = response
+            .text()
+            .await
+            .map_err(|e| OAuth2Error::FetchUserInfo(e.to_string()))?;
+
+        tracing::debug!("Response Body: {:#?}", response_body);
+        serde_json::from_str(&response_body)
+            .map_err(|e| OAuth2Error::Serde(format!("Failed to deserialize response body: {}", e)))
+    }
+
+    /// Revoke the grant at the revocation endpoint (RFC 7009)
+    ///
+    /// The refresh token is revoked if there is one, which also ends the grant of its
+    /// access tokens, otherwise the access token. Without an endpoint nothing is done.
+    async fn revoke_tokens(
+        &self,
+        metadata: &ProviderMetadata,
+        access_token: &str,
+        refresh_token: Option<&str>,
+    ) -> Result<(), OAuth2Error> {
+        let Some(revocation_endpoint) = &metadata.revocation_endpoint else {
+            tracing::debug!("No revocation endpoint for {}", self.name());
+            return Ok(());
+        };
+        let (token, token_type_hint) = match refresh_token {
+            Some(refresh_token) => (refresh_token, "refresh_token"),
+            None => (access_token, "access_token"),
+        };
+
+        client_auth_request(
+            self,
+            metadata,
+            revocation_endpoint,
+            vec![
+                ("token", token.to_string()),
+                ("token_type_hint", token_type_hint.to_string()),
+            ],
+        )?
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| OAuth2Error::TokenRevocation(e.to_string()))?;
+        Ok(())
+    }
+}
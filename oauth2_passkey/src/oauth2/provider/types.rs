@@ -0,0 +1,159 @@
+use jsonwebtoken::{Algorithm, EncodingKey};
+use serde::Deserialize;
+use std::sync::Mutex;
+
+use crate::oauth2::types::Prompt;
+
+/// Client credentials and endpoint configuration of an OAuth2/OIDC provider
+#[derive(Debug, Clone)]
+pub(crate) struct ProviderConfig {
+    pub(crate) name: String,
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) scope: String,
+    /// Scopes an authorization request may ask for in addition to `scope`
+    pub(crate) extra_scopes: Vec<String>,
+    /// Additional query parameters appended to the authorization URL, e.g. "&access_type=offline"
+    pub(crate) extra_params: String,
+    /// `prompt` sent unless the authorization request sets its own
+    pub(crate) prompt: Option<Prompt>,
+    /// Request a refresh token and keep the tokens for calling the provider's APIs later
+    pub(crate) offline_access: bool,
+    /// Issuer URL to fetch `/.well-known/openid-configuration` from, if OIDC Discovery is used
+    pub(crate) issuer_url: Option<String>,
+    /// Endpoints set explicitly, taking precedence over the discovered ones
+    pub(crate) endpoints: ProviderEndpoints,
+    /// Tenant IDs (`tid` claim) accepted from a multi-tenant provider, any tenant if empty
+    pub(crate) allowed_tenants: Vec<String>,
+    /// Google Workspace domains (`hd` claim) or verified email domains allowed to sign in,
+    /// any if empty
+    pub(crate) allowed_domains: Vec<String>,
+    /// Linking of new accounts to the user with the same verified email address
+    pub(crate) auto_link: AutoLinkPolicy,
+    /// Claims mapped into the account, over the provider's own mapping
+    pub(crate) claim_map: Vec<ClaimMapping>,
+    /// Client IDs whose ID tokens are accepted when posted by the client itself,
+    /// e.g. the web client ID for One Tap and the native apps' client IDs
+    pub(crate) id_token_audiences: Vec<String>,
+    /// How the client authenticates at the token and revocation endpoints
+    pub(crate) token_auth_method: TokenAuthMethod,
+    /// Algorithms accepted for ID token signatures, narrowed down by the discovered ones
+    pub(crate) id_token_signing_algs: Vec<Algorithm>,
+    /// Maximum age in seconds of the authentication at the IdP, sent as `max_age`
+    pub(crate) max_age: Option<u64>,
+    /// Tolerated clock difference in seconds when checking the ID token's timestamps
+    pub(crate) clock_skew: u64,
+    /// End the session at the provider too when the user logs out (RP-initiated logout)
+    pub(crate) rp_initiated_logout: bool,
+    /// Push the authorization parameters to the provider first (PAR, RFC 9126),
+    /// also done when the provider's metadata requires it
+    pub(crate) pushed_auth_requests: bool,
+}
+
+/// Linking a new account to an existing user by a matching verified email address
+///
+/// Providers with a policy other than `Off` are trusted to verify email addresses, so
+/// only their accounts are matched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AutoLinkPolicy {
+    Off,
+    /// Link on sign-in
+    Auto,
+    /// Link once the user has signed in to the existing user and confirmed
+    Confirm,
+}
+
+/// Maps an ID token or userinfo claim into a field of the OAuth2Account
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ClaimMapping {
+    pub(crate) target: ClaimTarget,
+    /// Claim name, or a dotted path into nested claims like "address.country"
+    pub(crate) claim: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ClaimTarget {
+    Name,
+    Email,
+    Picture,
+    /// A key of the account's metadata
+    Metadata(String),
+}
+
+/// Client authentication methods at the token endpoint (OIDC Core 1.0, 9)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TokenAuthMethod {
+    /// client_id and client_secret in the request body
+    ClientSecretPost,
+    /// client_id and client_secret with HTTP Basic authentication
+    ClientSecretBasic,
+    /// JWT assertion signed with the client secret (HS256)
+    ClientSecretJwt,
+    /// JWT assertion signed with the client signing key
+    PrivateKeyJwt,
+}
+
+/// Explicitly configured endpoints of a provider
+///
+/// Without OIDC Discovery an OIDC provider needs all of them except `userinfo_url`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProviderEndpoints {
+    pub(crate) auth_url: Option<String>,
+    pub(crate) token_url: Option<String>,
+    pub(crate) userinfo_url: Option<String>,
+    pub(crate) jwks_url: Option<String>,
+    pub(crate) issuer: Option<String>,
+    pub(crate) revocation_url: Option<String>,
+    pub(crate) end_session_url: Option<String>,
+    pub(crate) par_url: Option<String>,
+}
+
+/// Resolved provider metadata, as defined by OpenID Connect Discovery 1.0
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ProviderMetadata {
+    pub(crate) issuer: String,
+    pub(crate) authorization_endpoint: String,
+    pub(crate) token_endpoint: String,
+    pub(crate) userinfo_endpoint: Option<String>,
+    /// Not set for plain OAuth2 providers, which issue no ID token
+    pub(crate) jwks_uri: Option<String>,
+    /// Token revocation endpoint (RFC 7009)
+    pub(crate) revocation_endpoint: Option<String>,
+    /// Endpoint for RP-initiated logout (OIDC RP-Initiated Logout 1.0)
+    pub(crate) end_session_endpoint: Option<String>,
+    /// Endpoint for pushed authorization requests (RFC 9126)
+    pub(crate) pushed_authorization_request_endpoint: Option<String>,
+    #[serde(default)]
+    pub(crate) require_pushed_authorization_requests: bool,
+    /// Empty when not advertised, in which case the algorithm is not restricted
+    #[serde(default)]
+    pub(crate) id_token_signing_alg_values_supported: Vec<String>,
+}
+
+/// Google, with Google's endpoints as defaults
+pub(crate) struct GoogleProvider {
+    pub(super) config: ProviderConfig,
+}
+
+/// Any OpenID Connect provider configured through environment variables
+pub(crate) struct OidcProvider {
+    pub(super) config: ProviderConfig,
+}
+
+/// GitHub, a plain OAuth2 provider identifying users by its REST API
+pub(crate) struct GitHubProvider {
+    pub(super) config: ProviderConfig,
+    pub(super) emails_url: String,
+    /// Endpoint deleting the app authorization of the user, GitHub's way of revocation
+    pub(super) grant_url: String,
+}
+
+/// Sign in with Apple, authenticating with a client secret JWT signed by the team's key
+pub(crate) struct AppleProvider {
+    pub(super) config: ProviderConfig,
+    pub(super) team_id: String,
+    pub(super) key_id: String,
+    pub(super) private_key: EncodingKey,
+    /// The current client secret and its expiration as a UNIX timestamp
+    pub(super) client_secret: Mutex<Option<(String, i64)>>,
+}
@@ -7,3 +7,9 @@ pub(super) static DB_TABLE_OAUTH2_ACCOUNTS: LazyLock<String> = LazyLock::new(||
     env::var("DB_TABLE_OAUTH2_ACCOUNTS")
         .unwrap_or_else(|_| format!("{}{}", *DB_TABLE_PREFIX, "oauth2_accounts"))
 });
+
+/// OAuth2 tokens table name
+pub(super) static DB_TABLE_OAUTH2_TOKENS: LazyLock<String> = LazyLock::new(|| {
+    env::var("DB_TABLE_OAUTH2_TOKENS")
+        .unwrap_or_else(|_| format!("{}{}", *DB_TABLE_PREFIX, "oauth2_tokens"))
+});
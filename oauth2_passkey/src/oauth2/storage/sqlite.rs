@@ -1,13 +1,13 @@
 use crate::oauth2::{
     errors::OAuth2Error,
-    types::{AccountSearchField, OAuth2Account},
+    types::{AccountSearchField, OAuth2Account, StoredOAuth2Tokens},
 };
 use crate::storage::validate_sqlite_table_schema;
 use crate::userdb::DB_TABLE_USERS;
 use chrono::Utc;
 use sqlx::{Pool, Sqlite};
 
-use super::config::DB_TABLE_OAUTH2_ACCOUNTS;
+use super::config::{DB_TABLE_OAUTH2_ACCOUNTS, DB_TABLE_OAUTH2_TOKENS};
 
 // SQLite implementations
 pub(super) async fn create_tables_sqlite(pool: &Pool<Sqlite>) -> Result<(), OAuth2Error> {
@@ -49,6 +49,25 @@ pub(super) async fn create_tables_sqlite(pool: &Pool<Sqlite>) -> Result<(), OAut
     .await
     .map_err(|e| OAuth2Error::Storage(e.to_string()))?;
 
+    // Create oauth2_tokens table, one row per account
+    sqlx::query(&format!(
+        r#"
+        CREATE TABLE IF NOT EXISTS {} (
+            account_id TEXT PRIMARY KEY NOT NULL REFERENCES {}(id),
+            access_token TEXT NOT NULL,
+            refresh_token TEXT,
+            expires_at TIMESTAMP,
+            scope TEXT,
+            updated_at TIMESTAMP NOT NULL
+        )
+        "#,
+        DB_TABLE_OAUTH2_TOKENS.as_str(),
+        oauth2_table
+    ))
+    .execute(pool)
+    .await
+    .map_err(|e| OAuth2Error::Storage(e.to_string()))?;
+
     Ok(())
 }
 
@@ -70,7 +89,25 @@ pub(super) async fn validate_oauth2_tables_sqlite(pool: &Pool<Sqlite>) -> Result
         ("updated_at", "TIMESTAMP"),
     ];
 
-    validate_sqlite_table_schema(pool, oauth2_table, &expected_columns, OAuth2Error::Storage).await
+    validate_sqlite_table_schema(pool, oauth2_table, &expected_columns, OAuth2Error::Storage)
+        .await?;
+
+    let expected_token_columns = [
+        ("account_id", "TEXT"),
+        ("access_token", "TEXT"),
+        ("refresh_token", "TEXT"),
+        ("expires_at", "TIMESTAMP"),
+        ("scope", "TEXT"),
+        ("updated_at", "TIMESTAMP"),
+    ];
+
+    validate_sqlite_table_schema(
+        pool,
+        DB_TABLE_OAUTH2_TOKENS.as_str(),
+        &expected_token_columns,
+        OAuth2Error::Storage,
+    )
+    .await
 }
 
 pub(super) async fn get_oauth2_accounts_by_field_sqlite(
@@ -277,3 +314,69 @@ pub(super) async fn delete_oauth2_accounts_by_field_sqlite(
 
     Ok(())
 }
+
+pub(super) async fn get_oauth2_tokens_sqlite(
+    pool: &Pool<Sqlite>,
+    account_id: &str,
+) -> Result<Option<StoredOAuth2Tokens>, OAuth2Error> {
+    let table_name = DB_TABLE_OAUTH2_TOKENS.as_str();
+
+    sqlx::query_as::<_, StoredOAuth2Tokens>(&format!(
+        "SELECT * FROM {} WHERE account_id = ?",
+        table_name
+    ))
+    .bind(account_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| OAuth2Error::Storage(e.to_string()))
+}
+
+/// Insert or replace the tokens of an account, keeping the stored refresh token
+/// when the new response carries none
+pub(super) async fn upsert_oauth2_tokens_sqlite(
+    pool: &Pool<Sqlite>,
+    tokens: StoredOAuth2Tokens,
+) -> Result<(), OAuth2Error> {
+    let table_name = DB_TABLE_OAUTH2_TOKENS.as_str();
+
+    sqlx::query(&format!(
+        r#"
+        INSERT INTO {0}
+        (account_id, access_token, refresh_token, expires_at, scope, updated_at)
+        VALUES (?, ?, ?, ?, ?, ?)
+        ON CONFLICT (account_id) DO UPDATE SET
+            access_token = excluded.access_token,
+            refresh_token = COALESCE(excluded.refresh_token, {0}.refresh_token),
+            expires_at = excluded.expires_at,
+            scope = COALESCE(excluded.scope, {0}.scope),
+            updated_at = excluded.updated_at
+        "#,
+        table_name
+    ))
+    .bind(&tokens.account_id)
+    .bind(&tokens.access_token)
+    .bind(&tokens.refresh_token)
+    .bind(tokens.expires_at)
+    .bind(&tokens.scope)
+    .bind(tokens.updated_at)
+    .execute(pool)
+    .await
+    .map_err(|e| OAuth2Error::Storage(e.to_string()))?;
+
+    Ok(())
+}
+
+pub(super) async fn delete_oauth2_tokens_sqlite(
+    pool: &Pool<Sqlite>,
+    account_id: &str,
+) -> Result<(), OAuth2Error> {
+    let table_name = DB_TABLE_OAUTH2_TOKENS.as_str();
+
+    sqlx::query(&format!("DELETE FROM {} WHERE account_id = ?", table_name))
+        .bind(account_id)
+        .execute(pool)
+        .await
+        .map_err(|e| OAuth2Error::Storage(e.to_string()))?;
+
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use crate::oauth2::errors::OAuth2Error;
-use crate::oauth2::types::{AccountSearchField, OAuth2Account};
+use crate::oauth2::types::{AccountSearchField, OAuth2Account, StoredOAuth2Tokens};
 use crate::storage::GENERIC_DATA_STORE;
 
 use super::postgres::*;
@@ -145,6 +145,11 @@ impl OAuth2Store {
     }
 
     pub async fn delete_oauth2_accounts_by(field: AccountSearchField) -> Result<(), OAuth2Error> {
+        // Tokens reference the accounts, so they go first
+        for account in Self::get_oauth2_accounts_by(field.clone()).await? {
+            Self::delete_oauth2_tokens(&account.id).await?;
+        }
+
         let store = GENERIC_DATA_STORE.lock().await;
 
         if let Some(pool) = store.as_sqlite() {
@@ -157,4 +162,53 @@ impl OAuth2Store {
             ))
         }
     }
+
+    /// Get the stored tokens of an OAuth2 account
+    pub(crate) async fn get_oauth2_tokens(
+        account_id: &str,
+    ) -> Result<Option<StoredOAuth2Tokens>, OAuth2Error> {
+        let store = GENERIC_DATA_STORE.lock().await;
+
+        if let Some(pool) = store.as_sqlite() {
+            get_oauth2_tokens_sqlite(pool, account_id).await
+        } else if let Some(pool) = store.as_postgres() {
+            get_oauth2_tokens_postgres(pool, account_id).await
+        } else {
+            Err(OAuth2Error::Storage(
+                "Unsupported database type".to_string(),
+            ))
+        }
+    }
+
+    /// Create or update the tokens of an OAuth2 account
+    pub(crate) async fn upsert_oauth2_tokens(
+        tokens: StoredOAuth2Tokens,
+    ) -> Result<(), OAuth2Error> {
+        let store = GENERIC_DATA_STORE.lock().await;
+
+        if let Some(pool) = store.as_sqlite() {
+            upsert_oauth2_tokens_sqlite(pool, tokens).await
+        } else if let Some(pool) = store.as_postgres() {
+            upsert_oauth2_tokens_postgres(pool, tokens).await
+        } else {
+            Err(OAuth2Error::Storage(
+                "Unsupported database type".to_string(),
+            ))
+        }
+    }
+
+    /// Delete the tokens of an OAuth2 account
+    pub(crate) async fn delete_oauth2_tokens(account_id: &str) -> Result<(), OAuth2Error> {
+        let store = GENERIC_DATA_STORE.lock().await;
+
+        if let Some(pool) = store.as_sqlite() {
+            delete_oauth2_tokens_sqlite(pool, account_id).await
+        } else if let Some(pool) = store.as_postgres() {
+            delete_oauth2_tokens_postgres(pool, account_id).await
+        } else {
+            Err(OAuth2Error::Storage(
+                "Unsupported database type".to_string(),
+            ))
+        }
+    }
 }
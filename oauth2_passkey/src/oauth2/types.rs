@@ -1,10 +1,10 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use serde_json::{Value, json};
+use serde_json::{Map, Value};
 use sqlx::FromRow;
+use std::str::FromStr;
 
 use super::errors::OAuth2Error;
-use super::main::IdInfo as GoogleIdInfo;
 
 use crate::storage::CacheData;
 
@@ -40,70 +40,228 @@ impl Default for OAuth2Account {
     }
 }
 
-// The user data we'll get back from Google
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct GoogleUserInfo {
-    pub(crate) id: String,
-    pub(crate) family_name: String,
-    pub name: String,
-    pub picture: Option<String>,
-    pub(crate) email: String,
-    pub(crate) given_name: String,
-    pub(crate) hd: Option<String>,
-    pub(crate) verified_email: bool,
-}
+impl OAuth2Account {
+    /// Whether the provider verified the email address, recorded as `verified_email` metadata
+    pub(crate) fn email_verified(&self) -> bool {
+        self.metadata
+            .get("verified_email")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false)
+    }
 
-// Add these implementations
-impl From<GoogleUserInfo> for OAuth2Account {
-    fn from(google_user: GoogleUserInfo) -> Self {
-        Self {
-            id: String::new(),      // Will be set during storage
-            user_id: String::new(), // Will be set during upsert process
-            name: google_user.name,
-            email: google_user.email,
-            picture: google_user.picture,
-            provider: "google".to_string(),
-            provider_user_id: format!("google_{}", google_user.id),
-            metadata: json!({
-                "family_name": google_user.family_name,
-                "given_name": google_user.given_name,
-                "hd": google_user.hd,
-                "verified_email": google_user.verified_email,
-            }),
-            created_at: Utc::now(),
-            updated_at: Utc::now(),
-        }
+    /// Scopes granted to the client, recorded as `granted_scopes` metadata at each sign-in
+    ///
+    /// Lets features check whether an additional scope has to be requested before
+    /// calling the provider's APIs.
+    pub fn granted_scopes(&self) -> Vec<String> {
+        self.metadata
+            .get("granted_scopes")
+            .and_then(|v| v.as_array())
+            .map(|scopes| {
+                scopes
+                    .iter()
+                    .filter_map(|scope| scope.as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default()
     }
-}
 
-impl From<GoogleIdInfo> for OAuth2Account {
-    fn from(idinfo: GoogleIdInfo) -> Self {
-        Self {
-            id: String::new(),      // Will be set during storage
-            user_id: String::new(), // Will be set during upsert process
-            name: idinfo.name,
-            email: idinfo.email,
-            picture: idinfo.picture,
-            provider: "google".to_string(),
-            provider_user_id: format!("google_{}", idinfo.sub),
-            metadata: json!({
-                "family_name": idinfo.family_name,
-                "given_name": idinfo.given_name,
-                "hd": idinfo.hd,
-                "verified_email": idinfo.email_verified,
-            }),
-            created_at: Utc::now(),
-            updated_at: Utc::now(),
+    /// Update the profile with the one just mapped from the provider's claims
+    ///
+    /// Empty values never overwrite stored ones, and granted scopes are added to the
+    /// recorded ones. Returns the changed fields as `{field: {from, to}}`.
+    pub(crate) fn merge_profile(&mut self, fresh: &OAuth2Account) -> Map<String, Value> {
+        let mut changes = Map::new();
+        let mut record = |field: &str, from: Value, to: Value| {
+            changes.insert(
+                field.to_string(),
+                serde_json::json!({ "from": from, "to": to }),
+            );
+        };
+
+        if !fresh.name.is_empty() && fresh.name != self.name {
+            record("name", self.name.clone().into(), fresh.name.clone().into());
+            self.name = fresh.name.clone();
+        }
+        if !fresh.email.is_empty() && fresh.email != self.email {
+            record(
+                "email",
+                self.email.clone().into(),
+                fresh.email.clone().into(),
+            );
+            self.email = fresh.email.clone();
         }
+        if let Some(picture) = fresh.picture.as_ref().filter(|p| !p.is_empty())
+            && self.picture.as_ref() != Some(picture)
+        {
+            record(
+                "picture",
+                self.picture.clone().into(),
+                picture.clone().into(),
+            );
+            self.picture = Some(picture.clone());
+        }
+
+        let mut scopes = self.granted_scopes();
+        let scope_count = scopes.len();
+        for scope in fresh.granted_scopes() {
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+
+        if !self.metadata.is_object() {
+            self.metadata = Value::Object(Map::new());
+        }
+        if let (Some(metadata), Some(fresh_metadata)) =
+            (self.metadata.as_object_mut(), fresh.metadata.as_object())
+        {
+            for (key, value) in fresh_metadata {
+                if value.is_null() || key == "granted_scopes" || metadata.get(key) == Some(value) {
+                    continue;
+                }
+                let from = metadata.insert(key.clone(), value.clone());
+                record(
+                    &format!("metadata.{}", key),
+                    from.unwrap_or(Value::Null),
+                    value.clone(),
+                );
+            }
+            if scopes.len() != scope_count {
+                let from = metadata.insert("granted_scopes".to_string(), serde_json::json!(scopes));
+                record(
+                    "metadata.granted_scopes",
+                    from.unwrap_or(Value::Null),
+                    serde_json::json!(scopes),
+                );
+            }
+        }
+        changes
     }
 }
 
+/// Tokens issued for an OAuth2 account, with the tokens encrypted at rest
+#[derive(Debug, Clone, FromRow)]
+pub(crate) struct StoredOAuth2Tokens {
+    pub(crate) account_id: String,
+    pub(crate) access_token: String,
+    pub(crate) refresh_token: Option<String>,
+    pub(crate) expires_at: Option<DateTime<Utc>>,
+    pub(crate) scope: Option<String>,
+    pub(crate) updated_at: DateTime<Utc>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StateParams {
+    pub(crate) provider: String,
     pub(crate) csrf_token: String,
+    /// Cache ID of the CSRF token, for removing it when the provider returns an error
+    #[serde(default)]
+    pub(crate) csrf_id: String,
     pub(crate) nonce_id: String,
     pub(crate) pkce_id: String,
     pub(crate) misc_id: Option<String>,
+    #[serde(default)]
+    pub(crate) callback_mode: CallbackMode,
+    /// Space separated scopes requested, granted unless the token response says otherwise
+    #[serde(default)]
+    pub(crate) scope: String,
+    /// Validated URL to send the user to after login
+    #[serde(default)]
+    pub(crate) return_to: Option<String>,
+    /// Unix time after which the state is no longer accepted
+    pub(crate) expires_at: i64,
+}
+
+/// How the callback hands the result of the authorization back to the client
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CallbackMode {
+    /// Close the popup window the flow was started in
+    #[default]
+    Popup,
+    /// Full-page redirect back to the application's pages
+    Redirect,
+    /// Redirect to the configured app URL with the result, for SPAs and mobile apps
+    App,
+}
+
+impl FromStr for CallbackMode {
+    type Err = OAuth2Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "popup" => Ok(Self::Popup),
+            "redirect" => Ok(Self::Redirect),
+            "app" => Ok(Self::App),
+            _ => Err(OAuth2Error::InvalidRequest(format!(
+                "Unsupported callback mode: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Values of the `prompt` parameter of an authorization request (OIDC Core 1.0, 3.1.2.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prompt {
+    /// Fail instead of showing any page, e.g. to check for an existing session at the IdP
+    None,
+    Login,
+    Consent,
+    SelectAccount,
+}
+
+impl Prompt {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Login => "login",
+            Self::Consent => "consent",
+            Self::SelectAccount => "select_account",
+        }
+    }
+}
+
+impl FromStr for Prompt {
+    type Err = OAuth2Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "login" => Ok(Self::Login),
+            "consent" => Ok(Self::Consent),
+            "select_account" => Ok(Self::SelectAccount),
+            _ => Err(OAuth2Error::InvalidRequest(format!(
+                "Unsupported prompt: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Options of an authorization request
+#[derive(Debug, Clone, Default)]
+pub struct AuthRequestOptions {
+    pub callback_mode: CallbackMode,
+    /// Overrides the prompt configured for the provider
+    pub prompt: Option<Prompt>,
+    /// Email address or subject of the user, to preselect the account at the IdP
+    pub login_hint: Option<String>,
+    /// Scopes requested in addition to the provider's configured scope, from its
+    /// `OAUTH2_{NAME}_EXTRA_SCOPES`
+    pub scopes: Vec<String>,
+    /// Keep the scopes granted before, for incremental authorization at Google
+    pub include_granted_scopes: bool,
+    /// Where to send the user after login, a path or a URL of an allowed origin
+    pub return_to: Option<String>,
+}
+
+/// An authorization request to send the user agent to
+#[derive(Serialize, Debug, Clone)]
+pub struct OAuth2AuthRequest {
+    pub auth_url: String,
+    pub state: String,
 }
 
 #[derive(Serialize, Clone, Deserialize, Debug)]
@@ -116,18 +274,24 @@ pub(crate) struct StoredToken {
 
 #[derive(Debug, Deserialize)]
 pub struct AuthResponse {
-    pub(crate) code: String,
+    /// Not set when the provider responds with an error
+    pub(crate) code: Option<String>,
     pub state: String,
+    /// Error code of an error response (RFC 6749, 4.1.2.1)
+    pub(crate) error: Option<String>,
+    pub(crate) error_description: Option<String>,
     _id_token: Option<String>,
+    /// User's name as JSON, posted by Sign in with Apple on the first authorization only
+    pub(crate) user: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct OidcTokenResponse {
     pub(crate) access_token: String,
     token_type: String,
-    expires_in: u64,
-    refresh_token: Option<String>,
-    scope: String,
+    pub(crate) expires_in: Option<u64>,
+    pub(crate) refresh_token: Option<String>,
+    pub(crate) scope: Option<String>,
     pub(crate) id_token: Option<String>,
 }
 
@@ -149,7 +313,7 @@ impl TryFrom<CacheData> for StoredToken {
 
 /// Search field options for credential lookup
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum AccountSearchField {
     /// Search by ID
     Id(String),
@@ -164,3 +328,88 @@ pub enum AccountSearchField {
     /// Search by email
     Email(String),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oauth2::main::IdInfo;
+    use crate::oauth2::provider::get_provider;
+    use crate::test_utils::init_test_env;
+
+    #[test]
+    fn test_merge_profile() {
+        let mut stored = OAuth2Account {
+            name: "John".to_string(),
+            email: "john@example.com".to_string(),
+            picture: Some("https://example.com/john.png".to_string()),
+            metadata: serde_json::json!({
+                "verified_email": true,
+                "granted_scopes": ["openid", "email"],
+            }),
+            ..Default::default()
+        };
+        let fresh = OAuth2Account {
+            name: "John Doe".to_string(),
+            email: String::new(),
+            picture: None,
+            metadata: serde_json::json!({
+                "verified_email": true,
+                "family_name": null,
+                "granted_scopes": ["openid", "drive"],
+            }),
+            ..Default::default()
+        };
+
+        let changes = stored.merge_profile(&fresh);
+
+        assert_eq!(stored.name, "John Doe");
+        assert_eq!(stored.email, "john@example.com");
+        assert_eq!(
+            stored.picture.as_deref(),
+            Some("https://example.com/john.png")
+        );
+        assert_eq!(stored.granted_scopes(), vec!["openid", "email", "drive"]);
+        assert_eq!(stored.metadata.get("family_name"), None);
+        let mut fields: Vec<&String> = changes.keys().collect();
+        fields.sort();
+        assert_eq!(fields, vec!["metadata.granted_scopes", "name"]);
+        assert_eq!(
+            changes["name"],
+            serde_json::json!({ "from": "John", "to": "John Doe" })
+        );
+
+        assert!(stored.clone().merge_profile(&stored).is_empty());
+    }
+
+    #[test]
+    fn test_merge_profile_apple_without_name() {
+        init_test_env();
+        let apple = get_provider("apple").unwrap();
+        let idinfo: IdInfo = serde_json::from_value(serde_json::json!({
+            "iss": "https://appleid.apple.com",
+            "sub": "001234.abcd",
+            "aud": "com.example.app",
+            "email": "jane@example.com",
+            "email_verified": "true",
+            "iat": 0,
+            "exp": 0,
+        }))
+        .unwrap();
+        let user = serde_json::json!({
+            "user": { "name": { "firstName": "Jane", "lastName": "Appleseed" } },
+        });
+
+        // The first authorization posts the name
+        let mut stored = apple.to_oauth2_account(Some(&idinfo), &user).unwrap();
+        assert_eq!(stored.name, "Jane Appleseed");
+
+        // Later ones don't, which leaves the stored name alone
+        let fresh = apple
+            .to_oauth2_account(Some(&idinfo), &serde_json::json!({}))
+            .unwrap();
+        assert_eq!(fresh.name, "");
+        let changes = stored.merge_profile(&fresh);
+        assert_eq!(stored.name, "Jane Appleseed");
+        assert!(changes.is_empty());
+    }
+}
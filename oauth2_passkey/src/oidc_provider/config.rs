@@ -0,0 +1,107 @@
+use std::{collections::HashMap, env, sync::LazyLock};
+
+use crate::config::O2P_ROUTE_PREFIX;
+use crate::oauth2::{SigningKey, signing_key_from_env};
+
+use super::types::{OidcClient, SubjectType};
+
+/// Lifetime in seconds of the authorization codes
+pub(super) const OIDC_CODE_TTL: u64 = 60;
+
+/// Lifetime in seconds of a consent page waiting for the user's decision
+pub(super) const OIDC_CONSENT_TTL: u64 = 600;
+
+/// Scopes the provider knows about, others are dropped from the requests
+pub(super) const OIDC_SCOPES_SUPPORTED: &[&str] = &["openid", "profile", "email"];
+
+/// Issuer of the ID tokens, the provider endpoints live under it
+pub(super) static OIDC_ISSUER: LazyLock<String> = LazyLock::new(|| {
+    format!(
+        "{}{}/op",
+        env::var("ORIGIN").expect("Missing ORIGIN!"),
+        O2P_ROUTE_PREFIX.as_str()
+    )
+});
+
+/// Lifetime in seconds of the ID tokens and access tokens
+pub(super) static OIDC_TOKEN_TTL: LazyLock<u64> = LazyLock::new(|| {
+    env::var("O2P_OIDC_TOKEN_TTL")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(3600)
+});
+
+/// Key signing the ID tokens, from O2P_OIDC_SIGNING_KEY (PEM) or O2P_OIDC_SIGNING_KEY_PATH
+pub(super) static OIDC_SIGNING_KEY: LazyLock<Option<SigningKey>> =
+    LazyLock::new(|| signing_key_from_env("O2P_OIDC_SIGNING_KEY"));
+
+/// Clients registered with O2P_OIDC_CLIENTS, by client ID
+pub(super) static OIDC_CLIENTS: LazyLock<HashMap<String, OidcClient>> = LazyLock::new(|| {
+    let names = env::var("O2P_OIDC_CLIENTS").unwrap_or_default();
+    let clients: HashMap<String, OidcClient> = names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let client = load_client(name);
+            (client.client_id.clone(), client)
+        })
+        .collect();
+    if !clients.is_empty() && OIDC_SIGNING_KEY.is_none() {
+        panic!(
+            "O2P_OIDC_SIGNING_KEY or O2P_OIDC_SIGNING_KEY_PATH must be set for the OIDC provider"
+        );
+    }
+    clients
+});
+
+fn client_env(name: &str, key: &str) -> Option<String> {
+    env::var(format!("O2P_OIDC_{}_{}", name.to_uppercase(), key))
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+fn load_client(name: &str) -> OidcClient {
+    let redirect_uris: Vec<String> = client_env(name, "REDIRECT_URIS")
+        .unwrap_or_else(|| panic!("O2P_OIDC_{}_REDIRECT_URIS must be set", name.to_uppercase()))
+        .split(',')
+        .map(|uri| uri.trim().to_string())
+        .filter(|uri| !uri.is_empty())
+        .collect();
+    let hosts: Vec<String> = redirect_uris
+        .iter()
+        .map(|uri| match url::Url::parse(uri) {
+            Ok(url) if url.fragment().is_none() => url.host_str().unwrap_or_default().to_string(),
+            _ => panic!("Invalid redirect URI for OIDC client {}: {}", name, uri),
+        })
+        .collect();
+
+    let subject_type = match client_env(name, "SUBJECT_TYPE").as_deref() {
+        None | Some("pairwise") => SubjectType::Pairwise,
+        Some("public") => SubjectType::Public,
+        Some(other) => panic!(
+            "Unsupported subject type for OIDC client {}: {}",
+            name, other
+        ),
+    };
+    // Redirect URIs on several hosts need an explicit sector (OIDC Core 1.0, 8.1)
+    let sector = client_env(name, "SECTOR").unwrap_or_else(|| {
+        if subject_type == SubjectType::Pairwise && hosts.iter().any(|host| *host != hosts[0]) {
+            panic!(
+                "O2P_OIDC_{}_SECTOR must be set for redirect URIs on several hosts",
+                name.to_uppercase()
+            );
+        }
+        hosts.first().cloned().unwrap_or_default()
+    });
+
+    OidcClient {
+        name: name.to_string(),
+        client_id: client_env(name, "CLIENT_ID").unwrap_or_else(|| name.to_string()),
+        client_secret: client_env(name, "CLIENT_SECRET"),
+        redirect_uris,
+        skip_consent: client_env(name, "SKIP_CONSENT").as_deref() == Some("true"),
+        subject_type,
+        sector,
+    }
+}
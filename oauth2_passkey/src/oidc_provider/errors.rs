@@ -0,0 +1,85 @@
+use crate::userdb::UserError;
+use crate::utils::UtilError;
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone)]
+pub enum OidcProviderError {
+    /// Unknown client, or failed client authentication
+    #[error("Invalid client: {0}")]
+    InvalidClient(String),
+
+    /// The redirect URI isn't registered, the user must not be sent there
+    #[error("Invalid redirect URI: {0}")]
+    InvalidRedirectUri(String),
+
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("Unsupported response type: {0}")]
+    UnsupportedResponseType(String),
+
+    #[error("Invalid scope: {0}")]
+    InvalidScope(String),
+
+    /// The user isn't signed in, but the request had prompt=none
+    #[error("Login required")]
+    LoginRequired,
+
+    /// The user hasn't consented, but the request had prompt=none
+    #[error("Consent required")]
+    ConsentRequired,
+
+    #[error("Access denied: {0}")]
+    AccessDenied(String),
+
+    /// The authorization code is unknown, expired, used or issued to another client
+    #[error("Invalid grant: {0}")]
+    InvalidGrant(String),
+
+    #[error("Unsupported grant type: {0}")]
+    UnsupportedGrantType(String),
+
+    /// The access token is unknown or expired
+    #[error("Invalid token: {0}")]
+    InvalidToken(String),
+
+    #[error("Storage error: {0}")]
+    Storage(String),
+
+    #[error("Serde error: {0}")]
+    Serde(String),
+
+    #[error("Crypto error: {0}")]
+    Crypto(String),
+
+    /// Error from utils operations
+    #[error("Utils error: {0}")]
+    Utils(#[from] UtilError),
+
+    /// Error from user database operations
+    #[error("User error: {0}")]
+    User(#[from] UserError),
+}
+
+impl OidcProviderError {
+    /// Error code of the OAuth 2.0 error response (RFC 6749, 4.1.2.1 and 5.2; RFC 6750, 3.1)
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            Self::InvalidClient(_) => "invalid_client",
+            Self::InvalidRedirectUri(_) | Self::InvalidRequest(_) => "invalid_request",
+            Self::UnsupportedResponseType(_) => "unsupported_response_type",
+            Self::InvalidScope(_) => "invalid_scope",
+            Self::LoginRequired => "login_required",
+            Self::ConsentRequired => "consent_required",
+            Self::AccessDenied(_) => "access_denied",
+            Self::InvalidGrant(_) => "invalid_grant",
+            Self::UnsupportedGrantType(_) => "unsupported_grant_type",
+            Self::InvalidToken(_) => "invalid_token",
+            Self::Storage(_)
+            | Self::Serde(_)
+            | Self::Crypto(_)
+            | Self::Utils(_)
+            | Self::User(_) => "server_error",
+        }
+    }
+}
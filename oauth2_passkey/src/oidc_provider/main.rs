@@ -0,0 +1,1041 @@
+use base64::{
+    Engine as _,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use chrono::Utc;
+use jsonwebtoken::Header;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use url::form_urlencoded;
+
+use crate::config::O2P_ROUTE_PREFIX;
+use crate::session::{User as SessionUser, obfuscate_user_id};
+use crate::storage::{CacheData, GENERIC_CACHE_STORE};
+use crate::userdb::{User as DbUser, UserStore};
+use crate::utils::gen_random_string;
+
+use super::config::{
+    OIDC_CLIENTS, OIDC_CODE_TTL, OIDC_CONSENT_TTL, OIDC_ISSUER, OIDC_SCOPES_SUPPORTED,
+    OIDC_SIGNING_KEY, OIDC_TOKEN_TTL,
+};
+use super::errors::OidcProviderError;
+use super::storage::OidcConsentStore;
+use super::types::{
+    AccessTokenGrant, AuthorizationCode, AuthorizationParams, IdTokenClaims, OidcAuthorizeRequest,
+    OidcAuthorizeResponse, OidcClient, OidcConsentForm, OidcConsentPage, OidcTokenRequest,
+    OidcTokenResponse, PendingConsent, StoredConsent, SubjectType,
+};
+
+const CODE_PREFIX: &str = "op_code";
+const ACCESS_TOKEN_PREFIX: &str = "op_access_token";
+const CONSENT_PREFIX: &str = "op_consent";
+
+/// Whether any client is registered, the provider endpoints are served only then
+pub fn is_oidc_provider_enabled() -> bool {
+    !OIDC_CLIENTS.is_empty()
+}
+
+/// Discovery document of the provider (OpenID Connect Discovery 1.0, 3)
+pub fn get_oidc_provider_metadata() -> Value {
+    let issuer = OIDC_ISSUER.as_str();
+    let algs: Vec<String> = OIDC_SIGNING_KEY
+        .iter()
+        .map(|key| format!("{:?}", key.alg))
+        .collect();
+    json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "userinfo_endpoint": format!("{}/userinfo", issuer),
+        "jwks_uri": format!("{}/jwks.json", issuer),
+        "response_types_supported": ["code"],
+        "response_modes_supported": ["query"],
+        "grant_types_supported": ["authorization_code"],
+        "subject_types_supported": ["pairwise", "public"],
+        "id_token_signing_alg_values_supported": algs,
+        "scopes_supported": OIDC_SCOPES_SUPPORTED,
+        "claims_supported": [
+            "iss", "sub", "aud", "exp", "iat", "auth_time", "nonce",
+            "name", "preferred_username", "email",
+        ],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+        "code_challenge_methods_supported": ["S256"],
+        "prompt_values_supported": ["none", "login", "consent"],
+        "authorization_response_iss_parameter_supported": true,
+    })
+}
+
+/// Public keys of the provider as a JWK Set, for clients verifying the ID tokens
+pub fn get_oidc_provider_jwks() -> Value {
+    let keys: Vec<&Value> = OIDC_SIGNING_KEY.iter().map(|key| &key.jwk).collect();
+    json!({ "keys": keys })
+}
+
+/// Handle an authentication request of a registered client for the user of the session
+///
+/// Once the client and the redirect URI are known to be valid, errors are sent back to
+/// the client. Before that they are returned, and must be shown to the user instead.
+pub async fn oidc_authorize_core(
+    request: &OidcAuthorizeRequest,
+    user: Option<&SessionUser>,
+) -> Result<OidcAuthorizeResponse, OidcProviderError> {
+    let client = get_client(request.client_id.as_deref())?;
+    let redirect_uri = request
+        .redirect_uri
+        .as_deref()
+        .filter(|uri| {
+            client
+                .redirect_uris
+                .iter()
+                .any(|registered| registered == uri)
+        })
+        .ok_or_else(|| {
+            OidcProviderError::InvalidRedirectUri(format!(
+                "Not registered for client {}",
+                client.client_id
+            ))
+        })?;
+
+    match authorize(client, redirect_uri, request, user).await {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            tracing::info!("OIDC authentication request refused: {}", e);
+            Ok(OidcAuthorizeResponse::Redirect(error_redirect(
+                redirect_uri,
+                request.state.as_deref(),
+                &e,
+            )))
+        }
+    }
+}
+
+async fn authorize(
+    client: &OidcClient,
+    redirect_uri: &str,
+    request: &OidcAuthorizeRequest,
+    user: Option<&SessionUser>,
+) -> Result<OidcAuthorizeResponse, OidcProviderError> {
+    if request.response_type.as_deref() != Some("code") {
+        return Err(OidcProviderError::UnsupportedResponseType(
+            request.response_type.clone().unwrap_or_default(),
+        ));
+    }
+    let scopes = parse_scopes(request.scope.as_deref().unwrap_or_default())?;
+    // PKCE protects the code of public and confidential clients alike
+    let code_challenge = match (
+        request.code_challenge.as_deref(),
+        request.code_challenge_method.as_deref(),
+    ) {
+        (Some(challenge), Some("S256")) if challenge.len() == 43 => challenge.to_string(),
+        _ => {
+            return Err(OidcProviderError::InvalidRequest(
+                "A S256 code_challenge is required".to_string(),
+            ));
+        }
+    };
+    let prompt: Vec<&str> = request
+        .prompt
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect();
+    if prompt.contains(&"none") && prompt.len() > 1 {
+        return Err(OidcProviderError::InvalidRequest(
+            "prompt=none can't be combined with other values".to_string(),
+        ));
+    }
+    let max_age = match request.max_age.as_deref() {
+        Some(max_age) => Some(max_age.parse::<i64>().map_err(|_| {
+            OidcProviderError::InvalidRequest(format!("Invalid max_age: {}", max_age))
+        })?),
+        None => None,
+    };
+
+    let auth_time = user.and_then(|user| user.auth_time).map(|t| t.timestamp());
+    let too_old = max_age.is_some_and(|max_age| {
+        auth_time.is_none_or(|auth_time| auth_time + max_age < Utc::now().timestamp())
+    });
+    let user = match user {
+        Some(user) if !too_old && !prompt.contains(&"login") => user,
+        _ if prompt.contains(&"none") => return Err(OidcProviderError::LoginRequired),
+        _ => {
+            return Ok(OidcAuthorizeResponse::Login {
+                return_to: authorize_path(request),
+                reauthenticate: user.is_some(),
+            });
+        }
+    };
+
+    let params = AuthorizationParams {
+        client_id: client.client_id.clone(),
+        redirect_uri: redirect_uri.to_string(),
+        scopes,
+        state: request.state.clone(),
+        nonce: request.nonce.clone(),
+        code_challenge,
+    };
+
+    let consented = match OidcConsentStore::get_consent(&user.id, &client.client_id).await? {
+        Some(consent) => {
+            let granted: HashSet<&str> = consent.scope.split_whitespace().collect();
+            params
+                .scopes
+                .iter()
+                .all(|scope| granted.contains(scope.as_str()))
+        }
+        None => false,
+    };
+    if client.skip_consent || (consented && !prompt.contains(&"consent")) {
+        let url = issue_code(user.id.clone(), auth_time, params).await?;
+        return Ok(OidcAuthorizeResponse::Redirect(url));
+    }
+    if prompt.contains(&"none") {
+        return Err(OidcProviderError::ConsentRequired);
+    }
+
+    let consent_id = gen_random_string(32)?;
+    let scopes = params.scopes.clone();
+    let pending = PendingConsent {
+        user_id: user.id.clone(),
+        auth_time,
+        params,
+    };
+    cache_put(CONSENT_PREFIX, &consent_id, &pending, OIDC_CONSENT_TTL).await?;
+
+    Ok(OidcAuthorizeResponse::Consent(OidcConsentPage {
+        consent_id,
+        client_name: client.name.clone(),
+        scopes,
+    }))
+}
+
+/// Handle the decision posted from the consent page, returning where to send the user
+pub async fn oidc_consent_core(
+    form: &OidcConsentForm,
+    user: &SessionUser,
+) -> Result<String, OidcProviderError> {
+    let expired = || OidcProviderError::InvalidRequest("Consent request expired".to_string());
+    let pending: PendingConsent = cache_get(CONSENT_PREFIX, &form.consent_id)
+        .await?
+        .ok_or_else(expired)?;
+    if pending.user_id != user.id {
+        return Err(OidcProviderError::InvalidRequest(
+            "Consent request of another user".to_string(),
+        ));
+    }
+    // Taken out of the cache only by its user, so another user can't void the request
+    let pending: PendingConsent = cache_take(CONSENT_PREFIX, &form.consent_id)
+        .await?
+        .ok_or_else(expired)?;
+    let params = pending.params;
+
+    if form.decision != "approve" {
+        let error = OidcProviderError::AccessDenied("The user denied the request".to_string());
+        return Ok(error_redirect(
+            &params.redirect_uri,
+            params.state.as_deref(),
+            &error,
+        ));
+    }
+
+    // Scopes granted before stay granted
+    let existing = OidcConsentStore::get_consent(&user.id, &params.client_id).await?;
+    let mut scopes: Vec<String> = existing
+        .as_ref()
+        .map(|consent| {
+            consent
+                .scope
+                .split_whitespace()
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    for scope in &params.scopes {
+        if !scopes.contains(scope) {
+            scopes.push(scope.clone());
+        }
+    }
+    let now = Utc::now();
+    OidcConsentStore::upsert_consent(StoredConsent {
+        user_id: user.id.clone(),
+        client_id: params.client_id.clone(),
+        scope: scopes.join(" "),
+        created_at: existing.map_or(now, |consent| consent.created_at),
+        updated_at: now,
+    })
+    .await?;
+
+    let redirect_uri = params.redirect_uri.clone();
+    let state = params.state.clone();
+    match issue_code(pending.user_id, pending.auth_time, params).await {
+        Ok(url) => Ok(url),
+        Err(e) => Ok(error_redirect(&redirect_uri, state.as_deref(), &e)),
+    }
+}
+
+/// Redirect URI with a new authorization code for the request
+async fn issue_code(
+    user_id: String,
+    auth_time: Option<i64>,
+    params: AuthorizationParams,
+) -> Result<String, OidcProviderError> {
+    let code = gen_random_string(32)?;
+    let redirect_uri = params.redirect_uri.clone();
+    let state = params.state.clone();
+    let grant = AuthorizationCode {
+        user_id,
+        auth_time,
+        params,
+    };
+    cache_put(CODE_PREFIX, &code, &grant, OIDC_CODE_TTL).await?;
+
+    let mut query = vec![("code", code.as_str()), ("iss", OIDC_ISSUER.as_str())];
+    if let Some(state) = state.as_deref() {
+        query.push(("state", state));
+    }
+    Ok(redirect_with(&redirect_uri, &query))
+}
+
+/// Redeem an authorization code for an ID token and an access token
+///
+/// `authorization` is the Authorization header, for clients using client_secret_basic.
+pub async fn oidc_token_core(
+    authorization: Option<&str>,
+    request: &OidcTokenRequest,
+) -> Result<OidcTokenResponse, OidcProviderError> {
+    if request.grant_type.as_deref() != Some("authorization_code") {
+        return Err(OidcProviderError::UnsupportedGrantType(
+            request.grant_type.clone().unwrap_or_default(),
+        ));
+    }
+    let client = authenticate_client(authorization, request)?;
+
+    let code = request
+        .code
+        .as_deref()
+        .ok_or_else(|| OidcProviderError::InvalidRequest("Missing code".to_string()))?;
+    // Taken out of the cache, so a code is redeemed at most once
+    let grant: AuthorizationCode = cache_take(CODE_PREFIX, code)
+        .await?
+        .ok_or_else(|| OidcProviderError::InvalidGrant("Unknown or expired code".to_string()))?;
+    let params = grant.params;
+    if params.client_id != client.client_id {
+        return Err(OidcProviderError::InvalidGrant(
+            "Code issued to another client".to_string(),
+        ));
+    }
+    if request.redirect_uri.as_deref() != Some(params.redirect_uri.as_str()) {
+        return Err(OidcProviderError::InvalidGrant(
+            "redirect_uri mismatch".to_string(),
+        ));
+    }
+    if !verify_pkce(
+        request.code_verifier.as_deref().unwrap_or_default(),
+        &params.code_challenge,
+    ) {
+        return Err(OidcProviderError::InvalidGrant(
+            "PKCE verification failed".to_string(),
+        ));
+    }
+
+    let user = UserStore::get_user(&grant.user_id)
+        .await?
+        .ok_or_else(|| OidcProviderError::InvalidGrant("User no longer exists".to_string()))?;
+
+    let access_token = gen_random_string(32)?;
+    let access_grant = AccessTokenGrant {
+        user_id: user.id.clone(),
+        client_id: client.client_id.clone(),
+        scopes: params.scopes.clone(),
+    };
+    cache_put(
+        ACCESS_TOKEN_PREFIX,
+        &access_token,
+        &access_grant,
+        *OIDC_TOKEN_TTL,
+    )
+    .await?;
+
+    let now = Utc::now().timestamp();
+    let claims = IdTokenClaims {
+        iss: OIDC_ISSUER.to_string(),
+        sub: subject(client, &user.id),
+        aud: client.client_id.clone(),
+        exp: now + *OIDC_TOKEN_TTL as i64,
+        iat: now,
+        auth_time: grant.auth_time,
+        nonce: params.nonce,
+        at_hash: URL_SAFE_NO_PAD.encode(&Sha256::digest(access_token.as_bytes())[..16]),
+        profile: profile_claims(&user, &params.scopes),
+    };
+
+    Ok(OidcTokenResponse {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in: *OIDC_TOKEN_TTL,
+        id_token: sign_id_token(&claims)?,
+        scope: params.scopes.join(" "),
+    })
+}
+
+/// Claims of the user an access token was issued for (OIDC Core 1.0, 5.3)
+///
+/// `authorization` is the Authorization header with the Bearer access token.
+pub async fn oidc_userinfo_core(authorization: Option<&str>) -> Result<Value, OidcProviderError> {
+    let access_token = authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| OidcProviderError::InvalidToken("Missing Bearer token".to_string()))?;
+    let grant: AccessTokenGrant = cache_get(ACCESS_TOKEN_PREFIX, access_token.trim())
+        .await?
+        .ok_or_else(|| OidcProviderError::InvalidToken("Unknown or expired token".to_string()))?;
+    let client = get_client(Some(&grant.client_id))?;
+    let user = UserStore::get_user(&grant.user_id)
+        .await?
+        .ok_or_else(|| OidcProviderError::InvalidToken("User no longer exists".to_string()))?;
+
+    let mut claims = Map::new();
+    claims.insert("sub".to_string(), json!(subject(client, &user.id)));
+    claims.extend(profile_claims(&user, &grant.scopes));
+    Ok(Value::Object(claims))
+}
+
+fn get_client(client_id: Option<&str>) -> Result<&'static OidcClient, OidcProviderError> {
+    let client_id = client_id.unwrap_or_default();
+    OIDC_CLIENTS
+        .get(client_id)
+        .ok_or_else(|| OidcProviderError::InvalidClient(format!("Unknown client: {}", client_id)))
+}
+
+/// Client of a token request, by client_secret_basic, client_secret_post or, for public
+/// clients, by client_id alone
+fn authenticate_client(
+    authorization: Option<&str>,
+    request: &OidcTokenRequest,
+) -> Result<&'static OidcClient, OidcProviderError> {
+    let basic = authorization.and_then(|value| value.strip_prefix("Basic "));
+    let (client_id, secret) = match basic {
+        Some(_) if request.client_secret.is_some() => {
+            return Err(OidcProviderError::InvalidRequest(
+                "More than one client authentication method".to_string(),
+            ));
+        }
+        Some(credentials) => {
+            let decoded = STANDARD
+                .decode(credentials.trim())
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or_else(|| {
+                    OidcProviderError::InvalidClient("Malformed Basic credentials".to_string())
+                })?;
+            let (id, secret) = decoded.split_once(':').ok_or_else(|| {
+                OidcProviderError::InvalidClient("Malformed Basic credentials".to_string())
+            })?;
+            // Both are form-urlencoded before being joined (RFC 6749, 2.3.1)
+            (Some(form_decode(id)), Some(form_decode(secret)))
+        }
+        None => (request.client_id.clone(), request.client_secret.clone()),
+    };
+
+    let client = get_client(client_id.as_deref())?;
+    let authenticated = match (&client.client_secret, &secret) {
+        // Compared as digests, so the time taken says nothing about the secret
+        (Some(expected), Some(secret)) => {
+            Sha256::digest(expected.as_bytes()) == Sha256::digest(secret.as_bytes())
+        }
+        (None, None) => true,
+        _ => false,
+    };
+    if !authenticated {
+        return Err(OidcProviderError::InvalidClient(format!(
+            "Authentication failed for client {}",
+            client.client_id
+        )));
+    }
+    Ok(client)
+}
+
+fn form_decode(value: &str) -> String {
+    form_urlencoded::parse(format!("v={}", value).as_bytes())
+        .next()
+        .map(|(_, value)| value.into_owned())
+        .unwrap_or_default()
+}
+
+/// Requested scopes the provider supports, which must include openid
+fn parse_scopes(scope: &str) -> Result<Vec<String>, OidcProviderError> {
+    let mut scopes: Vec<String> = Vec::new();
+    for scope in scope.split_whitespace() {
+        if OIDC_SCOPES_SUPPORTED.contains(&scope) && !scopes.iter().any(|s| s == scope) {
+            scopes.push(scope.to_string());
+        }
+    }
+    if !scopes.iter().any(|scope| scope == "openid") {
+        return Err(OidcProviderError::InvalidScope(
+            "The openid scope is required".to_string(),
+        ));
+    }
+    Ok(scopes)
+}
+
+/// Check a code verifier against the S256 code challenge (RFC 7636, 4.6)
+fn verify_pkce(code_verifier: &str, code_challenge: &str) -> bool {
+    (43..=128).contains(&code_verifier.len())
+        && URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())) == code_challenge
+}
+
+/// `sub` of a user for a client, pairwise ones derived from the client's sector
+fn subject(client: &OidcClient, user_id: &str) -> String {
+    match client.subject_type {
+        SubjectType::Public => obfuscate_user_id(user_id),
+        SubjectType::Pairwise => obfuscate_user_id(&format!("{}|{}", client.sector, user_id)),
+    }
+}
+
+/// Claims of the profile and email scopes
+fn profile_claims(user: &DbUser, scopes: &[String]) -> Map<String, Value> {
+    let mut claims = Map::new();
+    if scopes.iter().any(|scope| scope == "profile") {
+        claims.insert("name".to_string(), json!(user.label));
+        claims.insert("preferred_username".to_string(), json!(user.account));
+    }
+    // The account is an email address for most users, but not for all
+    if scopes.iter().any(|scope| scope == "email") && user.account.contains('@') {
+        claims.insert("email".to_string(), json!(user.account));
+    }
+    claims
+}
+
+fn sign_id_token(claims: &IdTokenClaims) -> Result<String, OidcProviderError> {
+    let key = OIDC_SIGNING_KEY
+        .as_ref()
+        .ok_or_else(|| OidcProviderError::Crypto("No OIDC signing key configured".to_string()))?;
+    let header = Header {
+        kid: Some(key.kid.clone()),
+        ..Header::new(key.alg)
+    };
+    jsonwebtoken::encode(&header, claims, &key.encoding_key)
+        .map_err(|e| OidcProviderError::Crypto(e.to_string()))
+}
+
+/// Path of an authentication request to come back to after login
+///
+/// prompt=login and max_age are dropped, the new login satisfies them and the login page
+/// sends signed-in users straight back.
+fn authorize_path(request: &OidcAuthorizeRequest) -> String {
+    let prompt = request.prompt.as_deref().map(|prompt| {
+        prompt
+            .split_whitespace()
+            .filter(|value| *value != "login")
+            .collect::<Vec<_>>()
+            .join(" ")
+    });
+    let params = [
+        ("client_id", request.client_id.as_deref()),
+        ("redirect_uri", request.redirect_uri.as_deref()),
+        ("response_type", request.response_type.as_deref()),
+        ("scope", request.scope.as_deref()),
+        ("state", request.state.as_deref()),
+        ("nonce", request.nonce.as_deref()),
+        ("code_challenge", request.code_challenge.as_deref()),
+        (
+            "code_challenge_method",
+            request.code_challenge_method.as_deref(),
+        ),
+        (
+            "prompt",
+            prompt.as_deref().filter(|prompt| !prompt.is_empty()),
+        ),
+    ];
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(
+            params
+                .iter()
+                .filter_map(|(key, value)| value.map(|value| (key, value))),
+        )
+        .finish();
+    format!("{}/op/authorize?{}", O2P_ROUTE_PREFIX.as_str(), query)
+}
+
+/// Error response to the client's redirect URI (RFC 6749, 4.1.2.1; RFC 9207)
+fn error_redirect(redirect_uri: &str, state: Option<&str>, error: &OidcProviderError) -> String {
+    let description = match error.error_code() {
+        // Details of internal errors stay in the logs
+        "server_error" => "Internal error".to_string(),
+        _ => error.to_string(),
+    };
+    let mut query = vec![
+        ("error", error.error_code()),
+        ("error_description", description.as_str()),
+        ("iss", OIDC_ISSUER.as_str()),
+    ];
+    if let Some(state) = state {
+        query.push(("state", state));
+    }
+    redirect_with(redirect_uri, &query)
+}
+
+fn redirect_with(redirect_uri: &str, query: &[(&str, &str)]) -> String {
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(query)
+        .finish();
+    let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", redirect_uri, separator, query)
+}
+
+async fn cache_put<T: Serialize>(
+    prefix: &str,
+    key: &str,
+    value: &T,
+    ttl: u64,
+) -> Result<(), OidcProviderError> {
+    let value =
+        serde_json::to_string(value).map_err(|e| OidcProviderError::Serde(e.to_string()))?;
+    GENERIC_CACHE_STORE
+        .lock()
+        .await
+        .put_with_ttl(prefix, key, CacheData { value }, ttl as usize)
+        .await
+        .map_err(|e| OidcProviderError::Storage(e.to_string()))
+}
+
+async fn cache_get<T: DeserializeOwned>(
+    prefix: &str,
+    key: &str,
+) -> Result<Option<T>, OidcProviderError> {
+    let data = GENERIC_CACHE_STORE
+        .lock()
+        .await
+        .get(prefix, key)
+        .await
+        .map_err(|e| OidcProviderError::Storage(e.to_string()))?;
+    data.map(|data| serde_json::from_str(&data.value))
+        .transpose()
+        .map_err(|e| OidcProviderError::Serde(e.to_string()))
+}
+
+/// Get and remove an entry under a single lock, so it is taken at most once
+async fn cache_take<T: DeserializeOwned>(
+    prefix: &str,
+    key: &str,
+) -> Result<Option<T>, OidcProviderError> {
+    let mut store = GENERIC_CACHE_STORE.lock().await;
+    let data = store
+        .get(prefix, key)
+        .await
+        .map_err(|e| OidcProviderError::Storage(e.to_string()))?;
+    if data.is_some() {
+        store
+            .remove(prefix, key)
+            .await
+            .map_err(|e| OidcProviderError::Storage(e.to_string()))?;
+    }
+    data.map(|data| serde_json::from_str(&data.value))
+        .transpose()
+        .map_err(|e| OidcProviderError::Serde(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{init_test_stores, test_ec_jwk};
+    use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+
+    /// PKCE verifier and challenge of RFC 7636, Appendix B
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+    /// A new user, signed in `signed_in_ago` seconds ago
+    async fn signed_in(name: &str, signed_in_ago: i64) -> SessionUser {
+        init_test_stores().await;
+        let now = Utc::now();
+        let user = UserStore::upsert_user(DbUser {
+            id: format!("op-{}-{}", name, gen_random_string(8).unwrap()),
+            account: format!("{}@example.com", name),
+            label: name.to_string(),
+            created_at: now,
+            updated_at: now,
+        })
+        .await
+        .unwrap();
+        SessionUser {
+            auth_time: Some(now - chrono::Duration::seconds(signed_in_ago)),
+            ..user.into()
+        }
+    }
+
+    fn redirect_uri(client_id: &str) -> String {
+        format!("https://{}.example.com/callback", client_id)
+    }
+
+    fn authorize_request(client_id: &str) -> OidcAuthorizeRequest {
+        OidcAuthorizeRequest {
+            client_id: Some(client_id.to_string()),
+            redirect_uri: Some(redirect_uri(client_id)),
+            response_type: Some("code".to_string()),
+            scope: Some("openid profile email".to_string()),
+            state: Some("state-1".to_string()),
+            nonce: Some("nonce-1".to_string()),
+            code_challenge: Some(CHALLENGE.to_string()),
+            code_challenge_method: Some("S256".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn query_param(url: &str, name: &str) -> Option<String> {
+        url::Url::parse(url)
+            .unwrap()
+            .query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    }
+
+    /// Authorization code of the request, approving the consent if it is asked for
+    async fn authorize_code(request: &OidcAuthorizeRequest, user: &SessionUser) -> String {
+        let url = match oidc_authorize_core(request, Some(user)).await.unwrap() {
+            OidcAuthorizeResponse::Redirect(url) => url,
+            OidcAuthorizeResponse::Consent(page) => {
+                let form = OidcConsentForm {
+                    consent_id: page.consent_id,
+                    decision: "approve".to_string(),
+                };
+                oidc_consent_core(&form, user).await.unwrap()
+            }
+            OidcAuthorizeResponse::Login { .. } => panic!("Unexpected login"),
+        };
+        assert!(url.starts_with(request.redirect_uri.as_deref().unwrap()));
+        assert_eq!(query_param(&url, "state"), request.state);
+        query_param(&url, "code").unwrap()
+    }
+
+    fn token_request(client_id: &str, code: &str) -> OidcTokenRequest {
+        OidcTokenRequest {
+            grant_type: Some("authorization_code".to_string()),
+            code: Some(code.to_string()),
+            redirect_uri: Some(redirect_uri(client_id)),
+            code_verifier: Some(VERIFIER.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn basic(client_id: &str, secret: &str) -> String {
+        format!(
+            "Basic {}",
+            STANDARD.encode(format!("{}:{}", client_id, secret))
+        )
+    }
+
+    /// Claims of an ID token, verified with the provider's key
+    fn id_token_claims(id_token: &str, client_id: &str) -> Value {
+        let jwk = test_ec_jwk("");
+        let key =
+            DecodingKey::from_ec_components(jwk["x"].as_str().unwrap(), jwk["y"].as_str().unwrap())
+                .unwrap();
+        let mut validation = Validation::new(Algorithm::ES256);
+        validation.set_audience(&[client_id]);
+        validation.set_issuer(&[OIDC_ISSUER.as_str()]);
+        jsonwebtoken::decode::<Value>(id_token, &key, &validation)
+            .unwrap()
+            .claims
+    }
+
+    /// `sub` of the user at the client, from the ID token of a complete flow
+    async fn flow_subject(client_id: &str, secret: &str, user: &SessionUser) -> String {
+        let code = authorize_code(&authorize_request(client_id), user).await;
+        let authorization = basic(client_id, secret);
+        let request = token_request(client_id, &code);
+        let response = oidc_token_core(Some(&authorization), &request)
+            .await
+            .unwrap();
+        let claims = id_token_claims(&response.id_token, client_id);
+        claims["sub"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_code_flow() {
+        let user = signed_in("flow", 10).await;
+        let request = authorize_request("wiki");
+
+        // The user is asked for consent the first time only
+        let page = match oidc_authorize_core(&request, Some(&user)).await.unwrap() {
+            OidcAuthorizeResponse::Consent(page) => page,
+            response => panic!("Unexpected response: {:?}", response),
+        };
+        assert_eq!(page.scopes, vec!["openid", "profile", "email"]);
+        let form = OidcConsentForm {
+            consent_id: page.consent_id,
+            decision: "approve".to_string(),
+        };
+        let url = oidc_consent_core(&form, &user).await.unwrap();
+        assert_eq!(
+            query_param(&url, "iss").as_deref(),
+            Some(OIDC_ISSUER.as_str())
+        );
+        let code = query_param(&url, "code").unwrap();
+
+        let authorization = basic("wiki", "wiki-secret");
+        let response = oidc_token_core(Some(&authorization), &token_request("wiki", &code))
+            .await
+            .unwrap();
+        assert_eq!(response.scope, "openid profile email");
+        let claims = id_token_claims(&response.id_token, "wiki");
+        assert_eq!(claims["nonce"], "nonce-1");
+        assert_eq!(claims["email"], "flow@example.com");
+        assert_eq!(
+            claims["auth_time"],
+            json!(user.auth_time.unwrap().timestamp())
+        );
+
+        let authorization = format!("Bearer {}", response.access_token);
+        let userinfo = oidc_userinfo_core(Some(&authorization)).await.unwrap();
+        assert_eq!(userinfo["sub"], claims["sub"]);
+        assert_eq!(userinfo["name"], "flow");
+
+        // A code is redeemed only once
+        let authorization = basic("wiki", "wiki-secret");
+        assert!(matches!(
+            oidc_token_core(Some(&authorization), &token_request("wiki", &code)).await,
+            Err(OidcProviderError::InvalidGrant(_))
+        ));
+
+        // The consent is remembered
+        assert!(matches!(
+            oidc_authorize_core(&request, Some(&user)).await.unwrap(),
+            OidcAuthorizeResponse::Redirect(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_token_request_checks() {
+        let user = signed_in("checks", 10).await;
+        let authorization = basic("wiki", "wiki-secret");
+
+        // The redirect_uri must be the one of the authentication request
+        let code = authorize_code(&authorize_request("wiki"), &user).await;
+        let request = OidcTokenRequest {
+            redirect_uri: Some("https://wiki.example.com/other".to_string()),
+            ..token_request("wiki", &code)
+        };
+        assert!(matches!(
+            oidc_token_core(Some(&authorization), &request).await,
+            Err(OidcProviderError::InvalidGrant(_))
+        ));
+
+        // and the verifier the one of its code challenge
+        let code = authorize_code(&authorize_request("wiki"), &user).await;
+        let request = OidcTokenRequest {
+            code_verifier: Some(VERIFIER.replace('d', "e")),
+            ..token_request("wiki", &code)
+        };
+        assert!(matches!(
+            oidc_token_core(Some(&authorization), &request).await,
+            Err(OidcProviderError::InvalidGrant(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_client_authentication() {
+        let user = signed_in("client-auth", 10).await;
+
+        // client_secret_post
+        let code = authorize_code(&authorize_request("wiki"), &user).await;
+        let post = |secret: &str| OidcTokenRequest {
+            client_id: Some("wiki".to_string()),
+            client_secret: Some(secret.to_string()),
+            ..token_request("wiki", &code)
+        };
+        let error = oidc_token_core(None, &post("wrong")).await.unwrap_err();
+        assert_eq!(error.error_code(), "invalid_client");
+        let error = oidc_token_core(Some(&basic("wiki", "wrong")), &token_request("wiki", &code))
+            .await
+            .unwrap_err();
+        assert_eq!(error.error_code(), "invalid_client");
+        // Failed authentications leave the code redeemable
+        assert!(oidc_token_core(None, &post("wiki-secret")).await.is_ok());
+
+        // A confidential client has to authenticate
+        let code = authorize_code(&authorize_request("wiki"), &user).await;
+        let request = OidcTokenRequest {
+            client_id: Some("wiki".to_string()),
+            ..token_request("wiki", &code)
+        };
+        let error = oidc_token_core(None, &request).await.unwrap_err();
+        assert_eq!(error.error_code(), "invalid_client");
+
+        // A public client identifies itself by its client_id, without a secret
+        let code = authorize_code(&authorize_request("spa"), &user).await;
+        let request = |secret: Option<&str>| OidcTokenRequest {
+            client_id: Some("spa".to_string()),
+            client_secret: secret.map(str::to_string),
+            ..token_request("spa", &code)
+        };
+        let error = oidc_token_core(None, &request(Some("guess")))
+            .await
+            .unwrap_err();
+        assert_eq!(error.error_code(), "invalid_client");
+        assert!(oidc_token_core(None, &request(None)).await.is_ok());
+    }
+
+    /// `error` of the redirect an authentication request ends with
+    async fn redirect_error(
+        request: &OidcAuthorizeRequest,
+        user: Option<&SessionUser>,
+    ) -> Option<String> {
+        match oidc_authorize_core(request, user).await.unwrap() {
+            OidcAuthorizeResponse::Redirect(url) => query_param(&url, "error"),
+            response => panic!("Unexpected response: {:?}", response),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prompt_none() {
+        let user = signed_in("prompt-none", 10).await;
+        let request = OidcAuthorizeRequest {
+            prompt: Some("none".to_string()),
+            ..authorize_request("grafana")
+        };
+        assert_eq!(
+            redirect_error(&request, None).await.as_deref(),
+            Some("login_required")
+        );
+        assert_eq!(
+            redirect_error(&request, Some(&user)).await.as_deref(),
+            Some("consent_required")
+        );
+
+        // Once consented, no page is needed
+        authorize_code(&authorize_request("grafana"), &user).await;
+        assert_eq!(redirect_error(&request, Some(&user)).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_max_age() {
+        let user = signed_in("max-age", 3600).await;
+        let request = |max_age: &str| OidcAuthorizeRequest {
+            max_age: Some(max_age.to_string()),
+            ..authorize_request("wiki")
+        };
+
+        // The session is too old, the user signs in again
+        match oidc_authorize_core(&request("600"), Some(&user))
+            .await
+            .unwrap()
+        {
+            OidcAuthorizeResponse::Login {
+                return_to,
+                reauthenticate,
+            } => {
+                assert!(reauthenticate);
+                assert!(!return_to.contains("max_age"));
+            }
+            response => panic!("Unexpected response: {:?}", response),
+        }
+        assert!(matches!(
+            oidc_authorize_core(&request("7200"), Some(&user))
+                .await
+                .unwrap(),
+            OidcAuthorizeResponse::Consent(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_consent_of_another_user() {
+        let user = signed_in("consent-owner", 10).await;
+        let other = signed_in("consent-other", 10).await;
+        let page = match oidc_authorize_core(&authorize_request("wiki"), Some(&user))
+            .await
+            .unwrap()
+        {
+            OidcAuthorizeResponse::Consent(page) => page,
+            response => panic!("Unexpected response: {:?}", response),
+        };
+        let form = OidcConsentForm {
+            consent_id: page.consent_id,
+            decision: "approve".to_string(),
+        };
+        assert!(matches!(
+            oidc_consent_core(&form, &other).await,
+            Err(OidcProviderError::InvalidRequest(_))
+        ));
+
+        // The request is still waiting for its own user
+        let url = oidc_consent_core(&form, &user).await.unwrap();
+        assert!(query_param(&url, "code").is_some());
+        assert!(oidc_consent_core(&form, &user).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pairwise_subject() {
+        let user = signed_in("pairwise", 10).await;
+        let wiki_sub = flow_subject("wiki", "wiki-secret", &user).await;
+        assert_eq!(flow_subject("wiki", "wiki-secret", &user).await, wiki_sub);
+        let grafana_sub = flow_subject("grafana", "grafana-secret", &user).await;
+        assert_ne!(grafana_sub, wiki_sub);
+        assert!(!wiki_sub.contains(&user.id));
+    }
+
+    fn client(subject_type: SubjectType, sector: &str) -> OidcClient {
+        OidcClient {
+            name: "wiki".to_string(),
+            client_id: "wiki".to_string(),
+            client_secret: None,
+            redirect_uris: vec![format!("https://{}/callback", sector)],
+            skip_consent: false,
+            subject_type,
+            sector: sector.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_scopes() {
+        let scopes = parse_scopes("openid email offline_access email").unwrap();
+        assert_eq!(scopes, vec!["openid", "email"]);
+        assert!(matches!(
+            parse_scopes("profile email"),
+            Err(OidcProviderError::InvalidScope(_))
+        ));
+    }
+
+    #[test]
+    fn test_verify_pkce() {
+        // RFC 7636, Appendix B
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        let challenge = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+        assert!(verify_pkce(verifier, challenge));
+        assert!(!verify_pkce(
+            verifier,
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cN"
+        ));
+        assert!(!verify_pkce("short", challenge));
+    }
+
+    #[test]
+    fn test_subject() {
+        let wiki = client(SubjectType::Pairwise, "wiki.example.com");
+        let grafana = client(SubjectType::Pairwise, "grafana.example.com");
+        assert_eq!(subject(&wiki, "user-1"), subject(&wiki, "user-1"));
+        assert_ne!(subject(&wiki, "user-1"), subject(&grafana, "user-1"));
+        assert_ne!(subject(&wiki, "user-1"), subject(&wiki, "user-2"));
+
+        let public = client(SubjectType::Public, "wiki.example.com");
+        let other = client(SubjectType::Public, "grafana.example.com");
+        assert_eq!(subject(&public, "user-1"), subject(&other, "user-1"));
+        assert_ne!(subject(&public, "user-1"), "user-1");
+    }
+
+    #[test]
+    fn test_redirect_with() {
+        assert_eq!(
+            redirect_with(
+                "https://app.example.com/cb",
+                &[("code", "a b"), ("state", "x&y")]
+            ),
+            "https://app.example.com/cb?code=a+b&state=x%26y"
+        );
+        assert_eq!(
+            redirect_with("https://app.example.com/cb?tenant=1", &[("code", "c")]),
+            "https://app.example.com/cb?tenant=1&code=c"
+        );
+    }
+}
@@ -0,0 +1,25 @@
+mod config;
+mod errors;
+mod main;
+mod storage;
+mod types;
+
+pub use errors::OidcProviderError;
+pub use main::{
+    get_oidc_provider_jwks, get_oidc_provider_metadata, is_oidc_provider_enabled,
+    oidc_authorize_core, oidc_consent_core, oidc_token_core, oidc_userinfo_core,
+};
+pub(crate) use storage::OidcConsentStore;
+pub use types::{
+    OidcAuthorizeRequest, OidcAuthorizeResponse, OidcConsentForm, OidcConsentPage,
+    OidcTokenRequest, OidcTokenResponse,
+};
+
+pub async fn init() -> Result<(), OidcProviderError> {
+    // Validate the registered clients and the signing key early
+    let _ = *config::OIDC_CLIENTS;
+    let _ = *config::OIDC_ISSUER;
+
+    // Initialize the consents table
+    OidcConsentStore::init().await
+}
@@ -0,0 +1,9 @@
+use std::{env, sync::LazyLock};
+
+use crate::storage::DB_TABLE_PREFIX;
+
+/// OIDC provider consents table name
+pub(super) static DB_TABLE_OIDC_CONSENTS: LazyLock<String> = LazyLock::new(|| {
+    env::var("DB_TABLE_OIDC_CONSENTS")
+        .unwrap_or_else(|_| format!("{}{}", *DB_TABLE_PREFIX, "oidc_consents"))
+});
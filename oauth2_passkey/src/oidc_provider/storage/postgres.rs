@@ -0,0 +1,113 @@
+use crate::oidc_provider::{errors::OidcProviderError, types::StoredConsent};
+use crate::storage::validate_postgres_table_schema;
+use crate::userdb::DB_TABLE_USERS;
+use sqlx::{Pool, Postgres};
+
+use super::config::DB_TABLE_OIDC_CONSENTS;
+
+// PostgreSQL implementations
+pub(super) async fn create_tables_postgres(pool: &Pool<Postgres>) -> Result<(), OidcProviderError> {
+    // Create oidc_consents table, one row per user and client
+    sqlx::query(&format!(
+        r#"
+        CREATE TABLE IF NOT EXISTS {} (
+            user_id TEXT NOT NULL REFERENCES {}(id),
+            client_id TEXT NOT NULL,
+            scope TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL,
+            PRIMARY KEY (user_id, client_id)
+        )
+        "#,
+        DB_TABLE_OIDC_CONSENTS.as_str(),
+        DB_TABLE_USERS.as_str()
+    ))
+    .execute(pool)
+    .await
+    .map_err(|e| OidcProviderError::Storage(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Validates that the consents table schema matches what we expect
+pub(super) async fn validate_oidc_tables_postgres(
+    pool: &Pool<Postgres>,
+) -> Result<(), OidcProviderError> {
+    let expected_columns = [
+        ("user_id", "text"),
+        ("client_id", "text"),
+        ("scope", "text"),
+        ("created_at", "timestamp with time zone"),
+        ("updated_at", "timestamp with time zone"),
+    ];
+
+    validate_postgres_table_schema(
+        pool,
+        DB_TABLE_OIDC_CONSENTS.as_str(),
+        &expected_columns,
+        OidcProviderError::Storage,
+    )
+    .await
+}
+
+pub(super) async fn get_consent_postgres(
+    pool: &Pool<Postgres>,
+    user_id: &str,
+    client_id: &str,
+) -> Result<Option<StoredConsent>, OidcProviderError> {
+    let table_name = DB_TABLE_OIDC_CONSENTS.as_str();
+
+    sqlx::query_as::<_, StoredConsent>(&format!(
+        "SELECT * FROM {} WHERE user_id = $1 AND client_id = $2",
+        table_name
+    ))
+    .bind(user_id)
+    .bind(client_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| OidcProviderError::Storage(e.to_string()))
+}
+
+pub(super) async fn upsert_consent_postgres(
+    pool: &Pool<Postgres>,
+    consent: StoredConsent,
+) -> Result<(), OidcProviderError> {
+    let table_name = DB_TABLE_OIDC_CONSENTS.as_str();
+
+    sqlx::query(&format!(
+        r#"
+        INSERT INTO {}
+        (user_id, client_id, scope, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (user_id, client_id) DO UPDATE SET
+            scope = excluded.scope,
+            updated_at = excluded.updated_at
+        "#,
+        table_name
+    ))
+    .bind(&consent.user_id)
+    .bind(&consent.client_id)
+    .bind(&consent.scope)
+    .bind(consent.created_at)
+    .bind(consent.updated_at)
+    .execute(pool)
+    .await
+    .map_err(|e| OidcProviderError::Storage(e.to_string()))?;
+
+    Ok(())
+}
+
+pub(super) async fn delete_consents_by_user_postgres(
+    pool: &Pool<Postgres>,
+    user_id: &str,
+) -> Result<(), OidcProviderError> {
+    let table_name = DB_TABLE_OIDC_CONSENTS.as_str();
+
+    sqlx::query(&format!("DELETE FROM {} WHERE user_id = $1", table_name))
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .map_err(|e| OidcProviderError::Storage(e.to_string()))?;
+
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use crate::oidc_provider::{errors::OidcProviderError, types::StoredConsent};
+use crate::storage::GENERIC_DATA_STORE;
+
+use super::postgres::*;
+use super::sqlite::*;
+
+pub(crate) struct OidcConsentStore;
+
+impl OidcConsentStore {
+    /// Initialize the consents table
+    pub(crate) async fn init() -> Result<(), OidcProviderError> {
+        let store = GENERIC_DATA_STORE.lock().await;
+
+        match (store.as_sqlite(), store.as_postgres()) {
+            (Some(pool), _) => {
+                create_tables_sqlite(pool).await?;
+                validate_oidc_tables_sqlite(pool).await
+            }
+            (_, Some(pool)) => {
+                create_tables_postgres(pool).await?;
+                validate_oidc_tables_postgres(pool).await
+            }
+            _ => Err(OidcProviderError::Storage(
+                "Unsupported database type".to_string(),
+            )),
+        }
+    }
+
+    /// Get the scopes a user has granted to a client
+    pub(crate) async fn get_consent(
+        user_id: &str,
+        client_id: &str,
+    ) -> Result<Option<StoredConsent>, OidcProviderError> {
+        let store = GENERIC_DATA_STORE.lock().await;
+
+        if let Some(pool) = store.as_sqlite() {
+            get_consent_sqlite(pool, user_id, client_id).await
+        } else if let Some(pool) = store.as_postgres() {
+            get_consent_postgres(pool, user_id, client_id).await
+        } else {
+            Err(OidcProviderError::Storage(
+                "Unsupported database type".to_string(),
+            ))
+        }
+    }
+
+    /// Create or update the consent of a user to a client
+    pub(crate) async fn upsert_consent(consent: StoredConsent) -> Result<(), OidcProviderError> {
+        let store = GENERIC_DATA_STORE.lock().await;
+
+        if let Some(pool) = store.as_sqlite() {
+            upsert_consent_sqlite(pool, consent).await
+        } else if let Some(pool) = store.as_postgres() {
+            upsert_consent_postgres(pool, consent).await
+        } else {
+            Err(OidcProviderError::Storage(
+                "Unsupported database type".to_string(),
+            ))
+        }
+    }
+
+    /// Delete all consents of a user
+    pub(crate) async fn delete_consents_by_user(user_id: &str) -> Result<(), OidcProviderError> {
+        let store = GENERIC_DATA_STORE.lock().await;
+
+        if let Some(pool) = store.as_sqlite() {
+            delete_consents_by_user_sqlite(pool, user_id).await
+        } else if let Some(pool) = store.as_postgres() {
+            delete_consents_by_user_postgres(pool, user_id).await
+        } else {
+            Err(OidcProviderError::Storage(
+                "Unsupported database type".to_string(),
+            ))
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+/// How the `sub` claim of a client is derived from the user ID (OIDC Core 1.0, 8)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SubjectType {
+    /// The same subject for every client
+    Public,
+    /// A subject of its own for every sector, so clients can't correlate users
+    Pairwise,
+}
+
+/// Client registered with the provider, see O2P_OIDC_CLIENTS
+#[derive(Debug, Clone)]
+pub(crate) struct OidcClient {
+    pub(super) name: String,
+    pub(super) client_id: String,
+    /// None for public clients, authenticated by PKCE alone
+    pub(super) client_secret: Option<String>,
+    pub(super) redirect_uris: Vec<String>,
+    pub(super) skip_consent: bool,
+    pub(super) subject_type: SubjectType,
+    /// Host the pairwise subjects are derived for
+    pub(super) sector: String,
+}
+
+/// Query of an authentication request (OIDC Core 1.0, 3.1.2.1)
+#[derive(Debug, Default, Deserialize)]
+pub struct OidcAuthorizeRequest {
+    pub client_id: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub response_type: Option<String>,
+    pub scope: Option<String>,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    pub prompt: Option<String>,
+    pub max_age: Option<String>,
+}
+
+/// What to do with an authentication request
+#[derive(Debug)]
+pub enum OidcAuthorizeResponse {
+    /// Send the user back to the client, with a code or an error
+    Redirect(String),
+    /// Sign the user in, then send them to `return_to`
+    Login {
+        return_to: String,
+        /// The user is signed in, but has to sign in again, so the session is to be ended
+        reauthenticate: bool,
+    },
+    /// Ask the user to consent to the client
+    Consent(OidcConsentPage),
+}
+
+/// Consent page of an authentication request
+#[derive(Debug)]
+pub struct OidcConsentPage {
+    /// To post back with the decision, expires with the request
+    pub consent_id: String,
+    pub client_name: String,
+    pub scopes: Vec<String>,
+}
+
+/// Decision posted from the consent page
+#[derive(Debug, Deserialize)]
+pub struct OidcConsentForm {
+    pub consent_id: String,
+    /// "approve" or "deny"
+    pub decision: String,
+}
+
+/// Form of a token request (RFC 6749, 4.1.3)
+#[derive(Debug, Default, Deserialize)]
+pub struct OidcTokenRequest {
+    pub grant_type: Option<String>,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+/// Successful token response (OIDC Core 1.0, 3.1.3.3)
+#[derive(Debug, Serialize)]
+pub struct OidcTokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: u64,
+    pub id_token: String,
+    pub scope: String,
+}
+
+/// An authentication request checked against its client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct AuthorizationParams {
+    pub(super) client_id: String,
+    pub(super) redirect_uri: String,
+    pub(super) scopes: Vec<String>,
+    pub(super) state: Option<String>,
+    pub(super) nonce: Option<String>,
+    pub(super) code_challenge: String,
+}
+
+/// Request waiting on the consent page, for the signed-in user only
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct PendingConsent {
+    pub(super) user_id: String,
+    pub(super) auth_time: Option<i64>,
+    pub(super) params: AuthorizationParams,
+}
+
+/// What an authorization code stands for until it is redeemed
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct AuthorizationCode {
+    pub(super) user_id: String,
+    pub(super) auth_time: Option<i64>,
+    pub(super) params: AuthorizationParams,
+}
+
+/// What an access token grants, for the userinfo endpoint
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct AccessTokenGrant {
+    pub(super) user_id: String,
+    pub(super) client_id: String,
+    pub(super) scopes: Vec<String>,
+}
+
+/// Claims of the issued ID tokens (OIDC Core 1.0, 2)
+#[derive(Debug, Serialize)]
+pub(super) struct IdTokenClaims {
+    pub(super) iss: String,
+    pub(super) sub: String,
+    pub(super) aud: String,
+    pub(super) exp: i64,
+    pub(super) iat: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) auth_time: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) nonce: Option<String>,
+    pub(super) at_hash: String,
+    #[serde(flatten)]
+    pub(super) profile: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Scopes a user has granted to a client
+#[derive(Debug, Clone, FromRow)]
+pub(crate) struct StoredConsent {
+    pub(crate) user_id: String,
+    pub(crate) client_id: String,
+    /// Space separated
+    pub(crate) scope: String,
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) updated_at: DateTime<Utc>,
+}
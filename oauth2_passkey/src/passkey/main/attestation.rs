@@ -143,10 +143,10 @@ fn verify_packed_attestation(
                     x5c_opt = Some(cert_chain);
                 }
             }
-        } else if let (CborValue::Text(key_str), CborValue::Bytes(id)) = (k, v) {
-            if key_str == "ecdaaKeyId" {
-                ecdaa_key_id = Some(id.clone());
-            }
+        } else if let (CborValue::Text(key_str), CborValue::Bytes(id)) = (k, v)
+            && key_str == "ecdaaKeyId"
+        {
+            ecdaa_key_id = Some(id.clone());
         }
     }
 
@@ -213,10 +213,10 @@ fn get_sig_from_stmt(
     for (key, value) in att_stmt {
         match key {
             CborValue::Text(k) if k == "alg" => {
-                if let CborValue::Integer(a) = value {
-                    if a == &Integer::from(ES256_ALG) {
-                        alg = Some(ES256_ALG);
-                    }
+                if let CborValue::Integer(a) = value
+                    && a == &Integer::from(ES256_ALG)
+                {
+                    alg = Some(ES256_ALG);
                 }
             }
             CborValue::Text(k) if k == "sig" => {
@@ -245,12 +245,11 @@ fn verify_packed_attestation_cert(
         .extensions()
         .iter()
         .find(|ext| ext.oid.as_bytes() == oid_registry::OID_X509_EXT_BASIC_CONSTRAINTS.as_bytes())
+        && basic_constraints.value.contains(&0x01)
     {
-        if basic_constraints.value.contains(&0x01) {
-            return Err(PasskeyError::Verification(
-                "Certificate must not be a CA certificate".to_string(),
-            ));
-        }
+        return Err(PasskeyError::Verification(
+            "Certificate must not be a CA certificate".to_string(),
+        ));
     }
 
     // Verify AAGUID if present
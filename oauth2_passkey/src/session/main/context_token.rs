@@ -16,6 +16,7 @@ use headers::{Cookie, HeaderMapExt};
 /// Name of the cookie where the user context token will be stored
 pub const USER_CONTEXT_TOKEN_COOKIE: &str = "auth_context_token";
 
+use crate::config::AUTH_SERVER_SECRET;
 use crate::session::errors::SessionError;
 
 type HmacSha256 = Hmac<Sha256>;
@@ -23,14 +24,6 @@ type HmacSha256 = Hmac<Sha256>;
 // We're using a simple string representation for tokens instead of a struct
 // to minimize dependencies and complexity
 
-static AUTH_SERVER_SECRET: LazyLock<Vec<u8>> =
-    LazyLock::new(|| match env::var("AUTH_SERVER_SECRET") {
-        Ok(secret) => secret.into_bytes(),
-        Err(_) => "default_secret_key_change_in_production"
-            .to_string()
-            .into_bytes(),
-    });
-
 static USE_CONTEXT_TOKEN_COOKIE: LazyLock<bool> = LazyLock::new(|| {
     match env::var("USE_CONTEXT_TOKEN_COOKIE") {
         Ok(val) => match val.as_str() {
@@ -176,13 +169,10 @@ fn create_context_token_cookie(user_id: &str) -> HeaderMap {
 /// of an incoming request.
 pub fn extract_context_token_from_cookies(headers: &HeaderMap) -> Option<String> {
     // Try to extract Cookie header
-
-    let token = headers.typed_get::<Cookie>().and_then(|cookie| {
+    headers.typed_get::<Cookie>().and_then(|cookie| {
         // Look for our specific context token cookie
         cookie.get(USER_CONTEXT_TOKEN_COOKIE).map(|s| s.to_string())
-    });
-
-    token
+    })
 }
 
 /// Verifies both context token and page context match the user ID
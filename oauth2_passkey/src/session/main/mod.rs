@@ -3,7 +3,8 @@ mod context_token;
 mod session;
 
 pub(crate) use session::{
-    delete_session_from_store_by_session_id, get_session_id_from_headers, renew_session_header,
+    delete_session_from_store_by_session_id, delete_sessions_by_idp_session, get_idp_session,
+    get_session_id_from_headers, renew_session_header,
 };
 
 pub use context_token::{obfuscate_user_id, verify_context_token_and_page};
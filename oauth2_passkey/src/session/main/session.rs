@@ -1,4 +1,4 @@
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
 use headers::Cookie;
 use http::header::{COOKIE, HeaderMap};
 
@@ -6,12 +6,16 @@ use super::context_token::add_context_token_to_header;
 
 use crate::session::config::{SESSION_COOKIE_MAX_AGE, SESSION_COOKIE_NAME};
 use crate::session::errors::SessionError;
-use crate::session::types::{SessionInfo, StoredSession, User as SessionUser};
+use crate::session::types::{IdpSession, SessionInfo, StoredSession, User as SessionUser};
 use crate::utils::{gen_random_string, header_set_cookie};
 
-use crate::storage::GENERIC_CACHE_STORE;
+use crate::storage::{CacheData, GENERIC_CACHE_STORE};
 use crate::userdb::UserStore;
 
+/// Cache prefix of the IDs of the sessions signed in with an IdP session, keyed by
+/// `{provider}:sid:{sid}` and `{provider}:sub:{sub}`
+const IDP_SESSION_PREFIX: &str = "idp_session";
+
 /// Get user information from libuserdb for a given session
 pub async fn prepare_logout_response(cookies: headers::Cookie) -> Result<HeaderMap, SessionError> {
     let mut headers = HeaderMap::new();
@@ -45,6 +49,9 @@ async fn create_new_session(session_info: SessionInfo) -> Result<HeaderMap, Sess
 
 async fn create_and_store_session(session_info: SessionInfo) -> Result<String, SessionError> {
     let session_id = gen_random_string(32)?;
+    if let Some(idp_session) = &session_info.idp_session {
+        index_idp_session(&session_id, idp_session).await?;
+    }
     let stored_session = StoredSession {
         info: session_info,
         ttl: *SESSION_COOKIE_MAX_AGE,
@@ -89,11 +96,144 @@ pub async fn delete_session_from_store_by_session_id(session_id: &str) -> Result
     Ok(())
 }
 
-pub async fn create_session_with_uid(user_id: &str) -> Result<HeaderMap, SessionError> {
+/// Index the session by the IdP session's `sid` and `sub`, for back-channel logout
+async fn index_idp_session(session_id: &str, idp_session: &IdpSession) -> Result<(), SessionError> {
+    let mut keys = vec![idp_session_key(
+        &idp_session.provider,
+        "sub",
+        &idp_session.sub,
+    )];
+    if let Some(sid) = &idp_session.sid {
+        keys.push(idp_session_key(&idp_session.provider, "sid", sid));
+    }
+
+    let mut store = GENERIC_CACHE_STORE.lock().await;
+    for key in keys {
+        let indexed: Vec<String> = match store
+            .get(IDP_SESSION_PREFIX, &key)
+            .await
+            .map_err(|e| SessionError::Storage(e.to_string()))?
+        {
+            Some(data) => serde_json::from_str(&data.value).unwrap_or_default(),
+            None => Vec::new(),
+        };
+        // Drop the sessions that ended in the meantime
+        let mut session_ids = Vec::new();
+        for id in indexed {
+            if store
+                .get("session", &id)
+                .await
+                .map_err(|e| SessionError::Storage(e.to_string()))?
+                .is_some()
+            {
+                session_ids.push(id);
+            }
+        }
+        session_ids.push(session_id.to_string());
+
+        let value = serde_json::to_string(&session_ids)
+            .map_err(|e| SessionError::Storage(e.to_string()))?;
+        store
+            .put_with_ttl(
+                IDP_SESSION_PREFIX,
+                &key,
+                CacheData { value },
+                *SESSION_COOKIE_MAX_AGE as usize,
+            )
+            .await
+            .map_err(|e| SessionError::Storage(e.to_string()))?;
+    }
+    Ok(())
+}
+
+fn idp_session_key(provider: &str, claim: &str, value: &str) -> String {
+    format!("{}:{}:{}", provider, claim, value)
+}
+
+/// Delete the sessions signed in with an IdP session, the one of `sid` if given,
+/// otherwise every session of `sub` (OIDC Back-Channel Logout 1.0, 2.7)
+///
+/// Returns the number of sessions deleted.
+pub(crate) async fn delete_sessions_by_idp_session(
+    provider: &str,
+    sub: Option<&str>,
+    sid: Option<&str>,
+) -> Result<usize, SessionError> {
+    let key = match (sid, sub) {
+        (Some(sid), _) => idp_session_key(provider, "sid", sid),
+        (None, Some(sub)) => idp_session_key(provider, "sub", sub),
+        (None, None) => return Ok(0),
+    };
+
+    let mut store = GENERIC_CACHE_STORE.lock().await;
+    let session_ids: Vec<String> = match store
+        .get(IDP_SESSION_PREFIX, &key)
+        .await
+        .map_err(|e| SessionError::Storage(e.to_string()))?
+    {
+        Some(data) => serde_json::from_str(&data.value).unwrap_or_default(),
+        None => Vec::new(),
+    };
+
+    let mut deleted = 0;
+    for session_id in session_ids {
+        let Some(data) = store
+            .get("session", &session_id)
+            .await
+            .map_err(|e| SessionError::Storage(e.to_string()))?
+        else {
+            continue;
+        };
+        let stored_session: StoredSession = data.try_into()?;
+        let matches = stored_session.info.idp_session.is_some_and(|idp_session| {
+            idp_session.provider == provider
+                && sub.is_none_or(|sub| idp_session.sub == sub)
+                && sid.is_none_or(|sid| idp_session.sid.as_deref() == Some(sid))
+        });
+        if matches {
+            store
+                .remove("session", &session_id)
+                .await
+                .map_err(|e| SessionError::Storage(e.to_string()))?;
+            deleted += 1;
+        }
+    }
+    store
+        .remove(IDP_SESSION_PREFIX, &key)
+        .await
+        .map_err(|e| SessionError::Storage(e.to_string()))?;
+    Ok(deleted)
+}
+
+/// The IdP session of the session in the cookies, if it came from an OpenID Connect login
+pub(crate) async fn get_idp_session(cookies: &Cookie) -> Result<Option<IdpSession>, SessionError> {
+    let Some(session_id) = cookies.get(SESSION_COOKIE_NAME.as_str()) else {
+        return Ok(None);
+    };
+    let Some(data) = GENERIC_CACHE_STORE
+        .lock()
+        .await
+        .get("session", session_id)
+        .await
+        .map_err(|e| SessionError::Storage(e.to_string()))?
+    else {
+        return Ok(None);
+    };
+    let stored_session: StoredSession = data.try_into()?;
+    Ok(stored_session.info.idp_session)
+}
+
+pub async fn create_session_with_uid(
+    user_id: &str,
+    auth_time: DateTime<Utc>,
+    idp_session: Option<IdpSession>,
+) -> Result<HeaderMap, SessionError> {
     // Create minimal session info
     let session_info = SessionInfo {
         user_id: user_id.to_string(),
         expires_at: Utc::now() + Duration::seconds(*SESSION_COOKIE_MAX_AGE as i64),
+        auth_time: Some(auth_time),
+        idp_session,
     };
 
     create_new_session(session_info).await
@@ -115,7 +255,10 @@ pub async fn get_user_from_session(session_cookie: &str) -> Result<SessionUser,
         .map_err(|_| SessionError::SessionError)?
         .ok_or(SessionError::SessionError)?;
 
-    Ok(SessionUser::from(user))
+    Ok(SessionUser {
+        auth_time: stored_session.info.auth_time,
+        ..SessionUser::from(user)
+    })
 }
 
 pub fn get_session_id_from_headers(headers: &HeaderMap) -> Result<Option<&str>, SessionError> {
@@ -224,10 +367,16 @@ pub async fn is_authenticated_strict(headers: &HeaderMap) -> Result<bool, Sessio
     is_authenticated(headers, true).await
 }
 
-#[tracing::instrument]
-pub(crate) async fn renew_session_header(user_id: String) -> Result<HeaderMap, SessionError> {
+/// Create the session and context token cookies for a user who authenticated at `auth_time`,
+/// at the OpenID Connect provider's `idp_session` for OAuth2 logins
+#[tracing::instrument(skip(idp_session))]
+pub(crate) async fn renew_session_header(
+    user_id: String,
+    auth_time: DateTime<Utc>,
+    idp_session: Option<IdpSession>,
+) -> Result<HeaderMap, SessionError> {
     // Create session cookie for authentication
-    let mut headers = create_session_with_uid(&user_id).await?;
+    let mut headers = create_session_with_uid(&user_id, auth_time, idp_session).await?;
 
     add_context_token_to_header(&user_id, &mut headers)?;
 
@@ -235,3 +384,63 @@ pub(crate) async fn renew_session_header(user_id: String) -> Result<HeaderMap, S
 
     Ok(headers)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::init_test_env;
+
+    async fn create_idp_session(provider: &str, sub: &str, sid: Option<&str>) -> String {
+        create_and_store_session(SessionInfo {
+            user_id: "user".to_string(),
+            expires_at: Utc::now() + Duration::seconds(600),
+            auth_time: None,
+            idp_session: Some(IdpSession {
+                provider: provider.to_string(),
+                sub: sub.to_string(),
+                sid: sid.map(String::from),
+                id_token: String::new(),
+            }),
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn session_exists(session_id: &str) -> bool {
+        GENERIC_CACHE_STORE
+            .lock()
+            .await
+            .get("session", session_id)
+            .await
+            .unwrap()
+            .is_some()
+    }
+
+    #[tokio::test]
+    async fn test_delete_sessions_by_idp_session() {
+        init_test_env();
+        let sid_1 = create_idp_session("idp", "logout-alice", Some("logout-sid-1")).await;
+        let sid_2 = create_idp_session("idp", "logout-alice", Some("logout-sid-2")).await;
+        let no_sid = create_idp_session("idp", "logout-alice", None).await;
+        let other_sub = create_idp_session("idp", "logout-bob", Some("logout-sid-3")).await;
+        let other_idp = create_idp_session("other", "logout-alice", Some("logout-sid-1")).await;
+
+        // A sid ends that session only
+        let deleted = delete_sessions_by_idp_session("idp", None, Some("logout-sid-1"));
+        assert_eq!(deleted.await.unwrap(), 1);
+        assert!(!session_exists(&sid_1).await);
+        assert!(session_exists(&sid_2).await);
+        assert!(session_exists(&other_idp).await);
+
+        // A sub alone ends every session of the user at the provider
+        let deleted = delete_sessions_by_idp_session("idp", Some("logout-alice"), None);
+        assert_eq!(deleted.await.unwrap(), 2);
+        assert!(!session_exists(&sid_2).await);
+        assert!(!session_exists(&no_sid).await);
+        assert!(session_exists(&other_sub).await);
+        assert!(session_exists(&other_idp).await);
+
+        let deleted = delete_sessions_by_idp_session("idp", None, None);
+        assert_eq!(deleted.await.unwrap(), 0);
+    }
+}
@@ -14,5 +14,7 @@ pub use main::{
 };
 
 pub(crate) use main::{
-    delete_session_from_store_by_session_id, get_session_id_from_headers, renew_session_header,
+    delete_session_from_store_by_session_id, delete_sessions_by_idp_session, get_idp_session,
+    get_session_id_from_headers, renew_session_header,
 };
+pub(crate) use types::IdpSession;
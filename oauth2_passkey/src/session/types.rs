@@ -10,6 +10,24 @@ pub struct SessionInfo {
     pub user_id: String,
     // pub provider: String,
     pub expires_at: DateTime<Utc>,
+    /// When the user authenticated, at the identity provider for OAuth2 logins
+    #[serde(default)]
+    pub auth_time: Option<DateTime<Utc>>,
+    /// The OpenID Connect provider's session the user signed in with
+    #[serde(default)]
+    pub(crate) idp_session: Option<IdpSession>,
+}
+
+/// Session at an OpenID Connect provider, for RP-initiated and back-channel logout
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct IdpSession {
+    pub(crate) provider: String,
+    /// Subject of the ID token, as issued by the provider
+    pub(crate) sub: String,
+    /// Session ID at the provider (`sid` claim), if it issues one
+    pub(crate) sid: Option<String>,
+    /// ID token of the sign-in, sent as `id_token_hint` at logout
+    pub(crate) id_token: String,
 }
 
 // User information from libuserdb
@@ -20,6 +38,9 @@ pub struct User {
     pub label: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// When the user authenticated for the current session, set when read from a session
+    #[serde(default)]
+    pub auth_time: Option<DateTime<Utc>>,
 }
 
 use crate::userdb::User as DbUser;
@@ -44,6 +65,7 @@ impl From<DbUser> for User {
             label: db_user.label,
             created_at: db_user.created_at,
             updated_at: db_user.updated_at,
+            auth_time: None,
         }
     }
 }
@@ -1,7 +1,13 @@
 use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
 use chrono::{DateTime, Utc};
 use http::header::{HeaderMap, SET_COOKIE};
-use ring::rand::SecureRandom;
+use ring::{
+    aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey},
+    hkdf,
+    rand::SecureRandom,
+};
+
+use crate::config::{AUTH_SERVER_SECRET, O2P_RETURN_TO_ORIGINS};
 
 // use crate::session::SessionError;
 // use crate::passkey::PasskeyError;
@@ -54,6 +60,82 @@ pub(crate) fn header_set_cookie(
     Ok(headers)
 }
 
+/// Derive an AES-256-GCM key for `purpose` from AUTH_SERVER_SECRET
+fn derive_aead_key(purpose: &str) -> Result<LessSafeKey, UtilError> {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, b"oauth2_passkey").extract(&AUTH_SERVER_SECRET);
+    let info = [purpose.as_bytes()];
+    let okm = prk
+        .expand(&info, &AES_256_GCM)
+        .map_err(|_| UtilError::Crypto("Failed to derive key".to_string()))?;
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+/// Encrypt a string with a key derived for `purpose`, returning base64url(nonce || ciphertext)
+pub(crate) fn encrypt_string(plaintext: &str, purpose: &str) -> Result<String, UtilError> {
+    let key = derive_aead_key(purpose)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    ring::rand::SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| UtilError::Crypto("Failed to generate nonce".to_string()))?;
+
+    let mut in_out = plaintext.as_bytes().to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(purpose.as_bytes()),
+        &mut in_out,
+    )
+    .map_err(|_| UtilError::Crypto("Failed to encrypt".to_string()))?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&in_out);
+    base64url_encode(sealed)
+}
+
+/// Decrypt a string produced by `encrypt_string` for the same `purpose`
+pub(crate) fn decrypt_string(sealed: &str, purpose: &str) -> Result<String, UtilError> {
+    let key = derive_aead_key(purpose)?;
+    let sealed = base64url_decode(sealed)?;
+    if sealed.len() < NONCE_LEN + AES_256_GCM.tag_len() {
+        return Err(UtilError::Format("Encrypted data too short".to_string()));
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce)
+        .map_err(|_| UtilError::Crypto("Invalid nonce".to_string()))?;
+    let mut in_out = ciphertext.to_vec();
+    let plaintext = key
+        .open_in_place(nonce, Aad::from(purpose.as_bytes()), &mut in_out)
+        .map_err(|_| UtilError::Crypto("Failed to decrypt".to_string()))?;
+
+    String::from_utf8(plaintext.to_vec())
+        .map_err(|_| UtilError::Format("Decrypted data is not UTF-8".to_string()))
+}
+
+/// Check a URL to send the user to after login against open redirects
+///
+/// Returns the URL if it is a path on this site or an absolute URL of ORIGIN or one of
+/// O2P_ALLOWED_RETURN_ORIGINS.
+pub fn validate_return_to(return_to: &str) -> Option<String> {
+    is_allowed_return_to(return_to, &O2P_RETURN_TO_ORIGINS).then(|| return_to.to_string())
+}
+
+fn is_allowed_return_to(return_to: &str, allowed_origins: &[String]) -> bool {
+    // Browsers treat backslashes as slashes and ignore tabs and newlines in URLs
+    if return_to.len() > 2048 || return_to.chars().any(|c| c == '\\' || c.is_control()) {
+        return false;
+    }
+    if return_to.starts_with('/') {
+        // "//host" is a protocol-relative URL of another site
+        return !return_to.starts_with("//");
+    }
+    match url::Url::parse(return_to) {
+        Ok(url) if matches!(url.scheme(), "https" | "http") => {
+            allowed_origins.contains(&url.origin().ascii_serialization())
+        }
+        _ => false,
+    }
+}
+
 use thiserror::Error;
 
 #[derive(Debug, Error, Clone)]
@@ -67,3 +149,38 @@ pub enum UtilError {
     #[error("Invalid format: {0}")]
     Format(String),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt_string() {
+        let sealed = encrypt_string("refresh-token", "test").unwrap();
+        assert_ne!(sealed, encrypt_string("refresh-token", "test").unwrap());
+        assert_eq!(decrypt_string(&sealed, "test").unwrap(), "refresh-token");
+        assert!(decrypt_string(&sealed, "other").is_err());
+    }
+
+    #[test]
+    fn test_is_allowed_return_to() {
+        let allowed = vec!["https://example.com".to_string()];
+        assert!(is_allowed_return_to("/account?tab=1", &allowed));
+        assert!(is_allowed_return_to("https://example.com/a", &allowed));
+        assert!(is_allowed_return_to("https://EXAMPLE.com:443/a", &allowed));
+        assert!(!is_allowed_return_to("//evil.com/a", &allowed));
+        assert!(!is_allowed_return_to("/\\evil.com", &allowed));
+        assert!(!is_allowed_return_to("/\t/evil.com", &allowed));
+        assert!(!is_allowed_return_to(
+            "https://example.com.evil.com/",
+            &allowed
+        ));
+        assert!(!is_allowed_return_to(
+            "https://example.com@evil.com/",
+            &allowed
+        ));
+        assert!(!is_allowed_return_to("http://example.com/", &allowed));
+        assert!(!is_allowed_return_to("javascript:alert(1)", &allowed));
+        assert!(!is_allowed_return_to("account", &allowed));
+    }
+}
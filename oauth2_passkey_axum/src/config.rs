@@ -13,3 +13,8 @@ pub static O2P_REDIRECT_ANON: LazyLock<String> = LazyLock::new(|| {
 pub static O2P_REDIRECT_USER: LazyLock<String> = LazyLock::new(|| {
     std::env::var("O2P_REDIRECT_USER").unwrap_or_else(|_| "/o2p/user/summary".to_string())
 });
+
+/// App URL the OAuth2 callback redirects to in the "app" callback mode, with the result
+/// as `result` ("success" or "error"), `status` and `message` query parameters
+pub static O2P_REDIRECT_APP: LazyLock<Option<String>> =
+    LazyLock::new(|| std::env::var("O2P_REDIRECT_APP").ok());
@@ -17,6 +17,8 @@ impl<T> IntoResponseError<T> for Result<T, CoordinationError> {
                 CoordinationError::UserError(_) => StatusCode::BAD_REQUEST,
                 CoordinationError::SessionError(_) => StatusCode::BAD_REQUEST,
                 CoordinationError::InvalidState => StatusCode::BAD_REQUEST,
+                CoordinationError::DomainNotAllowed(_) => StatusCode::FORBIDDEN,
+                CoordinationError::UnverifiedEmail(_) => StatusCode::FORBIDDEN,
                 CoordinationError::NoContent => StatusCode::NO_CONTENT,
                 CoordinationError::ResourceNotFound { .. } => StatusCode::NOT_FOUND,
                 _ => StatusCode::INTERNAL_SERVER_ERROR,
@@ -1,7 +1,10 @@
 mod config;
 mod error;
 mod middleware;
+#[cfg(feature = "mock-oidc")]
+pub mod mock_oidc;
 mod oauth2;
+mod oidc_provider;
 mod pages;
 mod passkey;
 mod router;
@@ -14,6 +17,6 @@ pub use middleware::{
 };
 pub use passkey::passkey_well_known_router;
 pub use router::oauth2_passkey_router;
-pub use session::AuthUser;
+pub use session::{AuthRedirect, AuthUser};
 
 pub use oauth2_passkey::O2P_ROUTE_PREFIX;
@@ -5,6 +5,8 @@ use axum::{
     response::{IntoResponse, Redirect},
 };
 
+use super::session::{login_url, original_url};
+
 // Simple authentication checker
 pub async fn is_authenticated_or_error(req: Request, next: Next) -> impl IntoResponse {
     match oauth2_passkey::is_authenticated_basic(req.headers()).await {
@@ -14,6 +16,7 @@ pub async fn is_authenticated_or_error(req: Request, next: Next) -> impl IntoRes
 }
 
 // Authentication checker with custom redirect URL
+// The requested page is passed to it as `return_to`
 pub async fn is_authenticated_or_redirect(
     redirect_url: Option<&'static str>,
     req: Request,
@@ -22,7 +25,10 @@ pub async fn is_authenticated_or_redirect(
     match oauth2_passkey::is_authenticated_basic(req.headers()).await {
         Ok(true) => next.run(req).await,
         Ok(false) | Err(_) => match redirect_url {
-            Some(url) => Redirect::temporary(url).into_response(),
+            Some(url) => {
+                let return_to = original_url(req.method(), req.uri(), req.extensions());
+                Redirect::temporary(&login_url(url, return_to.as_deref())).into_response()
+            }
             None => (StatusCode::UNAUTHORIZED, "Unauthorized").into_response(),
         },
     }
@@ -0,0 +1,765 @@
+//! Local OpenID Connect provider for end-to-end tests of the OAuth2 flow
+//!
+//! Enabled with the `mock-oidc` feature. The provider listens on a random port of
+//! 127.0.0.1 and serves the discovery, authorize, token, userinfo and JWKS endpoints,
+//! signing ID tokens with a P-256 key generated at start. The authorize endpoint shows
+//! no page, it signs in the scripted user right away.
+//!
+//! ```ignore
+//! let mock = MockOidcProvider::start("test-client", "test-secret").await?;
+//! mock.add_user(MockUser::new("alice", "alice@example.com"));
+//! mock.fail_next(MockError::Authorize("access_denied".to_string()));
+//! // With OAUTH2_PROVIDERS='mock', before the library is initialized
+//! for (key, value) in mock.env_vars("mock") {
+//!     unsafe { std::env::set_var(key, value) };
+//! }
+//! ```
+
+use axum::{
+    Form, Json, Router,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode, header::AUTHORIZATION},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+};
+use base64::{
+    Engine as _,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use ring::{
+    digest::{SHA256, digest},
+    rand::{SecureRandom, SystemRandom},
+    signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair},
+};
+use serde_json::{Map, Value, json};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+
+/// Lifetime of the issued tokens in seconds
+const TOKEN_LIFETIME: u64 = 3600;
+
+/// A user the mock provider signs in
+#[derive(Debug, Clone)]
+pub struct MockUser {
+    pub sub: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub name: String,
+    pub picture: Option<String>,
+    /// Additional claims of the ID token and the userinfo response
+    pub claims: Map<String, Value>,
+}
+
+impl MockUser {
+    /// A user with a verified email address, named after the subject
+    pub fn new(sub: &str, email: &str) -> Self {
+        Self {
+            sub: sub.to_string(),
+            email: email.to_string(),
+            email_verified: true,
+            name: sub.to_string(),
+            picture: None,
+            claims: Map::new(),
+        }
+    }
+
+    fn to_claims(&self) -> Map<String, Value> {
+        let mut claims = self.claims.clone();
+        claims.insert("sub".to_string(), json!(self.sub));
+        claims.insert("email".to_string(), json!(self.email));
+        claims.insert("email_verified".to_string(), json!(self.email_verified));
+        claims.insert("name".to_string(), json!(self.name));
+        if let Some(picture) = &self.picture {
+            claims.insert("picture".to_string(), json!(picture));
+        }
+        claims
+    }
+}
+
+/// A failure of the next request to the endpoint it applies to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockError {
+    /// Redirect back with this error code, e.g. "access_denied" or "login_required"
+    Authorize(String),
+    /// Respond to the token request with this error code, e.g. "invalid_grant"
+    Token(String),
+    /// Issue an ID token that expired an hour ago
+    ExpiredIdToken,
+    /// Issue an ID token for another audience
+    WrongAudience,
+    /// Issue an ID token with another nonce
+    WrongNonce,
+    /// Sign the ID token with a key missing from the JWKS
+    BadSignature,
+}
+
+/// A code or refresh token and what it was issued for
+#[derive(Clone)]
+struct Grant {
+    redirect_uri: String,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    sub: String,
+    scope: String,
+}
+
+#[derive(Default)]
+struct MockState {
+    users: Vec<MockUser>,
+    current_user: Option<String>,
+    errors: Vec<MockError>,
+    codes: HashMap<String, Grant>,
+    refresh_tokens: HashMap<String, Grant>,
+    /// Access token to the subject it was issued for
+    access_tokens: HashMap<String, String>,
+}
+
+struct SigningKey {
+    kid: String,
+    encoding_key: EncodingKey,
+    jwk: Value,
+}
+
+impl SigningKey {
+    fn generate(kid: &str) -> io::Result<Self> {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+        // Uncompressed point: 0x04 || x || y
+        let point = key_pair.public_key().as_ref();
+        Ok(Self {
+            kid: kid.to_string(),
+            encoding_key: EncodingKey::from_ec_der(pkcs8.as_ref()),
+            jwk: json!({
+                "kty": "EC",
+                "crv": "P-256",
+                "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                "y": URL_SAFE_NO_PAD.encode(&point[33..65]),
+                "kid": kid,
+                "alg": "ES256",
+                "use": "sig",
+            }),
+        })
+    }
+}
+
+struct Mock {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    key: SigningKey,
+    /// Same key ID as `key` but not published, for `MockError::BadSignature`
+    rogue_key: SigningKey,
+    state: Mutex<MockState>,
+}
+
+impl Mock {
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Take the first scripted error the predicate applies to
+    fn take_error(&self, applies: impl Fn(&MockError) -> bool) -> Option<MockError> {
+        let mut state = self.state();
+        let index = state.errors.iter().position(applies)?;
+        Some(state.errors.remove(index))
+    }
+}
+
+/// A running mock OIDC provider, stopped when dropped
+pub struct MockOidcProvider {
+    mock: Arc<Mock>,
+    server: JoinHandle<()>,
+}
+
+impl MockOidcProvider {
+    /// Start the provider for a client, on a random port of 127.0.0.1
+    pub async fn start(client_id: &str, client_secret: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let issuer = format!("http://{}", listener.local_addr()?);
+        let mock = Arc::new(Mock {
+            issuer,
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            key: SigningKey::generate("mock-key")?,
+            rogue_key: SigningKey::generate("mock-key")?,
+            state: Mutex::new(MockState::default()),
+        });
+
+        let router = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/authorize", get(authorize))
+            .route("/token", post(token))
+            .route("/userinfo", get(userinfo))
+            .route("/jwks", get(jwks))
+            .with_state(mock.clone());
+        let server = tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, router).await {
+                tracing::error!("Mock OIDC provider stopped: {}", e);
+            }
+        });
+
+        Ok(Self { mock, server })
+    }
+
+    /// Issuer URL, which the endpoints are discovered from
+    pub fn issuer(&self) -> &str {
+        &self.mock.issuer
+    }
+
+    /// Environment variables configuring an OIDC provider of this name to use the mock
+    pub fn env_vars(&self, name: &str) -> Vec<(String, String)> {
+        let prefix = format!("OAUTH2_{}", name.to_uppercase());
+        vec![
+            (format!("{}_CLIENT_ID", prefix), self.mock.client_id.clone()),
+            (
+                format!("{}_CLIENT_SECRET", prefix),
+                self.mock.client_secret.clone(),
+            ),
+            (format!("{}_ISSUER_URL", prefix), self.mock.issuer.clone()),
+            (
+                format!("{}_ID_TOKEN_SIGNING_ALGS", prefix),
+                "ES256".to_string(),
+            ),
+        ]
+    }
+
+    /// Add a user, signed in by default if it is the first one
+    pub fn add_user(&self, user: MockUser) {
+        let mut state = self.mock.state();
+        if state.current_user.is_none() {
+            state.current_user = Some(user.sub.clone());
+        }
+        state.users.retain(|u| u.sub != user.sub);
+        state.users.push(user);
+    }
+
+    /// Sign in the user with this subject unless the request has a matching `login_hint`
+    pub fn sign_in_as(&self, sub: &str) {
+        self.mock.state().current_user = Some(sub.to_string());
+    }
+
+    /// Fail the next request the error applies to, errors are used in the order added
+    pub fn fail_next(&self, error: MockError) {
+        self.mock.state().errors.push(error);
+    }
+}
+
+impl Drop for MockOidcProvider {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 24];
+    // SystemRandom only fails if the OS has no randomness, a test can't go on then anyway
+    let _ = SystemRandom::new().fill(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn oauth_error(status: StatusCode, error: &str) -> Response {
+    (status, Json(json!({ "error": error }))).into_response()
+}
+
+async fn discovery(State(mock): State<Arc<Mock>>) -> Json<Value> {
+    let issuer = &mock.issuer;
+    Json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "userinfo_endpoint": format!("{}/userinfo", issuer),
+        "jwks_uri": format!("{}/jwks", issuer),
+        "response_types_supported": ["code"],
+        "response_modes_supported": ["query", "form_post"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["ES256"],
+        "code_challenge_methods_supported": ["S256"],
+        "token_endpoint_auth_methods_supported": ["client_secret_post", "client_secret_basic"],
+    }))
+}
+
+async fn jwks(State(mock): State<Arc<Mock>>) -> Json<Value> {
+    Json(json!({ "keys": [mock.key.jwk] }))
+}
+
+/// Send the authorization response back to the client, as a redirect or a form post
+fn authorization_response(
+    redirect_uri: &str,
+    response_mode: Option<&str>,
+    params: &[(&str, &str)],
+) -> Response {
+    if response_mode == Some("form_post") {
+        let escape = |s: &str| {
+            s.replace('&', "&amp;")
+                .replace('"', "&quot;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+        };
+        let inputs: String = params
+            .iter()
+            .map(|(key, value)| {
+                format!(
+                    r#"<input type="hidden" name="{}" value="{}">"#,
+                    escape(key),
+                    escape(value)
+                )
+            })
+            .collect();
+        return Html(format!(
+            r#"<html><body onload="document.forms[0].submit()"><form method="post" action="{}">{}</form></body></html>"#,
+            escape(redirect_uri),
+            inputs
+        ))
+        .into_response();
+    }
+
+    let query = params
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, urlencoding::encode(value)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+    Redirect::to(&format!("{}{}{}", redirect_uri, separator, query)).into_response()
+}
+
+async fn authorize(
+    State(mock): State<Arc<Mock>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let param = |key: &str| params.get(key).map(String::as_str);
+    if param("client_id") != Some(mock.client_id.as_str()) {
+        return (StatusCode::BAD_REQUEST, "Unknown client_id").into_response();
+    }
+    let Some(redirect_uri) = param("redirect_uri") else {
+        return (StatusCode::BAD_REQUEST, "No redirect_uri").into_response();
+    };
+    let state = param("state").unwrap_or_default();
+    let response_mode = param("response_mode");
+
+    if let Some(MockError::Authorize(error)) =
+        mock.take_error(|e| matches!(e, MockError::Authorize(_)))
+    {
+        return authorization_response(
+            redirect_uri,
+            response_mode,
+            &[("error", &error), ("state", state)],
+        );
+    }
+
+    let sub = {
+        let state = mock.state();
+        param("login_hint")
+            .and_then(|hint| {
+                state
+                    .users
+                    .iter()
+                    .find(|u| u.sub == hint || u.email == hint)
+            })
+            .map(|user| user.sub.clone())
+            .or_else(|| state.current_user.clone())
+    };
+    let Some(sub) = sub else {
+        return authorization_response(
+            redirect_uri,
+            response_mode,
+            &[("error", "login_required"), ("state", state)],
+        );
+    };
+
+    let code = random_token();
+    mock.state().codes.insert(
+        code.clone(),
+        Grant {
+            redirect_uri: redirect_uri.to_string(),
+            nonce: param("nonce").map(String::from),
+            code_challenge: param("code_challenge").map(String::from),
+            sub,
+            scope: param("scope").unwrap_or("openid").to_string(),
+        },
+    );
+    authorization_response(
+        redirect_uri,
+        response_mode,
+        &[("code", &code), ("state", state)],
+    )
+}
+
+/// Client credentials from the Basic authorization header or the form
+fn client_credentials(
+    headers: &HeaderMap,
+    form: &HashMap<String, String>,
+) -> Option<(String, Option<String>)> {
+    let basic = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| STANDARD.decode(v).ok())
+        .and_then(|v| String::from_utf8(v).ok());
+    if let Some(basic) = basic {
+        let (id, secret) = basic.split_once(':')?;
+        let decode = |s: &str| urlencoding::decode(s).map(|s| s.into_owned()).ok();
+        return Some((decode(id)?, Some(decode(secret)?)));
+    }
+    Some((
+        form.get("client_id")?.clone(),
+        form.get("client_secret").cloned(),
+    ))
+}
+
+async fn token(
+    State(mock): State<Arc<Mock>>,
+    headers: HeaderMap,
+    Form(form): Form<HashMap<String, String>>,
+) -> Response {
+    match client_credentials(&headers, &form) {
+        Some((client_id, secret))
+            if client_id == mock.client_id
+                && secret
+                    .as_ref()
+                    .is_none_or(|secret| *secret == mock.client_secret) => {}
+        _ => return oauth_error(StatusCode::UNAUTHORIZED, "invalid_client"),
+    }
+
+    if let Some(MockError::Token(error)) = mock.take_error(|e| matches!(e, MockError::Token(_))) {
+        return oauth_error(StatusCode::BAD_REQUEST, &error);
+    }
+
+    let field = |key: &str| form.get(key).map(String::as_str);
+    let grant = match field("grant_type") {
+        Some("authorization_code") => {
+            let grant = field("code").and_then(|code| mock.state().codes.remove(code));
+            let Some(grant) = grant else {
+                return oauth_error(StatusCode::BAD_REQUEST, "invalid_grant");
+            };
+            if field("redirect_uri") != Some(grant.redirect_uri.as_str()) {
+                return oauth_error(StatusCode::BAD_REQUEST, "invalid_grant");
+            }
+            if let Some(challenge) = &grant.code_challenge {
+                let verified = field("code_verifier").is_some_and(|verifier| {
+                    URL_SAFE_NO_PAD.encode(digest(&SHA256, verifier.as_bytes())) == *challenge
+                });
+                if !verified {
+                    return oauth_error(StatusCode::BAD_REQUEST, "invalid_grant");
+                }
+            }
+            grant
+        }
+        Some("refresh_token") => {
+            let grant = field("refresh_token")
+                .and_then(|token| mock.state().refresh_tokens.get(token).cloned());
+            match grant {
+                // Only the first ID token carries the nonce
+                Some(grant) => Grant {
+                    nonce: None,
+                    ..grant
+                },
+                None => return oauth_error(StatusCode::BAD_REQUEST, "invalid_grant"),
+            }
+        }
+        _ => return oauth_error(StatusCode::BAD_REQUEST, "unsupported_grant_type"),
+    };
+
+    let user = mock
+        .state()
+        .users
+        .iter()
+        .find(|u| u.sub == grant.sub)
+        .cloned();
+    let Some(user) = user else {
+        return oauth_error(StatusCode::BAD_REQUEST, "invalid_grant");
+    };
+
+    let id_token = match sign_id_token(&mock, &user, &grant) {
+        Ok(id_token) => id_token,
+        Err(e) => {
+            tracing::error!("Failed to sign mock ID token: {}", e);
+            return oauth_error(StatusCode::INTERNAL_SERVER_ERROR, "server_error");
+        }
+    };
+    let access_token = random_token();
+    let refresh_token = random_token();
+    {
+        let mut state = mock.state();
+        state
+            .access_tokens
+            .insert(access_token.clone(), user.sub.clone());
+        state
+            .refresh_tokens
+            .insert(refresh_token.clone(), grant.clone());
+    }
+
+    Json(json!({
+        "access_token": access_token,
+        "token_type": "Bearer",
+        "expires_in": TOKEN_LIFETIME,
+        "refresh_token": refresh_token,
+        "scope": grant.scope.replace('+', " "),
+        "id_token": id_token,
+    }))
+    .into_response()
+}
+
+fn sign_id_token(
+    mock: &Mock,
+    user: &MockUser,
+    grant: &Grant,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let is_id_token_error = |e: &MockError| {
+        matches!(
+            e,
+            MockError::ExpiredIdToken
+                | MockError::WrongAudience
+                | MockError::WrongNonce
+                | MockError::BadSignature
+        )
+    };
+    let error = mock.take_error(is_id_token_error);
+
+    let now = now();
+    let (iat, exp) = match error {
+        Some(MockError::ExpiredIdToken) => (now - 2 * TOKEN_LIFETIME, now - TOKEN_LIFETIME),
+        _ => (now, now + TOKEN_LIFETIME),
+    };
+    let mut claims = user.to_claims();
+    claims.insert("iss".to_string(), json!(mock.issuer));
+    claims.insert("iat".to_string(), json!(iat));
+    claims.insert("exp".to_string(), json!(exp));
+    claims.insert("auth_time".to_string(), json!(iat));
+    let aud = match error {
+        Some(MockError::WrongAudience) => "another-client",
+        _ => mock.client_id.as_str(),
+    };
+    claims.insert("aud".to_string(), json!(aud));
+    if let Some(nonce) = &grant.nonce {
+        let nonce = match error {
+            Some(MockError::WrongNonce) => "another-nonce",
+            _ => nonce.as_str(),
+        };
+        claims.insert("nonce".to_string(), json!(nonce));
+    }
+
+    let key = match error {
+        Some(MockError::BadSignature) => &mock.rogue_key,
+        _ => &mock.key,
+    };
+    let header = Header {
+        kid: Some(key.kid.clone()),
+        ..Header::new(Algorithm::ES256)
+    };
+    jsonwebtoken::encode(&header, &claims, &key.encoding_key)
+}
+
+async fn userinfo(State(mock): State<Arc<Mock>>, headers: HeaderMap) -> Response {
+    let sub = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .and_then(|token| mock.state().access_tokens.get(token).cloned());
+    let user = sub.and_then(|sub| {
+        mock.state()
+            .users
+            .iter()
+            .find(|u| u.sub == sub)
+            .map(MockUser::to_claims)
+    });
+    match user {
+        Some(claims) => Json(Value::Object(claims)).into_response(),
+        None => oauth_error(StatusCode::UNAUTHORIZED, "invalid_token"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{O2P_REDIRECT_ANON, O2P_REDIRECT_USER, oauth2_passkey_router};
+    use axum::{
+        body::{Body, to_bytes},
+        http::{
+            Request,
+            header::{COOKIE, LOCATION, REFERER, SET_COOKIE, USER_AGENT},
+        },
+    };
+    use oauth2_passkey::O2P_ROUTE_PREFIX;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tower::ServiceExt;
+
+    const ORIGIN: &str = "https://example.com";
+
+    /// Configure the library for the mock, before anything reads the environment
+    async fn init(mock: &MockOidcProvider) {
+        let env = [
+            ("ORIGIN", ORIGIN),
+            ("GENERIC_DATA_STORE_TYPE", "sqlite"),
+            (
+                "GENERIC_DATA_STORE_URL",
+                "sqlite:file:o2p_axum_test?mode=memory&cache=shared",
+            ),
+            ("GENERIC_CACHE_STORE_TYPE", "memory"),
+            ("GENERIC_CACHE_STORE_URL", "memory"),
+            ("OAUTH2_PROVIDERS", "mock"),
+            ("OAUTH2_RESPONSE_MODE", "query"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+        for (key, value) in env.into_iter().chain(mock.env_vars("mock")) {
+            // SAFETY: Set before any test of this binary reads these variables
+            unsafe { std::env::set_var(key, value) };
+        }
+        oauth2_passkey::init().await.unwrap();
+    }
+
+    /// Cookies the browser keeps, from the responses so far
+    #[derive(Default)]
+    struct CookieJar(HashMap<String, String>);
+
+    impl CookieJar {
+        fn store(&mut self, headers: &HeaderMap) {
+            for cookie in headers.get_all(SET_COOKIE) {
+                let cookie = cookie.to_str().unwrap();
+                let (pair, attributes) = cookie.split_once(';').unwrap_or((cookie, ""));
+                let (name, value) = pair.split_once('=').unwrap();
+                if attributes.contains("Max-Age=-") || attributes.contains("Max-Age=0;") {
+                    self.0.remove(name);
+                } else {
+                    self.0.insert(name.to_string(), value.to_string());
+                }
+            }
+        }
+
+        fn header(&self) -> String {
+            self.0
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join("; ")
+        }
+    }
+
+    /// Request a path of the app like a browser, returning the status, headers and body
+    async fn browse(
+        app: &Router,
+        jar: &mut CookieJar,
+        uri: &str,
+        referer: Option<&str>,
+    ) -> (StatusCode, HeaderMap, String) {
+        let mut request = Request::get(uri)
+            .header(USER_AGENT, "test-browser")
+            .header(COOKIE, jar.header());
+        if let Some(referer) = referer {
+            request = request.header(REFERER, referer);
+        }
+        let response = app
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let (parts, body) = response.into_parts();
+        jar.store(&parts.headers);
+        let body = to_bytes(body, usize::MAX).await.unwrap();
+        (
+            parts.status,
+            parts.headers,
+            String::from_utf8_lossy(&body).to_string(),
+        )
+    }
+
+    fn location(headers: &HeaderMap) -> &str {
+        headers[LOCATION].to_str().unwrap()
+    }
+
+    /// Follow the authorization URL to the mock, returning where it redirects back to
+    async fn authorize(auth_url: &str) -> String {
+        let (host, path) = auth_url
+            .strip_prefix("http://")
+            .and_then(|url| url.split_once('/'))
+            .unwrap();
+        let mut stream = tokio::net::TcpStream::connect(host).await.unwrap();
+        let request = format!(
+            "GET /{} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, host
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+            .lines()
+            .find_map(|line| line.strip_prefix("location: "))
+            .expect("No redirect from the authorize endpoint")
+            .to_string()
+    }
+
+    /// Start a sign-in at the app, through the mock and back to the callback
+    async fn sign_in(
+        app: &Router,
+        jar: &mut CookieJar,
+        mock: &MockOidcProvider,
+    ) -> (StatusCode, HeaderMap) {
+        let prefix = O2P_ROUTE_PREFIX.as_str();
+        let start = format!("{}/oauth2/mock?callback=redirect", prefix);
+        let (status, headers, _) = browse(app, jar, &start, None).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert!(location(&headers).starts_with(&format!("{}/authorize?", mock.issuer())));
+
+        let callback = authorize(location(&headers)).await;
+        let callback = callback
+            .strip_prefix(ORIGIN)
+            .expect("Redirected to another origin");
+        assert!(callback.starts_with(&format!("{}/oauth2/authorized?", prefix)));
+        let referer = format!("{}/", mock.issuer());
+        let (status, headers, _) = browse(app, jar, callback, Some(&referer)).await;
+        (status, headers)
+    }
+
+    #[tokio::test]
+    async fn test_authorization_code_flow() {
+        let mock = MockOidcProvider::start("mock-client", "mock-secret")
+            .await
+            .unwrap();
+        mock.add_user(MockUser::new("alice", "alice@example.com"));
+        init(&mock).await;
+        let app = Router::new().nest(O2P_ROUTE_PREFIX.as_str(), oauth2_passkey_router());
+        let mut jar = CookieJar::default();
+
+        // The user cancels at the provider
+        mock.fail_next(MockError::Authorize("access_denied".to_string()));
+        let (status, headers) = sign_in(&app, &mut jar, &mock).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert_eq!(
+            location(&headers),
+            format!(
+                "{}?status=403&message=Sign-in%20was%20cancelled",
+                O2P_REDIRECT_ANON.as_str()
+            )
+        );
+
+        // The code is exchanged, the ID token verified and the user created and signed in
+        let (status, headers) = sign_in(&app, &mut jar, &mock).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert_eq!(location(&headers), O2P_REDIRECT_USER.as_str());
+
+        let accounts = format!("{}/oauth2/accounts", O2P_ROUTE_PREFIX.as_str());
+        let (status, _, body) = browse(&app, &mut jar, &accounts, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let accounts: Vec<Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0]["provider"], "mock");
+        assert_eq!(accounts[0]["provider_user_id"], "mock_alice");
+        assert_eq!(accounts[0]["email"], "alice@example.com");
+    }
+}
@@ -1,43 +1,60 @@
 use askama::Template;
 use axum::{
     Json, Router,
-    extract::{Form, Path, Query},
-    http::{HeaderMap, StatusCode, header::CONTENT_TYPE},
-    response::{Html, Redirect, Response},
+    extract::{Form, FromRequest, Path, Query, Request},
+    http::{
+        HeaderMap, HeaderName, StatusCode,
+        header::{CACHE_CONTROL, CONTENT_TYPE},
+    },
+    response::{Html, IntoResponse, Redirect, Response},
     routing::delete,
     routing::get,
+    routing::post,
 };
 use axum_extra::{TypedHeader, headers};
+use serde::Deserialize;
 use std::collections::HashMap;
 
 use oauth2_passkey::{
-    AuthResponse, O2P_ROUTE_PREFIX, OAuth2Account, SessionUser, delete_oauth2_account_core,
-    get_authorized_core, list_accounts_core, post_authorized_core, prepare_logout_response,
-    prepare_oauth2_auth_request, verify_context_token_and_page,
+    AuthRequestOptions, AuthResponse, CallbackMode, CoordinationError, IdTokenCheck,
+    IdTokenRequest, O2P_ROUTE_PREFIX, OAuth2Account, OAuth2AuthRequest, OAuth2Error, Prompt,
+    SessionUser, backchannel_logout_core, confirm_pending_link_core, delete_oauth2_account_core,
+    get_authorized_core, get_callback_mode, get_oauth2_client_jwks, get_return_to,
+    id_token_nonce_core, id_token_sign_in_core, list_accounts_core, logout_core,
+    post_authorized_core, prepare_oauth2_auth_request, verify_context_token_and_page,
 };
 
-use super::config::O2P_REDIRECT_ANON;
+use super::config::{O2P_REDIRECT_ANON, O2P_REDIRECT_APP, O2P_REDIRECT_USER};
 use super::error::IntoResponseError;
 use super::session::AuthUser;
 
 pub fn router() -> Router {
     Router::new()
         .route("/oauth2.js", get(serve_oauth2_js))
-        .route("/google", get(google_auth))
         .route("/authorized", get(get_authorized).post(post_authorized))
         .route("/popup_close", get(popup_close))
         .route("/logout", get(logout))
+        .route("/jwks.json", get(client_jwks))
         .route("/accounts", get(list_oauth2_accounts))
+        .route("/link/confirm", post(confirm_pending_link))
         .route(
             "/accounts/{provider}/{provider_user_id}",
             delete(delete_oauth2_account),
         )
+        // Static routes above take precedence over the provider route
+        .route("/{provider}", get(provider_auth))
+        .route("/{provider}/start", get(provider_auth_start))
+        .route("/{provider}/backchannel_logout", post(backchannel_logout))
+        .route("/{provider}/id_token", post(id_token_sign_in))
+        .route("/{provider}/id_token/nonce", get(id_token_nonce))
 }
 
 #[derive(Template)]
 #[template(path = "popup_close.j2")]
 struct PopupCloseTemplate {
     message: String,
+    /// Keeps the popup open so the user can read why the sign-in failed
+    failed: bool,
 }
 
 pub(crate) async fn popup_close(
@@ -47,7 +64,8 @@ pub(crate) async fn popup_close(
         .get("message")
         .cloned()
         .unwrap_or_else(|| "Authentication completed".to_string());
-    let template = PopupCloseTemplate { message };
+    let failed = params.contains_key("status");
+    let template = PopupCloseTemplate { message, failed };
     let html = Html(
         template
             .render()
@@ -56,6 +74,11 @@ pub(crate) async fn popup_close(
     Ok(html)
 }
 
+/// Public keys of the client, for providers verifying `private_key_jwt` assertions
+async fn client_jwks() -> Json<serde_json::Value> {
+    Json(get_oauth2_client_jwks())
+}
+
 pub(crate) async fn serve_oauth2_js() -> Result<Response, (StatusCode, String)> {
     let js_content = include_str!("../static/oauth2.js");
     Response::builder()
@@ -65,11 +88,41 @@ pub(crate) async fn serve_oauth2_js() -> Result<Response, (StatusCode, String)>
         .into_response_error()
 }
 
-pub(crate) async fn google_auth(
+/// Start the authorization flow with the provider named in the path, e.g. /oauth2/google
+///
+/// The `callback` query parameter selects the callback mode: "popup" (default),
+/// "redirect" or "app". `prompt`, `login_hint`, `scope` (space separated scopes requested
+/// in addition to the configured ones, each one of the provider's `EXTRA_SCOPES`) and
+/// `include_granted_scopes=true` are passed on to the provider. `return_to` is where the "redirect" and "app" callbacks send the user on
+/// success, a path or a URL of an allowed origin.
+pub(crate) async fn provider_auth(
     auth_user: Option<AuthUser>,
     headers: HeaderMap,
+    Path(provider): Path<String>,
     Query(params): Query<HashMap<String, String>>,
 ) -> Result<(HeaderMap, Redirect), (StatusCode, String)> {
+    let (auth_request, headers) = start_auth(auth_user, headers, &provider, &params).await?;
+    Ok((headers, Redirect::to(&auth_request.auth_url)))
+}
+
+/// Start the authorization flow and return `{auth_url, state}` as JSON instead of
+/// redirecting, for SPAs and mobile apps opening the URL themselves
+pub(crate) async fn provider_auth_start(
+    auth_user: Option<AuthUser>,
+    headers: HeaderMap,
+    Path(provider): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<(HeaderMap, Json<OAuth2AuthRequest>), (StatusCode, String)> {
+    let (auth_request, headers) = start_auth(auth_user, headers, &provider, &params).await?;
+    Ok((headers, Json(auth_request)))
+}
+
+async fn start_auth(
+    auth_user: Option<AuthUser>,
+    headers: HeaderMap,
+    provider: &str,
+    params: &HashMap<String, String>,
+) -> Result<(OAuth2AuthRequest, HeaderMap), (StatusCode, String)> {
     let mode = params.get("mode").cloned();
     let context = params.get("context").cloned();
 
@@ -86,20 +139,177 @@ pub(crate) async fn google_auth(
             .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
     }
 
-    let (auth_url, headers) = prepare_oauth2_auth_request(headers)
+    let callback_mode = params
+        .get("callback")
+        .map(|callback| callback.parse::<CallbackMode>())
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+        .unwrap_or_default();
+    if callback_mode == CallbackMode::App && O2P_REDIRECT_APP.is_none() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "O2P_REDIRECT_APP is not configured".to_string(),
+        ));
+    }
+    let prompt = params
+        .get("prompt")
+        .map(|prompt| prompt.parse::<Prompt>())
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let options = AuthRequestOptions {
+        callback_mode,
+        prompt,
+        login_hint: params.get("login_hint").cloned(),
+        scopes: params
+            .get("scope")
+            .map(|scope| scope.split_whitespace().map(String::from).collect())
+            .unwrap_or_default(),
+        include_granted_scopes: params
+            .get("include_granted_scopes")
+            .is_some_and(|value| value == "true"),
+        return_to: params.get("return_to").cloned(),
+    };
+
+    prepare_oauth2_auth_request(headers, provider, &options)
         .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        .map_err(|e| match e {
+            OAuth2Error::UnsupportedProvider(_) => (StatusCode::NOT_FOUND, e.to_string()),
+            OAuth2Error::InvalidRequest(_) => (StatusCode::BAD_REQUEST, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })
+}
 
-    Ok((headers, Redirect::to(&auth_url)))
+/// Send the browser where the callback mode of the flow expects the result
+///
+/// The popup leaves `return_to` to the page that opened it, which is reloaded.
+fn callback_response(
+    callback_mode: CallbackMode,
+    return_to: Option<String>,
+    result: Result<(HeaderMap, String), (StatusCode, String)>,
+) -> Result<(HeaderMap, Redirect), (StatusCode, String)> {
+    match (callback_mode, result) {
+        (CallbackMode::Popup, Ok((headers, message))) => Ok((
+            headers,
+            Redirect::to(&format!(
+                "{}/oauth2/popup_close?message={}",
+                O2P_ROUTE_PREFIX.as_str(),
+                urlencoding::encode(&message)
+            )),
+        )),
+        (CallbackMode::Popup, Err((status, message))) => Ok((
+            HeaderMap::new(),
+            Redirect::to(&with_query(
+                &format!("{}/oauth2/popup_close", O2P_ROUTE_PREFIX.as_str()),
+                &[("status", status.as_str()), ("message", &message)],
+            )),
+        )),
+        (CallbackMode::Redirect, Ok((headers, _))) => Ok((
+            headers,
+            Redirect::to(return_to.as_deref().unwrap_or(O2P_REDIRECT_USER.as_str())),
+        )),
+        (CallbackMode::Redirect, Err((status, message))) => Ok((
+            HeaderMap::new(),
+            Redirect::to(&with_query(
+                O2P_REDIRECT_ANON.as_str(),
+                &[("status", status.as_str()), ("message", &message)],
+            )),
+        )),
+        (CallbackMode::App, result) => {
+            let app_url = O2P_REDIRECT_APP.as_deref().ok_or((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "O2P_REDIRECT_APP is not configured".to_string(),
+            ))?;
+            let (headers, status, message) = match result {
+                Ok((headers, message)) => (headers, StatusCode::OK, message),
+                Err((status, message)) => (HeaderMap::new(), status, message),
+            };
+            let result = if status.is_success() {
+                "success"
+            } else {
+                "error"
+            };
+            let mut params = vec![
+                ("result", result),
+                ("status", status.as_str()),
+                ("message", &message),
+            ];
+            if let Some(return_to) = return_to.as_deref().filter(|_| status.is_success()) {
+                params.push(("return_to", return_to));
+            }
+            Ok((headers, Redirect::to(&with_query(app_url, &params))))
+        }
+    }
+}
+
+/// Map the result of a callback to a response error, with a message for the user when the
+/// provider sent an error instead of a code, e.g. after the user cancelled at the provider
+fn callback_result(
+    result: Result<(HeaderMap, String), CoordinationError>,
+) -> Result<(HeaderMap, String), (StatusCode, String)> {
+    let provider_error = match &result {
+        Err(CoordinationError::OAuth2Error(e)) => match e {
+            OAuth2Error::AccessDenied(_) => Some((StatusCode::FORBIDDEN, "Sign-in was cancelled")),
+            OAuth2Error::InteractionRequired(_) => Some((
+                StatusCode::UNAUTHORIZED,
+                "Sign-in at the provider is required",
+            )),
+            OAuth2Error::AuthorizationError(_) => Some((
+                StatusCode::BAD_REQUEST,
+                "The provider could not complete the sign-in",
+            )),
+            _ => None,
+        },
+        _ => None,
+    };
+    match provider_error {
+        Some((status, message)) => Err((status, message.to_string())),
+        None => result.into_response_error(),
+    }
+}
+
+pub(crate) fn with_query(url: &str, params: &[(&str, &str)]) -> String {
+    let query = params
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, urlencoding::encode(value)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", url, separator, query)
 }
 
+/// Log out, continuing to the provider's end_session_endpoint if the user signed in with
+/// a provider that has RP-initiated logout enabled
 pub async fn logout(
     TypedHeader(cookies): TypedHeader<headers::Cookie>,
 ) -> Result<(HeaderMap, Redirect), (StatusCode, String)> {
-    let headers = prepare_logout_response(cookies)
+    let (headers, end_session_url) = logout_core(cookies)
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    Ok((headers, Redirect::to(O2P_REDIRECT_ANON.as_str())))
+    let redirect_url = end_session_url.unwrap_or_else(|| O2P_REDIRECT_ANON.to_string());
+    Ok((headers, Redirect::to(&redirect_url)))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct BackchannelLogoutRequest {
+    logout_token: String,
+}
+
+/// Back-channel logout endpoint of a provider, e.g. /oauth2/keycloak/backchannel_logout
+///
+/// Responds 200 once the sessions are deleted and 400 if the logout failed
+/// (OIDC Back-Channel Logout 1.0, 2.8).
+pub(crate) async fn backchannel_logout(
+    Path(provider): Path<String>,
+    Form(request): Form<BackchannelLogoutRequest>,
+) -> (StatusCode, [(HeaderName, &'static str); 1], String) {
+    let no_store = [(CACHE_CONTROL, "no-store")];
+    match backchannel_logout_core(&provider, &request.logout_token).await {
+        Ok(()) => (StatusCode::OK, no_store, String::new()),
+        Err(e) => {
+            tracing::warn!("Back-channel logout from {} failed: {}", provider, e);
+            (StatusCode::BAD_REQUEST, no_store, e.to_string())
+        }
+    }
 }
 
 pub async fn get_authorized(
@@ -107,18 +317,13 @@ pub async fn get_authorized(
     TypedHeader(cookies): TypedHeader<headers::Cookie>,
     headers: HeaderMap,
 ) -> Result<(HeaderMap, Redirect), (StatusCode, String)> {
-    let (headers, message) = get_authorized_core(&query, &cookies, &headers)
-        .await
-        .into_response_error()?;
+    let result = callback_result(get_authorized_core(&query, &cookies, &headers).await);
 
-    Ok((
-        headers,
-        Redirect::to(&format!(
-            "{}/oauth2/popup_close?message={}",
-            O2P_ROUTE_PREFIX.as_str(),
-            urlencoding::encode(&message)
-        )),
-    ))
+    callback_response(
+        get_callback_mode(&query.state),
+        get_return_to(&query.state),
+        result,
+    )
 }
 
 /// Handler for OAuth2 callbacks using form_post response mode.
@@ -134,20 +339,77 @@ pub async fn post_authorized(
     headers: HeaderMap,
     Form(form): Form<AuthResponse>,
 ) -> Result<(HeaderMap, Redirect), (StatusCode, String)> {
-    let (headers, message) = post_authorized_core(&form, &headers)
-        .await
-        .into_response_error()?;
+    let result = callback_result(post_authorized_core(&form, &headers).await);
 
+    callback_response(
+        get_callback_mode(&form.state),
+        get_return_to(&form.state),
+        result,
+    )
+}
+
+/// Nonce for a native app to request an ID token with, e.g. GET /oauth2/google/id_token/nonce
+pub(crate) async fn id_token_nonce(
+    Path(provider): Path<String>,
+) -> Result<([(HeaderName, &'static str); 1], Json<serde_json::Value>), (StatusCode, String)> {
+    let nonce = id_token_nonce_core(&provider).await.into_response_error()?;
     Ok((
-        headers,
-        Redirect::to(&format!(
-            "{}/oauth2/popup_close?message={}",
-            O2P_ROUTE_PREFIX.as_str(),
-            urlencoding::encode(&message)
-        )),
+        [(CACHE_CONTROL, "no-store")],
+        Json(serde_json::json!({ "nonce": nonce })),
     ))
 }
 
+/// Sign in with an ID token the client obtained itself, e.g. POST /oauth2/google/id_token
+///
+/// Google One Tap posts a form with `credential` and `g_csrf_token`, and the browser is
+/// redirected like at the end of the authorization code flow. Native apps post JSON with
+/// `id_token` and the `nonce` they obtained it with, and get the session cookie with a
+/// JSON response.
+pub(crate) async fn id_token_sign_in(
+    Path(provider): Path<String>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
+    request: Request,
+) -> Result<Response, (StatusCode, String)> {
+    let is_json = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+
+    if is_json {
+        let Json(id_token) = Json::<IdTokenRequest>::from_request(request, &())
+            .await
+            .map_err(|e| (e.status(), e.body_text()))?;
+        let (headers, message) = id_token_sign_in_core(&provider, &id_token, IdTokenCheck::Nonce)
+            .await
+            .into_response_error()?;
+        return Ok((headers, Json(serde_json::json!({ "message": message }))).into_response());
+    }
+
+    let Form(id_token) = Form::<IdTokenRequest>::from_request(request, &())
+        .await
+        .map_err(|e| (e.status(), e.body_text()))?;
+    let Some(TypedHeader(cookies)) = cookies else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "No g_csrf_token cookie".to_string(),
+        ));
+    };
+    let check = IdTokenCheck::DoubleSubmit(&cookies);
+    let response = match id_token_sign_in_core(&provider, &id_token, check)
+        .await
+        .into_response_error()
+    {
+        Ok((headers, _)) => (headers, Redirect::to(O2P_REDIRECT_USER.as_str())).into_response(),
+        Err((status, message)) => Redirect::to(&with_query(
+            O2P_REDIRECT_ANON.as_str(),
+            &[("status", status.as_str()), ("message", &message)],
+        ))
+        .into_response(),
+    };
+    Ok(response)
+}
+
 pub async fn list_oauth2_accounts(
     auth_user: Option<AuthUser>,
 ) -> Result<Json<Vec<OAuth2Account>>, (StatusCode, String)> {
@@ -161,18 +423,167 @@ pub async fn list_oauth2_accounts(
     Ok(Json(accounts))
 }
 
+/// Link the account of a sign-in that matched the user's verified email address
+///
+/// The user has to be signed in to the existing user, the account is identified by the
+/// cookie set at the sign-in.
+pub async fn confirm_pending_link(
+    auth_user: Option<AuthUser>,
+    TypedHeader(cookies): TypedHeader<headers::Cookie>,
+) -> Result<(HeaderMap, String), (StatusCode, String)> {
+    let session_user = auth_user.as_ref().map(|u| u as &SessionUser);
+
+    confirm_pending_link_core(session_user, &cookies)
+        .await
+        .into_response_error()
+}
+
 /// Delete an OAuth2 account for the authenticated user
 ///
 /// This endpoint requires authentication and verifies that the account
-/// belongs to the authenticated user before deleting it.
+/// belongs to the authenticated user before deleting it. A warning is returned
+/// in the body if the grant couldn't be revoked at the provider.
 pub async fn delete_oauth2_account(
     auth_user: Option<AuthUser>,
     Path((provider, provider_user_id)): Path<(String, String)>,
-) -> Result<StatusCode, (StatusCode, String)> {
+) -> Result<Response, (StatusCode, String)> {
     let session_user = auth_user.as_ref().map(|u| u as &SessionUser);
 
     delete_oauth2_account_core(session_user, &provider, &provider_user_id)
         .await
-        .map(|()| StatusCode::NO_CONTENT)
+        .map(|warning| match warning {
+            Some(warning) => Json(serde_json::json!({ "warning": warning })).into_response(),
+            None => StatusCode::NO_CONTENT.into_response(),
+        })
         .into_response_error()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header::LOCATION;
+    use std::sync::Once;
+
+    static INIT_ENV: Once = Once::new();
+
+    fn init_test_env() {
+        INIT_ENV.call_once(|| {
+            // SAFETY: Set once before any test reads the environment
+            unsafe { std::env::set_var("O2P_REDIRECT_APP", "myapp://oauth2") };
+        });
+    }
+
+    fn location(response: Result<(HeaderMap, Redirect), (StatusCode, String)>) -> String {
+        let (_, redirect) = response.unwrap();
+        let response = redirect.into_response();
+        response.headers()[LOCATION].to_str().unwrap().to_string()
+    }
+
+    fn provider_error(error: OAuth2Error) -> Result<(HeaderMap, String), (StatusCode, String)> {
+        callback_result(Err(CoordinationError::OAuth2Error(error)))
+    }
+
+    #[test]
+    fn test_callback_result() {
+        assert_eq!(
+            provider_error(OAuth2Error::AccessDenied("access_denied".to_string())),
+            Err((StatusCode::FORBIDDEN, "Sign-in was cancelled".to_string()))
+        );
+        // The error of a prompt=none request when the user isn't signed in at the provider
+        assert_eq!(
+            provider_error(OAuth2Error::InteractionRequired(
+                "login_required: User not signed in".to_string()
+            )),
+            Err((
+                StatusCode::UNAUTHORIZED,
+                "Sign-in at the provider is required".to_string()
+            ))
+        );
+        assert_eq!(
+            provider_error(OAuth2Error::AuthorizationError("server_error".to_string())),
+            Err((
+                StatusCode::BAD_REQUEST,
+                "The provider could not complete the sign-in".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_callback_response_error() {
+        init_test_env();
+        let login_required = || {
+            provider_error(OAuth2Error::InteractionRequired(
+                "login_required".to_string(),
+            ))
+        };
+        let message = "Sign-in%20at%20the%20provider%20is%20required";
+
+        assert_eq!(
+            location(callback_response(
+                CallbackMode::Popup,
+                None,
+                login_required()
+            )),
+            format!(
+                "{}/oauth2/popup_close?status=401&message={}",
+                O2P_ROUTE_PREFIX.as_str(),
+                message
+            )
+        );
+        assert_eq!(
+            location(callback_response(
+                CallbackMode::Redirect,
+                Some("/page".to_string()),
+                login_required()
+            )),
+            format!(
+                "{}?status=401&message={}",
+                O2P_REDIRECT_ANON.as_str(),
+                message
+            )
+        );
+        // The app isn't sent to return_to after an error
+        assert_eq!(
+            location(callback_response(
+                CallbackMode::App,
+                Some("/page".to_string()),
+                login_required()
+            )),
+            format!("myapp://oauth2?result=error&status=401&message={}", message)
+        );
+    }
+
+    #[test]
+    fn test_callback_response_success() {
+        init_test_env();
+        let signed_in = || Ok((HeaderMap::new(), "Signed in".to_string()));
+
+        assert_eq!(
+            location(callback_response(CallbackMode::Popup, None, signed_in())),
+            format!(
+                "{}/oauth2/popup_close?message=Signed%20in",
+                O2P_ROUTE_PREFIX.as_str()
+            )
+        );
+        assert_eq!(
+            location(callback_response(
+                CallbackMode::Redirect,
+                Some("/page".to_string()),
+                signed_in()
+            )),
+            "/page"
+        );
+        assert_eq!(
+            location(callback_response(CallbackMode::Redirect, None, signed_in())),
+            O2P_REDIRECT_USER.as_str()
+        );
+        assert_eq!(
+            location(callback_response(
+                CallbackMode::App,
+                Some("/page".to_string()),
+                signed_in()
+            )),
+            "myapp://oauth2?result=success&status=200&message=Signed%20in&return_to=%2Fpage"
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use askama::Template;
+use axum::{
+    Json, Router,
+    extract::{Form, Query},
+    http::{
+        HeaderMap, HeaderValue, StatusCode,
+        header::{AUTHORIZATION, CACHE_CONTROL, PRAGMA, WWW_AUTHENTICATE},
+    },
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+};
+use axum_extra::{TypedHeader, headers};
+use serde_json::{Value, json};
+
+use oauth2_passkey::{
+    O2P_ROUTE_PREFIX, OidcAuthorizeRequest, OidcAuthorizeResponse, OidcConsentForm,
+    OidcProviderError, OidcTokenRequest, SessionUser, get_oidc_provider_jwks,
+    get_oidc_provider_metadata, oidc_authorize_core, oidc_consent_core, oidc_token_core,
+    oidc_userinfo_core, prepare_logout_response,
+};
+
+use super::config::O2P_REDIRECT_ANON;
+use super::session::{AuthUser, login_url};
+
+pub(super) fn router() -> Router {
+    Router::new()
+        .route(
+            "/.well-known/openid-configuration",
+            get(openid_configuration),
+        )
+        .route("/jwks.json", get(jwks))
+        .route("/authorize", get(authorize_get).post(authorize_post))
+        .route("/consent", post(consent))
+        .route("/token", post(token))
+        .route("/userinfo", get(userinfo).post(userinfo))
+}
+
+#[derive(Template)]
+#[template(path = "op_consent.j2")]
+struct ConsentTemplate<'a> {
+    o2p_route_prefix: &'a str,
+    user_label: &'a str,
+    client_name: &'a str,
+    scopes: Vec<String>,
+    consent_id: &'a str,
+}
+
+async fn openid_configuration() -> Json<Value> {
+    Json(get_oidc_provider_metadata())
+}
+
+async fn jwks() -> Json<Value> {
+    Json(get_oidc_provider_jwks())
+}
+
+async fn authorize_get(
+    auth_user: Option<AuthUser>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
+    Query(request): Query<OidcAuthorizeRequest>,
+) -> Result<Response, (StatusCode, String)> {
+    authorize(auth_user, cookies, request).await
+}
+
+async fn authorize_post(
+    auth_user: Option<AuthUser>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
+    Form(request): Form<OidcAuthorizeRequest>,
+) -> Result<Response, (StatusCode, String)> {
+    authorize(auth_user, cookies, request).await
+}
+
+/// Authorization endpoint, for users signed in with the existing session
+///
+/// Errors are shown to the user until the client and its redirect URI are validated,
+/// and sent to the client afterwards.
+async fn authorize(
+    auth_user: Option<AuthUser>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
+    request: OidcAuthorizeRequest,
+) -> Result<Response, (StatusCode, String)> {
+    let session_user = auth_user.as_ref().map(|u| u as &SessionUser);
+    let response = oidc_authorize_core(&request, session_user)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    match response {
+        OidcAuthorizeResponse::Redirect(url) => Ok(Redirect::to(&url).into_response()),
+        OidcAuthorizeResponse::Login {
+            return_to,
+            reauthenticate,
+        } => {
+            let redirect = Redirect::to(&login_url(O2P_REDIRECT_ANON.as_str(), Some(&return_to)));
+            // The login page sends signed-in users straight back, so the session ends first
+            match cookies {
+                Some(TypedHeader(cookies)) if reauthenticate => {
+                    let headers = prepare_logout_response(cookies)
+                        .await
+                        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+                    Ok((headers, redirect).into_response())
+                }
+                _ => Ok(redirect.into_response()),
+            }
+        }
+        OidcAuthorizeResponse::Consent(page) => {
+            let template = ConsentTemplate {
+                o2p_route_prefix: O2P_ROUTE_PREFIX.as_str(),
+                user_label: auth_user.as_ref().map_or("", |u| u.label.as_str()),
+                client_name: &page.client_name,
+                scopes: page.scopes,
+                consent_id: &page.consent_id,
+            };
+            let html = Html(
+                template
+                    .render()
+                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?,
+            );
+            Ok(html.into_response())
+        }
+    }
+}
+
+/// Decision of the consent page, sending the user back to the client
+async fn consent(
+    auth_user: AuthUser,
+    Form(form): Form<OidcConsentForm>,
+) -> Result<Redirect, (StatusCode, String)> {
+    let url = oidc_consent_core(&form, &auth_user)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok(Redirect::to(&url))
+}
+
+/// Token endpoint, exchanging an authorization code for an ID token and an access token
+async fn token(headers: HeaderMap, Form(request): Form<OidcTokenRequest>) -> Response {
+    let authorization = headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok());
+    let no_store = [
+        (CACHE_CONTROL, HeaderValue::from_static("no-store")),
+        (PRAGMA, HeaderValue::from_static("no-cache")),
+    ];
+
+    match oidc_token_core(authorization, &request).await {
+        Ok(response) => (no_store, Json(response)).into_response(),
+        Err(e) => {
+            tracing::info!("OIDC token request refused: {}", e);
+            let status = match e {
+                OidcProviderError::InvalidClient(_) => StatusCode::UNAUTHORIZED,
+                _ if error_is_internal(&e) => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            let mut response = (status, no_store, Json(error_body(&e))).into_response();
+            // Clients using client_secret_basic get a challenge (RFC 6749, 5.2)
+            if status == StatusCode::UNAUTHORIZED && authorization.is_some() {
+                response
+                    .headers_mut()
+                    .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Basic"));
+            }
+            response
+        }
+    }
+}
+
+/// UserInfo endpoint, for the Bearer access token issued with an ID token
+async fn userinfo(headers: HeaderMap) -> Response {
+    let authorization = headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok());
+
+    match oidc_userinfo_core(authorization).await {
+        Ok(claims) => Json(claims).into_response(),
+        Err(e) if error_is_internal(&e) => {
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_body(&e))).into_response()
+        }
+        // Bearer token errors are returned in the header (RFC 6750, 3)
+        Err(e) => (
+            StatusCode::UNAUTHORIZED,
+            [(
+                WWW_AUTHENTICATE,
+                format!("Bearer error=\"{}\"", e.error_code()),
+            )],
+        )
+            .into_response(),
+    }
+}
+
+fn error_is_internal(error: &OidcProviderError) -> bool {
+    error.error_code() == "server_error"
+}
+
+/// Error response body (RFC 6749, 5.2), without the details of internal errors
+fn error_body(error: &OidcProviderError) -> Value {
+    let description = match error_is_internal(error) {
+        true => "Internal error".to_string(),
+        false => error.to_string(),
+    };
+    json!({
+        "error": error.error_code(),
+        "error_description": description,
+    })
+}
@@ -1,9 +1,11 @@
 use askama::Template;
 use axum::{
+    extract::Query,
     http::StatusCode,
     response::{Html, IntoResponse, Redirect, Response},
 };
-use oauth2_passkey::O2P_ROUTE_PREFIX;
+use oauth2_passkey::{O2P_ROUTE_PREFIX, list_oauth2_providers, validate_return_to};
+use std::collections::HashMap;
 
 use crate::config::O2P_REDIRECT_USER;
 use crate::session::AuthUser as User;
@@ -13,15 +15,35 @@ use crate::session::AuthUser as User;
 struct LoginTemplate<'a> {
     message: &'a str,
     o2p_route_prefix: &'a str,
+    oauth2_providers: Vec<String>,
+    /// `?return_to=...` to pass on, or empty
+    return_to_query: String,
 }
 
-pub(crate) async fn login(user: Option<User>) -> Result<Response, (StatusCode, String)> {
+/// Login page, sending signed-in users to the validated `return_to` query parameter
+///
+/// The page reloads itself after an OAuth2 popup or passkey login, which ends up here
+/// with the `return_to` it was opened with.
+pub(crate) async fn login(
+    user: Option<User>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, (StatusCode, String)> {
+    let return_to = params
+        .get("return_to")
+        .and_then(|return_to| validate_return_to(return_to));
     match user {
-        Some(_) => Ok(Redirect::to(O2P_REDIRECT_USER.as_str()).into_response()),
+        Some(_) => Ok(
+            Redirect::to(return_to.as_deref().unwrap_or(O2P_REDIRECT_USER.as_str()))
+                .into_response(),
+        ),
         None => {
             let template = LoginTemplate {
                 message: "Passkey/OAuth2 Login Page!",
                 o2p_route_prefix: O2P_ROUTE_PREFIX.as_str(),
+                oauth2_providers: list_oauth2_providers(),
+                return_to_query: return_to
+                    .map(|return_to| format!("?return_to={}", urlencoding::encode(&return_to)))
+                    .unwrap_or_default(),
             };
             let html = Html(
                 template
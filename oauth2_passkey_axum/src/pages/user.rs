@@ -7,7 +7,7 @@ use axum::{
 
 use oauth2_passkey::{
     O2P_ROUTE_PREFIX, SessionUser, delete_user_account, list_accounts_core, list_credentials_core,
-    obfuscate_user_id, update_user_account,
+    list_oauth2_providers, obfuscate_user_id, update_user_account,
 };
 
 use crate::session::AuthUser;
@@ -49,6 +49,7 @@ pub struct UserSummaryTemplate {
     pub oauth2_accounts: Vec<TemplateAccount>,
     pub o2p_route_prefix: String,
     pub obfuscated_user_id: String,
+    pub oauth2_providers: Vec<String>,
 }
 
 impl UserSummaryTemplate {
@@ -66,6 +67,7 @@ impl UserSummaryTemplate {
             oauth2_accounts,
             o2p_route_prefix,
             obfuscated_user_id,
+            oauth2_providers: list_oauth2_providers(),
         }
     }
 }
@@ -198,7 +200,7 @@ pub async fn delete_user_account_handler(
 
     // Call the core function to delete the user account and all associated data
     // Using the imported function from libauth
-    let credential_ids = delete_user_account(&session_user_id)
+    let deletion = delete_user_account(&session_user_id)
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
@@ -206,7 +208,8 @@ pub async fn delete_user_account_handler(
     Ok(Json(json!({
         "status": "success",
         "message": "User account deleted successfully",
-        "credential_ids": credential_ids
+        "credential_ids": deletion.credential_ids,
+        "warnings": deletion.warnings
     })))
 }
 
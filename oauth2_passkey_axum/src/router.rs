@@ -1,6 +1,7 @@
 //! Combined router for all authentication endpoints
 
 use axum::Router;
+use oauth2_passkey::is_oidc_provider_enabled;
 
 /// Create a combined router for all authentication endpoints
 ///
@@ -9,11 +10,17 @@ use axum::Router;
 /// - {O2P_ROUTE_PREFIX}/oauth2/...
 /// - {O2P_ROUTE_PREFIX}/passkey/...
 /// - {O2P_ROUTE_PREFIX}/user/...
+/// - {O2P_ROUTE_PREFIX}/op/..., the OIDC provider, when clients are registered
 ///
 /// This simplifies integration by requiring only a single router to be mounted in the application.
 pub fn oauth2_passkey_router() -> Router {
-    Router::new()
+    let router = Router::new()
         .nest("/oauth2", super::oauth2::router())
         .nest("/passkey", super::passkey::router())
-        .nest("/user", super::pages::user_router())
+        .nest("/user", super::pages::user_router());
+    if is_oidc_provider_enabled() {
+        router.nest("/op", super::oidc_provider::router())
+    } else {
+        router
+    }
 }
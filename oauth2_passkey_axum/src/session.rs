@@ -1,26 +1,81 @@
 use axum::{
     RequestPartsExt,
-    extract::{FromRequestParts, OptionalFromRequestParts},
+    extract::{FromRequestParts, OptionalFromRequestParts, OriginalUri},
     response::{IntoResponse, Redirect, Response},
 };
 use axum_extra::{TypedHeader, headers};
-use http::request::Parts;
+use http::{Extensions, Method, Uri, request::Parts};
 
 use super::config::O2P_REDIRECT_ANON;
+use super::oauth2::with_query;
 use oauth2_passkey::{SESSION_COOKIE_NAME, SessionError, SessionUser, get_user_from_session};
 
-pub struct AuthRedirect;
+/// Redirect to the login page, passing the requested page as `return_to`
+pub struct AuthRedirect {
+    return_to: Option<String>,
+}
+
+impl AuthRedirect {
+    /// Redirect to the login page, without a page to return to
+    pub fn new() -> Self {
+        Self { return_to: None }
+    }
+
+    /// Redirect to the login page, returning to `return_to` after login
+    pub fn with_return_to(return_to: impl Into<String>) -> Self {
+        Self {
+            return_to: Some(return_to.into()),
+        }
+    }
+
+    fn from_parts(parts: &Parts) -> Self {
+        Self {
+            return_to: original_url(&parts.method, &parts.uri, &parts.extensions),
+        }
+    }
+}
+
+impl Default for AuthRedirect {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<SessionError> for AuthRedirect {
+    fn from(_: SessionError) -> Self {
+        Self::new()
+    }
+}
 
 impl IntoResponse for AuthRedirect {
     fn into_response(self) -> Response {
         println!("AuthRedirect called.");
-        Redirect::temporary(O2P_REDIRECT_ANON.as_str()).into_response()
+        Redirect::temporary(&login_url(
+            O2P_REDIRECT_ANON.as_str(),
+            self.return_to.as_deref(),
+        ))
+        .into_response()
     }
 }
 
-impl From<SessionError> for AuthRedirect {
-    fn from(_: SessionError) -> Self {
-        AuthRedirect
+/// Path and query of a GET request as the page to return to after login, also in a
+/// nested router
+pub(crate) fn original_url(method: &Method, uri: &Uri, extensions: &Extensions) -> Option<String> {
+    if method != Method::GET {
+        return None;
+    }
+    let uri = extensions
+        .get::<OriginalUri>()
+        .map(|original| &original.0)
+        .unwrap_or(uri);
+    uri.path_and_query().map(|path| path.to_string())
+}
+
+/// Login page URL with the page to return to
+pub(crate) fn login_url(login_url: &str, return_to: Option<&str>) -> String {
+    match return_to {
+        Some(return_to) => with_query(login_url, &[("return_to", return_to)]),
+        None => login_url.to_string(),
     }
 }
 
@@ -49,19 +104,21 @@ where
     type Rejection = AuthRedirect;
 
     async fn from_request_parts(parts: &mut Parts, _: &B) -> Result<Self, Self::Rejection> {
-        let cookies: TypedHeader<headers::Cookie> =
-            parts.extract().await.map_err(|_| AuthRedirect)?;
+        let cookies: TypedHeader<headers::Cookie> = parts
+            .extract()
+            .await
+            .map_err(|_| AuthRedirect::from_parts(parts))?;
 
         // Get session from cookie
         let session_cookie = cookies
             .get(SESSION_COOKIE_NAME.as_str())
-            .ok_or(AuthRedirect)?
+            .ok_or_else(|| AuthRedirect::from_parts(parts))?
             .to_string();
 
         // Convert libuserdb::User to libsession::User to AuthUser
         let user: SessionUser = get_user_from_session(&session_cookie)
             .await
-            .map_err(AuthRedirect::from)?;
+            .map_err(|_| AuthRedirect::from_parts(parts))?;
         Ok(AuthUser::from(user))
     }
 }
@@ -108,7 +108,11 @@ function base64URLToUint8Array(base64URL) {
                     throw new Error('Verification failed: ' + errorText);
                 }
 
-                window.location.href = '/';
+                // The login page sends the signed-in user to a validated return_to
+                const returnTo = new URLSearchParams(window.location.search).get('return_to');
+                window.location.href = returnTo
+                    ? `${O2P_ROUTE_PREFIX}/user/login?return_to=${encodeURIComponent(returnTo)}`
+                    : '/';
             }
         } catch (error) {
             if (error.name === 'AbortError') {
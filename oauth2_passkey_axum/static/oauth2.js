@@ -2,16 +2,16 @@ function initOAuth2Popup() {
     let popupWindow;
     let isReloading = false;
 
-    function openPopup(mode=null, page_context=null) {
+    function openPopup(mode=null, page_context=null, provider='google') {
         if (mode === 'add_to_existing_user') {
             popupWindow = window.open(
-                `${O2P_ROUTE_PREFIX}/oauth2/google?mode=${mode}&context=${page_context}`,
+                `${O2P_ROUTE_PREFIX}/oauth2/${provider}?mode=${mode}&context=${page_context}`,
                 "PopupWindow",
                 "width=550,height=640,left=1000,top=200,resizable=yes,scrollbars=yes"
             );
         } else {
             popupWindow = window.open(
-                `${O2P_ROUTE_PREFIX}/oauth2/google`,
+                `${O2P_ROUTE_PREFIX}/oauth2/${provider}`,
                 "PopupWindow",
                 "width=550,height=640,left=1000,top=200,resizable=yes,scrollbars=yes"
             );